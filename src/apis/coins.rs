@@ -1,5 +1,5 @@
 use crate::checkpointed_hashchain::CheckpointedHashchain;
-use crate::config::{Context, WalletCache};
+use crate::config::{Context, SyncCursor, TokenInfo, WalletCache};
 use crate::fp::Fp;
 use crate::helper::extract_token_amount;
 use crate::keys::Point;
@@ -13,8 +13,10 @@ use ethers::prelude::*;
 use eyre::Result;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct GetCoinsResponse {
@@ -32,6 +34,24 @@ pub async fn coins(
         if sync_task.is_finished() {
             let task = prov.syncing_task.take().unwrap();
             let (chc, coins) = task.await??;
+            // Coins of tokens the network isn't configured with are registered from their
+            // contracts, so that they show up with a symbol from now on.
+            if let Some(network) = prov.node_manager.network.as_mut() {
+                let name = network.config.name.clone();
+                let unknown: HashSet<H160> = coins
+                    .iter()
+                    .map(|c| c.uint_token)
+                    .filter(|addr| !addr.is_zero())
+                    .collect();
+                for addr in unknown {
+                    // Failures are logged and remembered by `discover_token` itself.
+                    let _ = network
+                        .config
+                        .token_contracts
+                        .discover_token(&name, addr)
+                        .await;
+                }
+            }
             prov.chc = chc;
             prov.coins = coins;
             *prov.syncing.lock().unwrap() = None;
@@ -108,12 +128,14 @@ pub async fn coins(
         let mut sent_events: Vec<SentFilter> = vec![];
 
         if prov.node_manager.is_peer2peer {
-            let from_spent = prov.events_latest_status.last_spent_event;
-            let from_sent = prov.events_latest_status.last_sent_event;
+            let cursor = SyncCursor {
+                from_spend: prov.events_latest_status.last_spent_event,
+                from_sent: prov.events_latest_status.last_sent_event,
+                last_block: 0,
+            };
             let (tmp_spent_events, tmp_sent_events, _) = prov
                 .node_manager
-                .clone()
-                .get_events_from_elected_peer(from_spent, from_sent)
+                .get_verified_events(&cursor, &CancellationToken::new())
                 .await?;
             spent_events = tmp_spent_events;
             sent_events = tmp_sent_events;
@@ -121,11 +143,11 @@ pub async fn coins(
             spent_events = prov
                 .node_manager
                 .get_spend_events(curr + 1, curr_block_number)
-                .await;
+                .await?;
             sent_events = prov
                 .node_manager
                 .get_sent_events(curr + 1, curr_block_number)
-                .await;
+                .await?;
         }
 
         let chc_task = chc.clone();
@@ -138,6 +160,12 @@ pub async fn coins(
             .cloned()
             .map(|e| e.into())
             .collect();
+        let tokens = network
+            .config
+            .token_contracts
+            .get(&network.config.name)
+            .cloned()
+            .unwrap_or_default();
         let task = tokio::task::spawn_blocking(move || {
             sync_coins(
                 &genesis_events,
@@ -149,6 +177,7 @@ pub async fn coins(
                 curr_block_number,
                 &wallet_cache_path,
                 &syncing_arc,
+                &tokens,
             )
         });
 
@@ -176,6 +205,7 @@ fn sync_coins(
     curr_block_number: u64,
     wallet_cache_path: &std::path::PathBuf,
     syncing_arc: &Arc<std::sync::Mutex<Option<f32>>>,
+    tokens: &[TokenInfo],
 ) -> Result<(CheckpointedHashchain, Vec<Coin>), eyre::Report> {
     let cache_exists = std::fs::metadata(&wallet_cache_path).is_ok();
 
@@ -227,15 +257,25 @@ fn sync_coins(
                     commitment,
                     stealth_pub,
                 ) {
-                    log::info!(
-                        "Found coin Index {} - Amount: {:?}",
-                        index.low_u32(),
-                        fp_hint_amount
-                    );
+                    let uint_token = u256_to_h160(fp_hint_token_address.into());
+                    let amount: U256 = fp_hint_amount.into();
+                    match tokens.iter().find(|t| t.token_address == uint_token) {
+                        Some(token) => log::info!(
+                            "Found coin Index {} - Amount: {} {}",
+                            index.low_u32(),
+                            token.format_amount(amount),
+                            token.symbol
+                        ),
+                        None => log::info!(
+                            "Found coin Index {} - Amount: {:?}",
+                            index.low_u32(),
+                            fp_hint_amount
+                        ),
+                    }
                     Some(Coin {
                         index,
-                        uint_token: u256_to_h160(fp_hint_token_address.into()),
-                        amount: fp_hint_amount.into(),
+                        uint_token,
+                        amount,
                         nullifier: stealth_priv.nullifier(index.low_u32()).into(),
                         priv_key: stealth_priv,
                         pub_key: stealth_pub,
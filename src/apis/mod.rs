@@ -16,9 +16,13 @@ pub use set_params_path::{set_params_path, SetParamsPathRequest};
 pub use stealth::{stealth, GetStealthRequest};
 pub use withdraw::{withdraw, GetWithdrawRequest};
 mod node;
+pub use node::metrics;
+pub use node::peers_status;
 pub use node::status;
-pub use node::{events, GetEventsRequest, GetEventsResponse};
+pub use node::{events, EventStreams, GetEventsRequest, GetEventsResponse};
 pub use node::{get_peers, GetPeersResponse};
 pub use node::{handshake, GetHandShakeRequest, GetHandShakeResponse};
 pub use node::{mempool, GetMempoolRequest};
+pub use node::{reachability, GetReachabilityRequest, GetReachabilityResponse};
+pub use node::{timestamped_events, GetTimestampedEventsRequest};
 pub use node::{transact, PostTransactRequest};
@@ -7,45 +7,83 @@ use tokio::sync::Mutex;
 
 use crate::config::NodeContext;
 
+/// Largest `length` a node serves in a single events page.
+pub const MAX_EVENTS_PAGE: usize = 256;
+
+/// Which event streams an events request asks for. Nodes that predate the parameter
+/// ignore it and always serve both.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventStreams {
+    #[default]
+    Both,
+    Spend,
+    Sent,
+}
+
+impl EventStreams {
+    pub fn includes_spend(self) -> bool {
+        self != EventStreams::Sent
+    }
+
+    pub fn includes_sent(self) -> bool {
+        self != EventStreams::Spend
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GetEventsRequest {
     pub from_spend: usize,
     pub from_sent: usize,
     pub length: usize,
+    #[serde(default)]
+    pub streams: EventStreams,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GetEventsResponse {
     pub spend_events: Vec<SpendFilter>,
     pub sent_events: Vec<SentFilter>,
+    /// Block the serving node had synced to when the page was taken.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_block_number: Option<u64>,
 }
 
 pub async fn events(
     Query(req): Query<GetEventsRequest>,
     context_events: Arc<Mutex<NodeContext>>,
 ) -> Result<Json<GetEventsResponse>, eyre::Report> {
-    if req.length > 256 {
-        return Err(eyre::eyre!("Length must be less than 256"));
+    if req.length > MAX_EVENTS_PAGE {
+        return Err(eyre::eyre!("Length must be less than {}", MAX_EVENTS_PAGE));
     }
 
     let context = context_events.lock().await;
-    let spend_events = context
-        .spent_events
-        .iter()
-        .skip(req.from_spend)
-        .take(req.length)
-        .cloned()
-        .collect();
-    let sent_events = context
-        .sent_events
-        .iter()
-        .skip(req.from_sent)
-        .take(req.length)
-        .cloned()
-        .collect();
+    let spend_events = if req.streams.includes_spend() {
+        context
+            .spent_events
+            .iter()
+            .skip(req.from_spend)
+            .take(req.length)
+            .cloned()
+            .collect()
+    } else {
+        vec![]
+    };
+    let sent_events = if req.streams.includes_sent() {
+        context
+            .sent_events
+            .iter()
+            .skip(req.from_sent)
+            .take(req.length)
+            .cloned()
+            .collect()
+    } else {
+        vec![]
+    };
 
     Ok(Json(GetEventsResponse {
         spend_events,
         sent_events,
+        current_block_number: Some(context.currnet_block_number),
     }))
 }
@@ -1,20 +1,37 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use axum::Json;
+use ethers::types::H256;
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
-use crate::config::{NodeContext, Peer};
+use super::MAX_EVENTS_PAGE;
+use crate::{
+    config::{NodeContext, Peer},
+    network::PROTOCOL_VERSION,
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GetHandShakeRequest {
     pub addr: Option<SocketAddr>,
     pub is_client: bool,
+    /// Protocol version of the caller. Nodes that predate versioning don't send one.
+    #[serde(default)]
+    pub version: Option<u32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GetHandShakeResponse {
     pub current_block_number: u64,
+    /// Largest events page the node serves. Older nodes don't advertise one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_events_page: Option<usize>,
+    #[serde(default)]
+    pub version: Option<u32>,
+    /// Commitment to the node's events up to `current_block_number`, see `EventsCommitment`.
+    /// Older nodes don't send one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub events_commitment: Option<H256>,
 }
 
 pub async fn handshake(
@@ -24,13 +41,22 @@ pub async fn handshake(
     let mut context = context.lock().await;
 
     if let Some(addr) = req.addr {
-        context.node_manager.add_peer(Peer {
-            addr: addr,
-            current_block: 0,
-        });
+        let version = req.version.unwrap_or_default();
+        if context.node_manager.is_compatible_version(version) {
+            context.node_manager.add_peer(Peer::new(addr));
+        } else {
+            log::warn!(
+                "Not adding peer {} with incompatible protocol version {}",
+                addr,
+                version
+            );
+        }
     }
 
     Ok(Json(GetHandShakeResponse {
         current_block_number: context.currnet_block_number,
+        max_events_page: Some(MAX_EVENTS_PAGE),
+        version: Some(PROTOCOL_VERSION),
+        events_commitment: Some(context.events_commitment.root()),
     }))
 }
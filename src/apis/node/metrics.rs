@@ -0,0 +1,10 @@
+use std::sync::Arc;
+
+use axum::Json;
+use tokio::sync::Mutex;
+
+use crate::{config::NodeContext, network::SyncMetrics};
+
+pub async fn metrics(context: Arc<Mutex<NodeContext>>) -> Result<Json<SyncMetrics>, eyre::Report> {
+    Ok(Json(context.lock().await.node_manager.metrics()))
+}
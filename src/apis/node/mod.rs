@@ -2,12 +2,20 @@ mod events;
 mod get_mempool;
 mod get_peers;
 mod handshake;
+mod metrics;
+mod peers_status;
 mod post_tx;
+mod reachability;
 mod status;
+mod timestamped_events;
 
-pub use events::{events, GetEventsRequest, GetEventsResponse};
+pub use events::{events, EventStreams, GetEventsRequest, GetEventsResponse, MAX_EVENTS_PAGE};
 pub use get_mempool::{mempool, GetMempoolRequest};
 pub use get_peers::{get_peers, GetPeersResponse};
 pub use handshake::{handshake, GetHandShakeRequest, GetHandShakeResponse};
+pub use metrics::metrics;
+pub use peers_status::peers_status;
 pub use post_tx::{transact, PostTransactRequest};
+pub use reachability::{reachability, GetReachabilityRequest, GetReachabilityResponse};
 pub use status::status;
+pub use timestamped_events::{timestamped_events, GetTimestampedEventsRequest};
@@ -0,0 +1,22 @@
+use std::sync::Arc;
+
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{config::NodeContext, network::PeerStatus};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetPeersStatusResponse {
+    pub peers: Vec<PeerStatus>,
+}
+
+pub async fn peers_status(
+    context: Arc<Mutex<NodeContext>>,
+) -> Result<Json<GetPeersStatusResponse>, eyre::Report> {
+    // Handshakes take a while, so they run on a copy rather than under the lock.
+    let node_manager = context.lock().await.node_manager.clone();
+    Ok(Json(GetPeersStatusResponse {
+        peers: node_manager.peer_status().await,
+    }))
+}
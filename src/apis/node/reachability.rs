@@ -0,0 +1,27 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{extract::Query, Json};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::NodeContext;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetReachabilityRequest {
+    pub addr: SocketAddr,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetReachabilityResponse {
+    pub reachable: bool,
+}
+
+pub async fn reachability(
+    Query(req): Query<GetReachabilityRequest>,
+    context: Arc<Mutex<NodeContext>>,
+) -> Result<Json<GetReachabilityResponse>, eyre::Report> {
+    let node_manager = context.lock().await.node_manager.clone();
+    Ok(Json(GetReachabilityResponse {
+        reachable: node_manager.is_reachable(req.addr).await,
+    }))
+}
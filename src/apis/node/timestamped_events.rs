@@ -0,0 +1,47 @@
+use std::sync::Arc;
+
+use axum::{extract::Query, Json};
+use bindings::owshen::{SentFilter, SpendFilter};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{config::NodeContext, network::TimestampedEvent};
+
+/// Widest block range a node scans for a single timestamped events request.
+pub const MAX_TIMESTAMPED_EVENTS_BLOCKS: u64 = 10_000;
+
+/// Asks for the events emitted in blocks `[from_block, to_block)`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetTimestampedEventsRequest {
+    pub from_block: u64,
+    pub to_block: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetTimestampedEventsResponse {
+    pub spend_events: Vec<TimestampedEvent<SpendFilter>>,
+    pub sent_events: Vec<TimestampedEvent<SentFilter>>,
+}
+
+pub async fn timestamped_events(
+    Query(req): Query<GetTimestampedEventsRequest>,
+    context: Arc<Mutex<NodeContext>>,
+) -> Result<Json<GetTimestampedEventsResponse>, eyre::Report> {
+    if req.to_block.saturating_sub(req.from_block) > MAX_TIMESTAMPED_EVENTS_BLOCKS {
+        return Err(eyre::eyre!(
+            "Block range must be at most {} blocks",
+            MAX_TIMESTAMPED_EVENTS_BLOCKS
+        ));
+    }
+
+    // The scan goes to the provider, so it runs on a copy rather than under the lock.
+    let node_manager = context.lock().await.node_manager.clone();
+    Ok(Json(GetTimestampedEventsResponse {
+        spend_events: node_manager
+            .get_spend_events_with_timestamps(req.from_block, req.to_block)
+            .await?,
+        sent_events: node_manager
+            .get_sent_events_with_timestamps(req.from_block, req.to_block)
+            .await?,
+    }))
+}
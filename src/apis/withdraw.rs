@@ -4,6 +4,7 @@ use crate::config::Context;
 use crate::fp::Fp;
 use crate::h160_to_u256;
 use crate::hash::hash4;
+use crate::helper::parse_address;
 use crate::keys::{Point, PrivateKey, PublicKey};
 use crate::proof::{prove, ProveResult};
 
@@ -90,7 +91,7 @@ pub async fn withdraw<P: AsRef<Path>>(
         let proofs: Vec<CheckpointedHashchainProof> = vec![chc_proof.clone(), chc_proof.clone()];
         let new_amounts: Vec<U256> = vec![fp_new_amount.into(), obfuscated_remaining_amount.into()];
 
-        let h160_address = H160::from_str(&address)?;
+        let h160_address = parse_address(&address)?;
         let null_pub_key = PublicKey {
             point: Point {
                 x: Fp::try_from(h160_to_u256(h160_address))?,
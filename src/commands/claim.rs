@@ -1,4 +1,4 @@
-use std::{str::FromStr, sync::Arc};
+use std::sync::Arc;
 
 use bindings::dive_token::DiveToken;
 use ethers::{
@@ -10,6 +10,8 @@ use ethers::{
 };
 use structopt::StructOpt;
 
+use crate::helper::parse_address;
+
 #[derive(StructOpt, Debug)]
 pub struct ClaimOpt {
     #[structopt(long)]
@@ -36,7 +38,7 @@ pub async fn claim(_opt: ClaimOpt) {
     let wallet = wallet::from(private_key).with_chain_id(_opt.chain_id);
     let client = Arc::new(SignerMiddleware::new(provider.clone(), wallet));
 
-    let token_h160_address = H160::from_str(&_opt.token_address).unwrap_or_else(|e| {
+    let token_h160_address = parse_address(&_opt.token_address).unwrap_or_else(|e| {
         panic!("Error: failed to parse token address: {:?}", e);
     });
     let contract = DiveToken::new(token_h160_address, client.clone());
@@ -93,7 +93,7 @@ async fn initialize_config(
     deploy_owshen: bool,
     genesis_feed: bool,
 ) -> Result<Config, eyre::Report> {
-    let mut network_manager = NetworkManager::new();
+    let mut network_manager = NetworkManager::new()?;
     let provider = Arc::new(Provider::<Http>::try_from(endpoint.clone())?);
     let private_key_bytes = hex_decode(&from)?;
     let private_key: SecretKey<_> = SecretKey::from_slice(&private_key_bytes)?;
@@ -200,6 +200,11 @@ async fn initialize_config(
     log::info!("Getting Owshen contract deployment blocknumber...");
     let mut owshen_contract_deployment_block_number: U64 = U64::default();
 
+    let genesis_deposit_index = if deploy_owshen {
+        Some(genesis.chc.size())
+    } else {
+        config.as_ref().and_then(|c| c.genesis_deposit_index)
+    };
     let (owshen_contract_address, owshen_contract_abi) = if deploy_owshen {
         log::info!("Deploying Owshen contract...");
         let o = Owshen::deploy(
@@ -240,6 +245,8 @@ async fn initialize_config(
     let mut tokens = vec![TokenInfo {
         token_address: dive_contract_address,
         symbol: "DIVE".to_string(),
+        decimals: 18,
+        chain_id: Some(chain_id),
     }];
 
     if dev {
@@ -262,11 +269,13 @@ async fn initialize_config(
             tokens.push(TokenInfo {
                 token_address: tkn.address(),
                 symbol: test_tkn_name.to_string(),
+                decimals: 18,
+                chain_id: Some(chain_id),
             })
         }
     }
 
-    network_manager.add_network(name.clone(), tokens);
+    network_manager.add_network(name.clone(), tokens)?;
 
     Ok(Config {
         name,
@@ -280,6 +289,8 @@ async fn initialize_config(
         token_contracts: network_manager,
         poseidon4_contract_address: poseidon4_addr,
         poseidon2_contract_address: poseidon2_addr,
+        rpc_headers: Default::default(),
+        genesis_deposit_index,
     })
 }
 
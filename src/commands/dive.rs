@@ -1,4 +1,4 @@
-use std::{str::FromStr, sync::Arc};
+use std::sync::Arc;
 
 use bindings::dive_token::DiveToken;
 use ethers::{
@@ -10,6 +10,8 @@ use ethers::{
 };
 use structopt::StructOpt;
 
+use crate::helper::parse_address;
+
 #[derive(StructOpt, Debug)]
 pub struct DiveOpt {
     #[structopt(long)]
@@ -38,7 +40,7 @@ pub async fn dive(_opt: DiveOpt) {
     let wallet = wallet::from(private_key).with_chain_id(_opt.chain_id);
     let client = Arc::new(SignerMiddleware::new(provider.clone(), wallet));
 
-    let token_h160_address = H160::from_str(&_opt.token_address);
+    let token_h160_address = parse_address(&_opt.token_address);
     let token_h160_address = match token_h160_address {
         Ok(token_h160_address) => token_h160_address,
         Err(e) => {
@@ -19,7 +19,7 @@ use crate::{
     config::Wallet,
     fp::Fp,
     hash::hash2,
-    helper::proof_to_groth16_proof,
+    helper::{parse_address, proof_to_groth16_proof},
     proof::{mpt_last_prove, mpt_path_prove, Proof},
 };
 
@@ -73,7 +73,7 @@ pub async fn mint(_opt: MintOpt, wallet_path: PathBuf) {
         panic!("Error: failed to parse private key: {:?}", e);
     });
 
-    let token_h160_address = H160::from_str(&_opt.token_address).unwrap_or_else(|e| {
+    let token_h160_address = parse_address(&_opt.token_address).unwrap_or_else(|e| {
         panic!("Error: failed to parse token address: {:?}", e);
     });
 
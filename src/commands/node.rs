@@ -1,24 +1,38 @@
 use std::net::SocketAddr;
-use std::{path::PathBuf, sync::Arc};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use axum::extract::Query;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::routing::{get, post};
 use axum::{extract, Json, Router};
-use ethers::providers::{Http, Middleware, Provider};
+use bindings::owshen::{SentFilter, SpendFilter};
+use ethers::providers::{Provider, Ws};
 use structopt::StructOpt;
 use tokio::sync::Mutex;
-use tower_http::cors::CorsLayer;
+use tokio_util::sync::CancellationToken;
+use tower_http::{compression::CompressionLayer, cors::CorsLayer};
 
 use crate::apis;
-use crate::config::{Config, Network, NodeContext, NodeManager, Peer, NODE_UPDATE_INTERVAL};
+use crate::config::{
+    Config, ConnectionPoolConfig, Network, NodeContext, NodeManager, Peer, SyncCursor,
+    NODE_UPDATE_INTERVAL, PEER_SYNC_INTERVAL,
+};
+use crate::network::{CertFingerprint, EventsCommitment, PeerOrder};
+
+/// File in the state directory the node's events are saved to on shutdown, next to the peers
+/// and cursor `NodeManager::shutdown` saves, since the cursor only counts them.
+const STATE_EVENTS_FILE: &str = "events.json";
 
 #[derive(StructOpt, Debug, Clone)]
 pub struct NodeOpt {
     #[structopt(long)]
     endpoint: String,
     #[structopt(long)]
+    ws_endpoint: Option<String>,
+    #[structopt(long)]
     config: PathBuf,
 
     #[structopt(long, default_value = "127.0.0.1:8888")]
@@ -30,6 +44,64 @@ pub struct NodeOpt {
     bootstrap_peers: Vec<Peer>,
     #[structopt(long)]
     peer2peer: bool,
+    #[structopt(long)]
+    seeds: Vec<String>,
+    #[structopt(long, default_value = "0")]
+    confirmations: u64,
+    #[structopt(long)]
+    auth_token: Option<String>,
+    #[structopt(long)]
+    node_id: Option<String>,
+    #[structopt(long)]
+    pool_max_idle_per_host: Option<usize>,
+    #[structopt(long)]
+    pool_idle_timeout_secs: Option<u64>,
+    #[structopt(long)]
+    pinned_certs: Vec<CertFingerprint>,
+    #[structopt(long)]
+    max_response_body: Option<usize>,
+    #[structopt(long, default_value = "insertion")]
+    peer_order: PeerOrder,
+    #[structopt(long)]
+    stale_peer_lag: Option<u64>,
+    #[structopt(long)]
+    max_blocks_per_call: Option<u64>,
+    #[structopt(long)]
+    max_election_age_secs: Option<u64>,
+    #[structopt(long)]
+    verify_sample_blocks: Option<u64>,
+    #[structopt(long, conflicts_with = "verify-sample-blocks")]
+    shard_peers: Option<usize>,
+    #[structopt(long)]
+    election_seed: Option<u64>,
+    #[structopt(long, default_value = "0")]
+    min_elect_block: u64,
+    #[structopt(long)]
+    http_timeout_ms: Option<u64>,
+    #[structopt(long)]
+    handshake_timeout_ms: Option<u64>,
+    #[structopt(long)]
+    peers_timeout_ms: Option<u64>,
+    #[structopt(long)]
+    events_timeout_ms: Option<u64>,
+    #[structopt(long)]
+    sync_round_timeout_secs: Option<u64>,
+    #[structopt(long)]
+    events_page_size: Option<usize>,
+    #[structopt(long)]
+    min_peer_version: Option<u32>,
+    #[structopt(long)]
+    max_peers: Option<usize>,
+    #[structopt(long)]
+    max_requests_per_second: Option<f64>,
+    #[structopt(long)]
+    health_recovery_window_secs: Option<u64>,
+    #[structopt(long, default_value = "0")]
+    event_cache_capacity: usize,
+    #[structopt(long)]
+    no_provider_fallback: bool,
+    #[structopt(long)]
+    state_dir: Option<PathBuf>,
 
     #[structopt(long)]
     relayer: Option<String>,
@@ -38,11 +110,41 @@ pub struct NodeOpt {
 pub async fn node(opt: NodeOpt) -> Result<(), eyre::Report> {
     let NodeOpt {
         endpoint,
+        ws_endpoint,
         config,
         external,
         interface,
         bootstrap_peers,
         peer2peer,
+        seeds,
+        confirmations,
+        auth_token,
+        node_id,
+        pool_max_idle_per_host,
+        pool_idle_timeout_secs,
+        pinned_certs,
+        max_response_body,
+        peer_order,
+        stale_peer_lag,
+        max_blocks_per_call,
+        max_election_age_secs,
+        verify_sample_blocks,
+        shard_peers,
+        election_seed,
+        min_elect_block,
+        http_timeout_ms,
+        handshake_timeout_ms,
+        peers_timeout_ms,
+        events_timeout_ms,
+        sync_round_timeout_secs,
+        events_page_size,
+        min_peer_version,
+        max_peers,
+        max_requests_per_second,
+        health_recovery_window_secs,
+        event_cache_capacity,
+        no_provider_fallback,
+        state_dir,
         relayer,
     } = opt;
 
@@ -51,55 +153,137 @@ pub async fn node(opt: NodeOpt) -> Result<(), eyre::Report> {
         c
     })?;
 
-    let provider = Provider::<Http>::try_from(endpoint.clone())?;
-    let context = Arc::new(Mutex::new(NodeContext {
-        node_manager: NodeManager {
-            external_addr: Some(external.clone()),
-            network: Some(Network {
-                provider: Arc::new(provider),
-                config,
-            }),
-            peers: bootstrap_peers,
-            elected_peer: None,
-            is_peer2peer: peer2peer,
-
-            is_client: false,
+    let ws_provider = match &ws_endpoint {
+        Some(ws_endpoint) => Some(Arc::new(Provider::<Ws>::connect(ws_endpoint).await?)),
+        None => None,
+    };
+    let mut node_manager = NodeManager::new(
+        Some(external),
+        Some(Network::connect(&endpoint, ws_provider, config)?),
+        bootstrap_peers,
+        peer2peer,
+        false,
+    )?;
+    node_manager.set_confirmations(confirmations);
+    node_manager.set_auth_token(auth_token);
+    node_manager.set_node_id(node_id)?;
+    let pool = ConnectionPoolConfig::default();
+    node_manager.set_connection_pool(ConnectionPoolConfig {
+        max_idle_per_host: pool_max_idle_per_host.unwrap_or(pool.max_idle_per_host),
+        idle_timeout: pool_idle_timeout_secs
+            .map(Duration::from_secs)
+            .or(pool.idle_timeout),
+    })?;
+    node_manager.set_pinned_certificates(pinned_certs)?;
+    if let Some(bytes) = max_response_body {
+        node_manager.set_max_response_body(bytes);
+    }
+    node_manager.set_peer_order(peer_order);
+    node_manager.set_stale_peer_lag(stale_peer_lag);
+    node_manager.set_max_blocks_per_call(max_blocks_per_call);
+    node_manager.set_max_election_age(max_election_age_secs.map(Duration::from_secs));
+    node_manager.set_event_verification(verify_sample_blocks);
+    if let Some(peers) = shard_peers {
+        node_manager.set_shard_peers(peers);
+    }
+    if let Some(seed) = election_seed {
+        node_manager.set_election_seed(seed);
+    }
+    node_manager.set_min_elect_block(min_elect_block);
+    if let Some(timeout) = http_timeout_ms {
+        node_manager.set_http_timeout(Duration::from_millis(timeout))?;
+    }
+    node_manager.set_request_timeouts(
+        handshake_timeout_ms.map_or(node_manager.handshake_timeout, Duration::from_millis),
+        peers_timeout_ms.map_or(node_manager.peers_timeout, Duration::from_millis),
+        events_timeout_ms.map_or(node_manager.events_timeout, Duration::from_millis),
+    );
+    node_manager.set_sync_round_timeout(sync_round_timeout_secs.map(Duration::from_secs));
+    if let Some(page_size) = events_page_size {
+        node_manager.set_events_page_size(page_size);
+    }
+    if let Some(version) = min_peer_version {
+        node_manager.set_min_peer_version(version);
+    }
+    if let Some(max_peers) = max_peers {
+        node_manager.set_max_peers(max_peers);
+    }
+    node_manager.set_max_requests_per_second(max_requests_per_second);
+    if let Some(window) = health_recovery_window_secs {
+        node_manager.set_health_recovery_window(Duration::from_secs(window));
+    }
+    node_manager.set_event_cache_capacity(event_cache_capacity);
+    node_manager.set_provider_fallback(!no_provider_fallback);
+    if !seeds.is_empty() {
+        let added = node_manager.bootstrap_from_seeds(&seeds).await;
+        log::info!("Added {} peers from DNS seeds", added);
+    }
+    node_manager.set_state_dir(state_dir.clone());
+    let known_peers = node_manager.peer_count();
+    let cursor = node_manager.restore();
+    if let Some(dir) = &state_dir {
+        let added = node_manager.peer_count().saturating_sub(known_peers);
+        log::info!("Loaded {} peers from {}", added, dir.display());
+    }
+    let restored = match (&state_dir, cursor) {
+        (Some(dir), Some(cursor)) => match load_events(dir, &cursor) {
+            Ok(events) => {
+                log::info!(
+                    spent = cursor.from_spend, sent = cursor.from_sent, block = cursor.last_block;
+                    "Resuming from saved events"
+                );
+                Some((events, cursor))
+            }
+            Err(e) => {
+                log::warn!(error:% = e; "Could not restore events, fetching them again");
+                node_manager.record_cursor(&SyncCursor::default());
+                None
+            }
         },
+        _ => None,
+    };
+    let ((spent_events, sent_events), cursor) = restored.unwrap_or_default();
+    let mut events_commitment = EventsCommitment::default();
+    events_commitment.extend(&spent_events, &sent_events);
+    let context = Arc::new(Mutex::new(NodeContext {
+        node_manager,
 
-        spent_events: vec![],
-        sent_events: vec![],
-        currnet_block_number: 0,
+        spent_events,
+        sent_events,
+        currnet_block_number: cursor.last_block,
+        events_commitment,
         mempool: vec![],
     }));
 
-    let context_sync = context.clone();
+    let sync_loop = NodeManager::spawn_sync_loop(
+        context.clone(),
+        |ctx| &mut ctx.node_manager,
+        Duration::from_secs(PEER_SYNC_INTERVAL),
+    );
+
+    // Once we know some peers, have one of them check that others can reach us.
+    let context_reachability_check = context.clone();
     tokio::spawn(async move {
         loop {
-            if let Err(e) = async {
-                log::info!("Syncing with peers...");
-                let now = std::time::Instant::now();
-
-                let mut node_manager = context_sync.lock().await.node_manager.clone();
-                node_manager.sync_with_peers().await?;
-                context_sync.lock().await.node_manager = node_manager;
-
-                log::info!("Syncing with peers took: {:?}", now.elapsed());
-                Ok::<(), eyre::Report>(())
+            tokio::time::sleep(Duration::from_secs(PEER_SYNC_INTERVAL)).await;
+            let node_manager = context_reachability_check.lock().await.node_manager.clone();
+            match node_manager.verify_reachability().await {
+                Ok(_) => break,
+                Err(e) => log::debug!(error:% = e; "Reachability check failed"),
             }
-            .await
-            {
-                log::error!("Error occurred while syncing with peers: {}", e);
-            }
-            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
         }
     });
 
     let context_status = context.clone();
     let context_events = context.clone();
+    let context_timestamps = context.clone();
     let context_get_peers = context.clone();
     let context_handshake = context.clone();
     let context_get_mempool = context.clone();
     let context_post_tx = context.clone();
+    let context_reachability = context.clone();
+    let context_peers_status = context.clone();
+    let context_metrics = context.clone();
 
     let app = Router::new()
         .route(
@@ -114,10 +298,28 @@ pub async fn node(opt: NodeOpt) -> Result<(), eyre::Report> {
                 },
             ),
         )
+        .route(
+            "/events/timestamps",
+            get(
+                move |Query(req): Query<apis::GetTimestampedEventsRequest>| async move {
+                    handle_error(apis::timestamped_events(Query(req), context_timestamps).await)
+                },
+            ),
+        )
         .route(
             "/get-peers",
             get(move || async move { handle_error(apis::get_peers(context_get_peers).await) }),
         )
+        .route(
+            "/peers/status",
+            get(move || async move {
+                handle_error(apis::peers_status(context_peers_status).await)
+            }),
+        )
+        .route(
+            "/metrics",
+            get(move || async move { handle_error(apis::metrics(context_metrics).await) }),
+        )
         .route(
             "/handshake",
             get(
@@ -126,6 +328,14 @@ pub async fn node(opt: NodeOpt) -> Result<(), eyre::Report> {
                 },
             ),
         )
+        .route(
+            "/reachability",
+            get(
+                move |extract::Query(req): extract::Query<apis::GetReachabilityRequest>| async move {
+                    handle_error(apis::reachability(Query(req), context_reachability).await)
+                },
+            ),
+        )
         .route(
             "/",
             get(move || async { Json(serde_json::json!({"ok": true})) }),
@@ -146,24 +356,30 @@ pub async fn node(opt: NodeOpt) -> Result<(), eyre::Report> {
                 },
             ),
         )
-        .layer(CorsLayer::permissive());
+        .layer(CorsLayer::permissive())
+        .layer(CompressionLayer::new());
 
+    let stop = CancellationToken::new();
     let backend = async {
         log::info!("Server started at: {:?}", interface);
         axum::Server::bind(&interface)
             .serve(app.into_make_service())
+            .with_graceful_shutdown(stop.cancelled())
             .await?;
         Ok::<(), eyre::Error>(())
     };
 
-    #[allow(unreachable_code)]
+    // An update that's under way when the node is asked to stop is let finish.
     let sync_job = async {
         loop {
             log::info!("Updating events...");
-            update_events(context.clone()).await?;
+            update_events(context.clone(), shard_peers.is_some()).await?;
 
             log::info!("Sleeping for {} seconds...", NODE_UPDATE_INTERVAL);
-            tokio::time::sleep(tokio::time::Duration::from_secs(NODE_UPDATE_INTERVAL)).await;
+            tokio::select! {
+                _ = stop.cancelled() => break,
+                _ = tokio::time::sleep(tokio::time::Duration::from_secs(NODE_UPDATE_INTERVAL)) => {}
+            }
 
             if let Some(priv_key) = &relayer {
                 relay_txs(priv_key.clone(), context.clone()).await.unwrap(); // TODO: handle exceptions of the loop
@@ -172,11 +388,61 @@ pub async fn node(opt: NodeOpt) -> Result<(), eyre::Report> {
         Ok::<(), eyre::Error>(())
     };
 
-    tokio::try_join!(backend, sync_job)?;
+    let shutdown = async {
+        shutdown_signal().await?;
+        log::info!("Shutting down...");
+        stop.cancel();
+        Ok::<(), eyre::Error>(())
+    };
+
+    tokio::try_join!(backend, sync_job, shutdown)?;
+
+    // The peer sync is stopped the same way before the state is saved for the next start.
+    sync_loop.stop().await;
+    let ctx = context.lock().await;
+    if let Some(dir) = &state_dir {
+        save_events(dir, &ctx)?;
+    }
+    ctx.node_manager.shutdown()?;
+
+    Ok(())
+}
 
+/// Resolves on ctrl-c, or on SIGTERM where there is one.
+async fn shutdown_signal() -> Result<(), eyre::Report> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
     Ok(())
 }
 
+fn save_events(dir: &Path, ctx: &NodeContext) -> Result<(), eyre::Report> {
+    let events = serde_json::to_string(&(&ctx.spent_events, &ctx.sent_events))?;
+    std::fs::write(dir.join(STATE_EVENTS_FILE), events)
+        .map_err(|e| eyre::eyre!("Error saving events: {}", e))
+}
+
+/// Loads the events `save_events` saved, which must be the ones `cursor` counts.
+fn load_events(
+    dir: &Path,
+    cursor: &SyncCursor,
+) -> Result<(Vec<SpendFilter>, Vec<SentFilter>), eyre::Report> {
+    let (spent_events, sent_events): (Vec<SpendFilter>, Vec<SentFilter>) =
+        serde_json::from_str(&std::fs::read_to_string(dir.join(STATE_EVENTS_FILE))?)?;
+    if spent_events.len() != cursor.from_spend || sent_events.len() != cursor.from_sent {
+        return Err(eyre::eyre!("Saved events don't match the saved cursor"));
+    }
+    Ok((spent_events, sent_events))
+}
+
 async fn relay_txs(priv_key: String, context: Arc<Mutex<NodeContext>>) -> Result<(), eyre::Report> {
     let txs = context.lock().await.mempool.clone();
     for _tx in txs.iter() {
@@ -186,29 +452,42 @@ async fn relay_txs(priv_key: String, context: Arc<Mutex<NodeContext>>) -> Result
     Ok(())
 }
 
-async fn update_events(context: Arc<Mutex<NodeContext>>) -> Result<(), eyre::Report> {
+async fn update_events(
+    context: Arc<Mutex<NodeContext>>,
+    sharded: bool,
+) -> Result<(), eyre::Report> {
     let mut ctx = context.lock().await;
-    let network = ctx.node_manager.get_provider_network().clone();
-
-    if let Some(network) = network {
+    if ctx.node_manager.get_provider_network().is_some() {
         if ctx.node_manager.is_peer2peer {
-            let from_spent = ctx.spent_events.len();
-            let from_sent = ctx.sent_events.len();
-            let (spent_events, sent_events, peer_current_block_number) = ctx
-                .node_manager
-                .clone()
-                .get_events_from_elected_peer(from_spent, from_sent)
-                .await?;
+            let cursor = SyncCursor {
+                from_spend: ctx.spent_events.len(),
+                from_sent: ctx.sent_events.len(),
+                last_block: ctx.currnet_block_number,
+            };
+            let (spent_events, sent_events, next) = if sharded {
+                // With `--shard-peers`, a round fetches a page from each of the best peers at
+                // once instead of everything from the elected peer.
+                let range = ctx.node_manager.shard_peers * ctx.node_manager.events_page_size;
+                ctx.node_manager.get_events_sharded(range, &cursor).await?
+            } else {
+                // With `--verify-sample-blocks`, the elected peer's events are checked against
+                // the provider before they are taken over.
+                ctx.node_manager
+                    .get_verified_events(&cursor, &CancellationToken::new())
+                    .await?
+            };
+            let peer_current_block_number = next.last_block;
 
             if peer_current_block_number >= ctx.currnet_block_number {
                 ctx.spent_events.extend(spent_events.clone());
                 ctx.sent_events.extend(sent_events.clone());
+                ctx.events_commitment.extend(&spent_events, &sent_events);
                 ctx.currnet_block_number = peer_current_block_number;
+                record_cursor(&ctx);
 
                 log::info!(
-                    "new events: {} spent, {} sent",
-                    spent_events.len(),
-                    sent_events.len()
+                    spent = spent_events.len(), sent = sent_events.len(), block = peer_current_block_number;
+                    "New events"
                 );
             } else {
                 log::info!("No new events");
@@ -220,28 +499,42 @@ async fn update_events(context: Arc<Mutex<NodeContext>>) -> Result<(), eyre::Rep
                     .network
                     .as_ref()
                     .unwrap()
-                    .config
-                    .owshen_contract_deployment_block_number
-                    .as_u64(),
+                    .deployment_block(),
             );
             let node_manager = ctx.node_manager.clone();
             drop(ctx);
 
-            let curr_block_number = network.provider.get_block_number().await?.as_u64();
+            let curr_block_number = node_manager.latest_confirmed_block().await?;
 
-            let spent_events = node_manager.get_spend_events(curr, curr_block_number).await;
-
-            let sent_events = node_manager.get_sent_events(curr, curr_block_number).await;
+            // With `max_blocks_per_call` set, a round may stop short of the confirmed block;
+            // the next round carries on from where it did.
+            let events = async {
+                let (spent_events, reached) = node_manager
+                    .get_spend_events_capped(curr, curr_block_number)
+                    .await?;
+                let (sent_events, _) = node_manager.get_sent_events_capped(curr, reached).await?;
+                Ok::<_, eyre::Report>((spent_events, sent_events, reached))
+            }
+            .await;
+            // Leave the block cursor untouched so the same range is retried next round.
+            let (spent_events, sent_events, curr_block_number) = match events {
+                Ok(events) => events,
+                Err(e) => {
+                    log::error!(block = curr, error:% = e; "Failed to fetch events");
+                    return Ok(());
+                }
+            };
 
             log::info!(
-                "New events: {} spent, {} sent",
-                spent_events.len(),
-                sent_events.len()
+                spent = spent_events.len(), sent = sent_events.len(), block = curr_block_number;
+                "New events"
             );
             ctx = context.lock().await;
+            ctx.events_commitment.extend(&spent_events, &sent_events);
             ctx.spent_events.extend(spent_events);
             ctx.sent_events.extend(sent_events);
             ctx.currnet_block_number = curr_block_number;
+            record_cursor(&ctx);
         }
     } else {
         log::error!("Provider is not set");
@@ -250,6 +543,15 @@ async fn update_events(context: Arc<Mutex<NodeContext>>) -> Result<(), eyre::Rep
     Ok(())
 }
 
+/// Records how far the node's events reach, for `NodeManager::shutdown` to save.
+fn record_cursor(ctx: &NodeContext) {
+    ctx.node_manager.record_cursor(&SyncCursor {
+        from_spend: ctx.spent_events.len(),
+        from_sent: ctx.sent_events.len(),
+        last_block: ctx.currnet_block_number,
+    });
+}
+
 fn handle_error<T: IntoResponse>(result: Result<T, eyre::Report>) -> impl IntoResponse {
     match result {
         Ok(a) => a.into_response(),
@@ -1,4 +1,4 @@
-use std::{path::PathBuf, str::FromStr, sync::Arc};
+use std::{path::PathBuf, sync::Arc};
 
 use bindings::dive_token::DiveToken;
 
@@ -14,7 +14,7 @@ use structopt::StructOpt;
 
 use crate::{
     config::Wallet,
-    helper::{proof_to_groth16_proof, to_wei},
+    helper::{parse_address, proof_to_groth16_proof, to_wei},
     proof::spend_prove,
 };
 
@@ -62,7 +62,7 @@ pub async fn spend(_opt: SpendOpt, wallet_path: PathBuf) {
         panic!("Error: failed to parse private key: {:?}", e);
     });
 
-    let token_h160_address = H160::from_str(&_opt.token_address).unwrap_or_else(|e| {
+    let token_h160_address = parse_address(&_opt.token_address).unwrap_or_else(|e| {
         panic!("Error: failed to parse token address: {:?}", e);
     });
 
@@ -25,7 +25,7 @@ use tower_http::cors::CorsLayer;
 use crate::{
     apis,
     checkpointed_hashchain::CheckpointedHashchain,
-    config::{Config, Context, EventsLatestStatus, NodeManager, Peer, Wallet},
+    config::{Config, Context, EventsLatestStatus, NodeManager, Peer, Wallet, PEER_SYNC_INTERVAL},
     genesis::Genesis,
     keys::{PrivateKey, PublicKey},
 };
@@ -66,6 +66,8 @@ pub struct WalletOpt {
     bootstrap_peers: Vec<Peer>,
     #[structopt(long)]
     peer2peer: bool,
+    #[structopt(long)]
+    verify_sample_blocks: Option<u64>,
     #[structopt(long, help = "Select mode: test, appimage, windows")]
     mode: Mode,
     #[structopt(long)]
@@ -80,6 +82,7 @@ pub async fn wallet(opt: WalletOpt, wallet_path: PathBuf) -> Result<(), eyre::Re
         port,
         bootstrap_peers,
         peer2peer,
+        verify_sample_blocks,
         mode,
         dev,
     } = opt;
@@ -98,6 +101,7 @@ pub async fn wallet(opt: WalletOpt, wallet_path: PathBuf) -> Result<(), eyre::Re
         wallet_path,
         bootstrap_peers,
         peer2peer,
+        verify_sample_blocks,
         mode,
         dev,
         forced_config,
@@ -131,11 +135,14 @@ async fn serve_wallet(
     wallet_path: PathBuf,
     bootstrap_peers: Vec<Peer>,
     peer2peer: bool,
+    verify_sample_blocks: Option<u64>,
     mode: Mode,
     dev: bool,
     forced_config: Option<Config>,
 ) -> Result<()> {
     let genesis: Genesis = bincode::deserialize(&std::fs::read(GENESIS_FILE.clone())?)?;
+    let mut node_manager = NodeManager::new(None, None, bootstrap_peers, peer2peer, true)?;
+    node_manager.set_event_verification(verify_sample_blocks);
 
     let context = Arc::new(Mutex::new(Context {
         coins: vec![],
@@ -145,16 +152,7 @@ async fn serve_wallet(
             last_sent_event: 0,
             last_spent_event: 0,
         },
-        node_manager: NodeManager {
-            external_addr: None,
-
-            network: None,
-            peers: bootstrap_peers,
-            elected_peer: None,
-            is_peer2peer: peer2peer,
-
-            is_client: true,
-        },
+        node_manager,
         syncing: Arc::new(std::sync::Mutex::new(None)),
         syncing_task: None,
     }));
@@ -163,30 +161,13 @@ async fn serve_wallet(
         context.lock().await.switch_network(conf)?;
     }
 
-    if peer2peer {
-        let context_sync = context.clone();
-        tokio::spawn(async move {
-            loop {
-                if let Err(e) = async {
-                    log::info!("Syncing with peers...");
-                    let now = std::time::Instant::now();
-
-                    let mut node_manager = context_sync.lock().await.node_manager.clone();
-                    node_manager.sync_with_peers().await?;
-
-                    context_sync.lock().await.node_manager = node_manager;
-
-                    log::info!("Syncing with peers took: {:?}", now.elapsed());
-                    Ok::<(), eyre::Report>(())
-                }
-                .await
-                {
-                    log::error!("Error occurred while syncing with peers: {}", e);
-                }
-                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-            }
-        });
-    }
+    let sync_loop = peer2peer.then(|| {
+        NodeManager::spawn_sync_loop(
+            context.clone(),
+            |ctx| &mut ctx.node_manager,
+            std::time::Duration::from_secs(PEER_SYNC_INTERVAL),
+        )
+    });
 
     let context_coin = context.clone();
     let context_withdraw = context.clone();
@@ -366,6 +347,9 @@ async fn serve_wallet(
         }
 
         server.await.map_err(eyre::Report::new)?;
+        if let Some(sync_loop) = sync_loop {
+            sync_loop.stop().await;
+        }
         Ok(())
     }
 }
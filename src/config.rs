@@ -1,7 +1,15 @@
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use bindings::owshen::{SentFilter, SpendFilter};
 use ethers::{abi::Abi, prelude::*, types::H160};
+use rand::rngs::StdRng;
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -11,10 +19,15 @@ use crate::{
     hash::hash2,
     helper::u256_to_h160,
     keys::{Entropy, PrivateKey, PublicKey},
+    network::{
+        CertFingerprint, Clock, ElectionChange, ElectionStrategy, EventCache, EventsCommitment,
+        PeerOrder, PeerTransport, RateLimiter, SyncCounters,
+    },
 };
 
 use sha2::{Digest, Sha256};
 pub const NODE_UPDATE_INTERVAL: u64 = 5;
+pub const PEER_SYNC_INTERVAL: u64 = 5;
 
 pub struct Context {
     pub coins: Vec<Coin>,
@@ -41,10 +54,13 @@ impl Context {
         {
             return Ok(());
         }
-        let provider: Arc<Provider<Http>> =
-            Arc::new(Provider::<Http>::try_from(config.endpoint.clone())?);
-        self.node_manager
-            .set_provider_network(Network { provider, config });
+        let endpoint = config.endpoint.clone();
+        let mut network = Network::connect(&endpoint, None, config)?;
+        network
+            .config
+            .token_contracts
+            .set_provider(network.config.name.clone(), network.provider.clone());
+        self.node_manager.set_provider_network(network);
         self.coins.clear();
 
         Ok(())
@@ -69,6 +85,8 @@ pub struct NodeContext {
     pub spent_events: Vec<SpendFilter>,
     pub sent_events: Vec<SentFilter>,
     pub currnet_block_number: u64,
+    /// Commitment to `spent_events` and `sent_events`, advertised in handshakes.
+    pub events_commitment: EventsCommitment,
 
     pub mempool: Vec<OwshenTransaction>,
 }
@@ -76,35 +94,129 @@ pub struct NodeContext {
 #[derive(Clone, Debug)]
 pub struct Network {
     pub provider: Arc<Provider<Http>>,
+    /// Optional WebSocket connection to the same chain, preferred for event queries and
+    /// subscriptions when set.
+    pub ws_provider: Option<Arc<Provider<Ws>>>,
+    /// The client `provider` sends its requests with, for RPC calls ethers can't make, such
+    /// as batches. Clones share its connection pool.
+    pub rpc_client: reqwest::Client,
     pub config: Config,
 }
 
+/// URL scheme used to reach a peer. Peers advertised without one are plain http.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerScheme {
+    #[default]
+    Http,
+    Https,
+}
+
+impl std::fmt::Display for PeerScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerScheme::Http => write!(f, "http"),
+            PeerScheme::Https => write!(f, "https"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Peer {
+    #[serde(default)]
+    pub scheme: PeerScheme,
     pub addr: SocketAddr,
     pub current_block: u64,
+
+    // Local observations about the peer, never exchanged with other nodes.
+    #[serde(skip, default = "default_peer_health")]
+    pub health: f64,
+    #[serde(skip)]
+    pub latency: Option<Duration>,
+    #[serde(skip)]
+    pub max_events_page: Option<usize>,
+    /// Token sent to this peer instead of our own, never gossiped to other nodes.
+    #[serde(skip)]
+    pub auth_token: Option<String>,
+    /// Timeout for requests to this peer instead of the manager's, never gossiped either.
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+}
+
+fn default_peer_health() -> f64 {
+    1.0
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr) -> Peer {
+        Peer {
+            scheme: PeerScheme::Http,
+            addr,
+            current_block: 0,
+            health: default_peer_health(),
+            latency: None,
+            max_events_page: None,
+            auth_token: None,
+            timeout: None,
+        }
+    }
 }
 
 impl FromStr for Peer {
     type Err = eyre::Report;
 
+    /// Parses `host:port`, optionally prefixed with `http://` or `https://` and with a bearer
+    /// token as in `https://token@host:port`. A `?timeout=<milliseconds>` suffix sets the
+    /// peer's own request timeout.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let addr: SocketAddr = s
+        let (s, timeout) = match s.split_once('?') {
+            Some((s, query)) => {
+                let millis = query
+                    .strip_prefix("timeout=")
+                    .and_then(|millis| millis.parse::<u64>().ok())
+                    .filter(|millis| *millis > 0)
+                    .ok_or_else(|| eyre::eyre!("Invalid peer timeout"))?;
+                (s, Some(Duration::from_millis(millis)))
+            }
+            None => (s, None),
+        };
+        let (scheme, rest) = if let Some(rest) = s.strip_prefix("https://") {
+            (PeerScheme::Https, rest)
+        } else if let Some(rest) = s.strip_prefix("http://") {
+            (PeerScheme::Http, rest)
+        } else {
+            (PeerScheme::Http, s)
+        };
+        let (auth_token, rest) = match rest.split_once('@') {
+            Some((token, rest)) if !token.is_empty() => (Some(token.to_string()), rest),
+            _ => (None, rest),
+        };
+        let addr: SocketAddr = rest
+            .trim_end_matches('/')
             .parse()
             .map_err(|_| eyre::eyre!("Invalid socket address"))?;
-        Ok(Peer {
-            addr,
-            current_block: 0,
-        })
+        let mut peer = Peer::new(addr);
+        peer.scheme = scheme;
+        peer.auth_token = auth_token;
+        peer.timeout = timeout;
+        Ok(peer)
     }
 }
 
 impl PartialEq for Peer {
     fn eq(&self, other: &Self) -> bool {
-        self.addr == other.addr
+        self.normalized_addr() == other.normalized_addr()
     }
 }
 
+/// Position reached in a peer's event streams, stored by callers to resume fetching.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncCursor {
+    pub from_spend: usize,
+    pub from_sent: usize,
+    pub last_block: u64,
+}
+
 #[derive(Clone, Debug)]
 pub struct EventsLatestStatus {
     pub last_sent_event: usize,
@@ -116,16 +228,161 @@ pub struct NodeManager {
     pub external_addr: Option<SocketAddr>,
 
     pub network: Option<Network>,
+    /// Further providers tried in order when `network` fails a query.
+    pub backup_networks: Vec<Network>,
+    /// Peers the manager was created with, added back if the peer list ever runs empty.
+    pub bootstrap_peers: Vec<Peer>,
+    /// DNS seeds resolved again if the peer list ever runs empty.
+    pub seeds: Vec<String>,
     pub peers: Vec<Peer>,
     pub elected_peer: Option<Peer>,
+    /// When `elected_peer` was last elected, by `clock`.
+    pub elected_at: Option<Instant>,
+    /// Oldest an election may be before fetching from the elected peer syncs again first.
+    pub max_election_age: Option<Duration>,
     pub is_peer2peer: bool,
 
     pub is_client: bool,
+
+    pub transport: Arc<dyn PeerTransport>,
+    pub http_timeout: Duration,
+    pub connection_pool: ConnectionPoolConfig,
+    /// Certificates https peers must present, see `NodeManager::set_pinned_certificates`.
+    pub pinned_certificates: Vec<CertFingerprint>,
+    pub handshake_timeout: Duration,
+    pub peers_timeout: Duration,
+    pub events_timeout: Duration,
+    pub max_handshake_retries: u32,
+    pub handshake_retry_backoff: Duration,
+    /// Handshake retries all peers of a `sync_with_peers` round share, unlimited when `None`.
+    pub sync_retry_budget: Option<u32>,
+    pub peer_failures: HashMap<SocketAddr, u32>,
+    pub clock: Arc<dyn Clock>,
+    /// Time over which a peer recovers from zero to full health.
+    pub health_recovery_window: Duration,
+    /// When peer health was last recovered, see `health_recovery_window`.
+    pub last_decay: Option<Instant>,
+    /// Peers that may not be added again until the given time.
+    pub blacklist: HashMap<SocketAddr, Instant>,
+    /// How often each peer was evicted for failing handshakes, to lengthen its next ban.
+    pub blacklist_strikes: HashMap<SocketAddr, u32>,
+    pub blacklist_duration: Duration,
+    /// Failed event requests in a row after which the elected peer's circuit breaker trips.
+    pub breaker_threshold: u32,
+    /// How long a peer whose circuit breaker tripped is left out of elections.
+    pub breaker_cooldown: Duration,
+    /// Peers that may not be elected until the given time.
+    pub tripped_peers: HashMap<SocketAddr, Instant>,
+    pub handshake_concurrency: usize,
+    pub election_block_tolerance: u64,
+    /// Lowest block a peer must report to be elected.
+    pub min_elect_block: u64,
+    /// Notified of changes of the elected peer, shared by every clone.
+    pub election_changes: tokio::sync::broadcast::Sender<ElectionChange>,
+    pub event_scan: EventScanConfig,
+    pub rpc_query_timeout: Duration,
+    #[cfg(test)]
+    pub event_poll_interval: Duration,
+    pub max_peers: usize,
+    pub peer_order: PeerOrder,
+    pub events_page_size: usize,
+    /// How many peers `NodeManager::get_events_sharded` spreads its shards across.
+    pub shard_peers: usize,
+    pub verify_sample_blocks: Option<u64>,
+    pub provider_fallback: bool,
+    pub min_peer_version: u32,
+    pub event_cache: Arc<std::sync::Mutex<EventCache>>,
+    /// Timestamps of confirmed blocks, see `NodeManager::block_timestamps`.
+    pub block_timestamps: Arc<std::sync::Mutex<HashMap<u64, u64>>>,
+    pub confirmations: u64,
+    /// Most blocks the `_capped` event queries scan per call, unlimited when `None`.
+    pub max_blocks_per_call: Option<u64>,
+    pub stale_peer_lag: Option<u64>,
+    /// Longest a whole `sync_with_peers` round may take, unlimited when `None`.
+    pub sync_round_timeout: Option<Duration>,
+    /// Token we send to peers, unless a peer carries its own.
+    pub auth_token: Option<String>,
+    /// Identity sent to peers with every request, see `network::NODE_ID_HEADER`.
+    pub node_id: Option<String>,
+    /// Cap on requests sent to peers per second, unlimited when `None`.
+    pub max_requests_per_second: Option<f64>,
+    /// Largest response body read from a peer, in bytes.
+    pub max_response_body: usize,
+    /// Where `shutdown` saves the peer list and sync cursor.
+    pub state_dir: Option<PathBuf>,
+    /// Last cursor recorded with `record_cursor`, shared by every clone.
+    pub last_cursor: Arc<std::sync::Mutex<Option<SyncCursor>>>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub election_rng: Arc<std::sync::Mutex<StdRng>>,
+    pub election_strategy: Arc<dyn ElectionStrategy>,
+    pub counters: Arc<SyncCounters>,
+}
+
+/// Block-range sizes used when scanning the provider for contract events.
+#[derive(Clone, Debug)]
+pub struct EventScanConfig {
+    pub base_step: u64,
+    pub min_step: u64,
+    pub max_step: u64,
+    pub max_consecutive_failures: u32,
+    /// Times a chunk is queried again as is after an RPC error other than a timeout, before
+    /// it's shrunk like a chunk that timed out.
+    pub transient_retries: u32,
+}
+
+impl Default for EventScanConfig {
+    fn default() -> Self {
+        EventScanConfig {
+            base_step: 1024,
+            min_step: 1,
+            max_step: 4096,
+            max_consecutive_failures: 20,
+            transient_retries: 2,
+        }
+    }
+}
+
+/// Limits on the connections the shared HTTP client keeps open to peers between requests.
+///
+/// The defaults keep a couple of connections per peer, enough for a handshake and an events
+/// page in flight, and hold them long enough to be reused by the next sync round. With the
+/// default peer limit that stays around a hundred idle sockets at most; nodes with many more
+/// peers or a tight file-descriptor limit can lower either value.
+#[derive(Clone, Debug)]
+pub struct ConnectionPoolConfig {
+    pub max_idle_per_host: usize,
+    /// How long an idle connection is kept, forever when `None`.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for ConnectionPoolConfig {
+    fn default() -> Self {
+        ConnectionPoolConfig {
+            max_idle_per_host: 2,
+            idle_timeout: Some(Duration::from_secs(30)),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NetworkManager {
     pub networks: HashMap<String, Vec<TokenInfo>>,
+    /// Token each network pays fees in, keyed like `networks`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub native_tokens: HashMap<String, NativeToken>,
+    /// Providers `discover_token` queries, keyed like `networks`.
+    #[serde(skip)]
+    pub providers: HashMap<String, Arc<Provider<Http>>>,
+    /// When `discover_token` last failed for a token, keyed by network and address.
+    #[serde(skip)]
+    pub discovery_failures: HashMap<(String, H160), Instant>,
+}
+
+/// A network's native token, which has no contract address.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NativeToken {
+    pub symbol: String,
+    pub decimals: u8,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -140,10 +397,50 @@ pub struct Coin {
     pub memo: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TokenInfo {
     pub token_address: H160,
     pub symbol: String,
+    #[serde(default = "default_token_decimals")]
+    pub decimals: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<u64>,
+}
+
+// Token lists written before decimals were recorded only held 18-decimal tokens.
+fn default_token_decimals() -> u8 {
+    18
+}
+
+impl TokenInfo {
+    /// Renders a raw on-chain amount in whole tokens, e.g. `1500000000000000000` as `1.5`
+    /// for an 18-decimal token. Trailing fractional zeros are dropped.
+    pub fn format_amount(&self, raw: U256) -> String {
+        let digits = raw.to_string();
+        let decimals = self.decimals as usize;
+        if decimals == 0 {
+            return digits;
+        }
+        let padded = format!("{:0>width$}", digits, width = decimals + 1);
+        let (whole, fraction) = padded.split_at(padded.len() - decimals);
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.is_empty() {
+            whole.to_string()
+        } else {
+            format!("{}.{}", whole, fraction)
+        }
+    }
+}
+
+impl Default for TokenInfo {
+    fn default() -> TokenInfo {
+        TokenInfo {
+            token_address: H160::zero(),
+            symbol: String::new(),
+            decimals: default_token_decimals(),
+            chain_id: None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -255,6 +552,14 @@ pub struct Config {
     pub token_contracts: NetworkManager,
     pub poseidon4_contract_address: H160,
     pub poseidon2_contract_address: H160,
+    /// Headers sent with every request to `endpoint`, e.g. an API key for RPC providers that
+    /// expect it in a header rather than in the URL.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub rpc_headers: HashMap<String, String>,
+    /// Deposit index the contract was deployed with, i.e. that of its first sent event, which
+    /// follows the genesis deposits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genesis_deposit_index: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
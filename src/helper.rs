@@ -3,6 +3,7 @@ use crate::keys::PublicKey;
 use crate::{fp::Fp, proof::Proof};
 use bindings::dive_token::Groth16Proof;
 use ethers::types::{H160, U256};
+use std::str::FromStr;
 
 pub fn extract_token_amount(
     hint_token_address: U256,
@@ -66,6 +67,22 @@ pub fn u256_to_h160(u256: U256) -> H160 {
     H160::from_slice(address_bytes)
 }
 
+/// Parses a hex address, with or without the `0x` prefix. Mixed-case input must carry a valid
+/// EIP-55 checksum; all-lowercase and all-uppercase input has none and is taken as is.
+pub fn parse_address(s: &str) -> Result<H160, eyre::Report> {
+    let hex = s.strip_prefix("0x").unwrap_or(s);
+    if hex.len() != 40 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(eyre::eyre!("Invalid address {}: expected 40 hex digits", s));
+    }
+    let address = H160::from_str(hex)?;
+    let mixed_case =
+        hex.chars().any(|c| c.is_ascii_lowercase()) && hex.chars().any(|c| c.is_ascii_uppercase());
+    if mixed_case && ethers::utils::to_checksum(&address, None)[2..] != *hex {
+        return Err(eyre::eyre!("Invalid address {}: checksum mismatch", s));
+    }
+    Ok(address)
+}
+
 pub fn h160_to_u256(h160_val: H160) -> U256 {
     let mut bytes = [0u8; 32];
     bytes[12..32].copy_from_slice(h160_val.as_bytes());
@@ -87,3 +104,26 @@ pub fn proof_to_groth16_proof(proof: Proof) -> Groth16Proof {
         c: [proof.c[0].into(), proof.c[1].into()],
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        let weth = "0xdD69DB25F6D620A7baD3023c5d32761D353D3De9";
+        let address = parse_address(weth).unwrap();
+        assert_eq!(parse_address(&weth[2..]).unwrap(), address);
+        assert_eq!(parse_address(&weth.to_lowercase()).unwrap(), address);
+        assert_eq!(
+            parse_address(&format!("0x{}", weth[2..].to_uppercase())).unwrap(),
+            address
+        );
+
+        // One letter with the wrong case breaks the checksum.
+        assert!(parse_address("0xDD69DB25F6D620A7baD3023c5d32761D353D3De9").is_err());
+        assert!(parse_address("0xdD69DB25F6D620A7baD3023c5d32761D353D3De").is_err());
+        assert!(parse_address("0xdD69DB25F6D620A7baD3023c5d32761D353D3Dzz").is_err());
+        assert!(parse_address("").is_err());
+    }
+}
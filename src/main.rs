@@ -7,6 +7,8 @@ mod genesis;
 mod hash;
 mod helper;
 mod keys;
+#[cfg(test)]
+mod mock_peer;
 mod network;
 mod poseidon;
 mod proof;
@@ -29,7 +31,7 @@ enum OwshenCliOpt {
     Info(commands::InfoOpt),
     Wallet(commands::WalletOpt),
     Deploy(commands::DeployOpt),
-    Node(commands::NodeOpt),
+    Node(Box<commands::NodeOpt>),
     Burn(commands::BurnOpt),
     Mint(commands::MintOpt),
     Dive(commands::DiveOpt),
@@ -88,7 +90,7 @@ async fn main() -> Result<()> {
             commands::info(info_opt, wallet_path).await?;
         }
         OwshenCliOpt::Node(node_opt) => {
-            commands::node(node_opt).await?;
+            commands::node(*node_opt).await?;
         }
 
         OwshenCliOpt::Burn(burn_opt) => {
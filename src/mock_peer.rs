@@ -0,0 +1,285 @@
+//! In-process stand-in for a peer node, serving `/handshake`, `/get-peers` and `/events` from
+//! canned data on an ephemeral local port, so that networking tests have a real HTTP target.
+//! Each endpoint can be made to misbehave with a `Fault`, also while the peer is running.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use bindings::owshen::{SentFilter, SpendFilter};
+use ethers::types::H256;
+
+use crate::{
+    apis::{GetEventsRequest, GetEventsResponse, GetHandShakeResponse, GetPeersResponse},
+    config::Peer,
+    network::PROTOCOL_VERSION,
+};
+
+/// An endpoint of the mock peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Handshake,
+    GetPeers,
+    Events,
+}
+
+/// How an endpoint misbehaves instead of answering normally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Never answers, leaving the request to time out.
+    Hang,
+    /// Answers with the given status and an empty body, e.g. a 5xx.
+    Status(StatusCode),
+    /// Answers 200 with a body that is cut off half way through the JSON.
+    MalformedJson,
+    /// Only for `/events`: serves the requested spend events but at most one sent event per
+    /// page, like a peer whose streams are paged unevenly.
+    Lopsided,
+}
+
+#[derive(Debug, Default)]
+struct MockPeerState {
+    current_block: u64,
+    max_events_page: Option<usize>,
+    version: Option<u32>,
+    events_commitment: Option<H256>,
+    peers: Vec<Peer>,
+    spend_events: Vec<SpendFilter>,
+    sent_events: Vec<SentFilter>,
+    faults: HashMap<Endpoint, Fault>,
+    requests: HashMap<Endpoint, usize>,
+}
+
+impl MockPeerState {
+    /// Counts the request and returns the fault it should get, if any.
+    fn request(&mut self, endpoint: Endpoint) -> Option<Fault> {
+        *self.requests.entry(endpoint).or_default() += 1;
+        self.faults.get(&endpoint).copied()
+    }
+}
+
+/// Builds and spawns a mock peer. By default it is at block 0 with no peers and no events,
+/// speaks the current protocol version and serves pages of any length.
+#[derive(Debug)]
+pub struct MockPeer {
+    state: MockPeerState,
+}
+
+impl MockPeer {
+    pub fn new() -> MockPeer {
+        MockPeer {
+            state: MockPeerState {
+                version: Some(PROTOCOL_VERSION),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn current_block(mut self, block: u64) -> Self {
+        self.state.current_block = block;
+        self
+    }
+
+    pub fn max_events_page(mut self, max: Option<usize>) -> Self {
+        self.state.max_events_page = max;
+        self
+    }
+
+    pub fn version(mut self, version: Option<u32>) -> Self {
+        self.state.version = version;
+        self
+    }
+
+    pub fn events_commitment(mut self, commitment: Option<H256>) -> Self {
+        self.state.events_commitment = commitment;
+        self
+    }
+
+    pub fn peers(mut self, peers: Vec<Peer>) -> Self {
+        self.state.peers = peers;
+        self
+    }
+
+    pub fn events(mut self, spend_events: Vec<SpendFilter>, sent_events: Vec<SentFilter>) -> Self {
+        self.state.spend_events = spend_events;
+        self.state.sent_events = sent_events;
+        self
+    }
+
+    pub fn fault(mut self, endpoint: Endpoint, fault: Fault) -> Self {
+        self.state.faults.insert(endpoint, fault);
+        self
+    }
+
+    /// Starts serving on an ephemeral port of 127.0.0.1. The server runs until the test's
+    /// runtime shuts down.
+    pub fn spawn(self) -> MockPeerHandle {
+        let state = Arc::new(Mutex::new(self.state));
+        let handshake_state = state.clone();
+        let get_peers_state = state.clone();
+        let events_state = state.clone();
+
+        let app = Router::new()
+            .route(
+                "/handshake",
+                get(move || async move { handshake(&handshake_state).await }),
+            )
+            .route(
+                "/get-peers",
+                get(move || async move { get_peers(&get_peers_state).await }),
+            )
+            .route(
+                "/events",
+                get(move |Query(req): Query<GetEventsRequest>| async move {
+                    events(&events_state, req).await
+                }),
+            );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+        MockPeerHandle { addr, state }
+    }
+}
+
+impl Default for MockPeer {
+    fn default() -> Self {
+        MockPeer::new()
+    }
+}
+
+/// A running mock peer, see `MockPeer::spawn`.
+#[derive(Clone, Debug)]
+pub struct MockPeerHandle {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockPeerState>>,
+}
+
+impl MockPeerHandle {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// A fresh `Peer` entry for this mock, as a node would add it.
+    pub fn peer(&self) -> Peer {
+        Peer::new(self.addr)
+    }
+
+    /// Makes `endpoint` misbehave from the next request on, or behave again with `None`.
+    pub fn set_fault(&self, endpoint: Endpoint, fault: Option<Fault>) {
+        let mut state = self.state.lock().unwrap();
+        match fault {
+            Some(fault) => state.faults.insert(endpoint, fault),
+            None => state.faults.remove(&endpoint),
+        };
+    }
+
+    pub fn set_current_block(&self, block: u64) {
+        self.state.lock().unwrap().current_block = block;
+    }
+
+    /// Appends to the events served from now on.
+    pub fn push_events(&self, spend_events: Vec<SpendFilter>, sent_events: Vec<SentFilter>) {
+        let mut state = self.state.lock().unwrap();
+        state.spend_events.extend(spend_events);
+        state.sent_events.extend(sent_events);
+    }
+
+    /// How many requests `endpoint` has received, faulty ones included.
+    pub fn requests(&self, endpoint: Endpoint) -> usize {
+        let state = self.state.lock().unwrap();
+        state.requests.get(&endpoint).copied().unwrap_or_default()
+    }
+}
+
+/// Answers with `fault`, or with `value` as JSON if there is none. Only a `Hang` awaits.
+async fn respond<T: serde::Serialize>(fault: Option<Fault>, value: T) -> Response {
+    match fault {
+        Some(Fault::Hang) => std::future::pending::<Response>().await,
+        Some(Fault::Status(status)) => status.into_response(),
+        Some(Fault::MalformedJson) => {
+            let body = serde_json::to_string(&value).unwrap();
+            (
+                [(header::CONTENT_TYPE, "application/json")],
+                body[..body.len() / 2].to_string(),
+            )
+                .into_response()
+        }
+        Some(Fault::Lopsided) | None => Json(value).into_response(),
+    }
+}
+
+async fn handshake(state: &Mutex<MockPeerState>) -> Response {
+    let (fault, resp) = {
+        let mut state = state.lock().unwrap();
+        let fault = state.request(Endpoint::Handshake);
+        let resp = GetHandShakeResponse {
+            current_block_number: state.current_block,
+            max_events_page: state.max_events_page,
+            version: state.version,
+            events_commitment: state.events_commitment,
+        };
+        (fault, resp)
+    };
+    respond(fault, resp).await
+}
+
+async fn get_peers(state: &Mutex<MockPeerState>) -> Response {
+    let (fault, resp) = {
+        let mut state = state.lock().unwrap();
+        let fault = state.request(Endpoint::GetPeers);
+        let resp = GetPeersResponse {
+            peers: state.peers.clone(),
+        };
+        (fault, resp)
+    };
+    respond(fault, resp).await
+}
+
+async fn events(state: &Mutex<MockPeerState>, req: GetEventsRequest) -> Response {
+    let (fault, resp) = {
+        let mut state = state.lock().unwrap();
+        let fault = state.request(Endpoint::Events);
+        let length = state
+            .max_events_page
+            .map_or(req.length, |max| req.length.min(max));
+        let sent_length = if fault == Some(Fault::Lopsided) {
+            length.min(1)
+        } else {
+            length
+        };
+        let spend_events = if req.streams.includes_spend() {
+            page(&state.spend_events, req.from_spend, length)
+        } else {
+            vec![]
+        };
+        let sent_events = if req.streams.includes_sent() {
+            page(&state.sent_events, req.from_sent, sent_length)
+        } else {
+            vec![]
+        };
+        let resp = GetEventsResponse {
+            spend_events,
+            sent_events,
+            current_block_number: Some(state.current_block),
+        };
+        (fault, resp)
+    };
+    respond(fault, resp).await
+}
+
+fn page<E: Clone>(events: &[E], from: usize, length: usize) -> Vec<E> {
+    events.iter().skip(from).take(length).cloned().collect()
+}
@@ -1,321 +1,8839 @@
-use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    any::Any,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{self, AtomicU32, AtomicU64},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use bindings::owshen::{SentFilter, SpendFilter};
-use ethers::{contract::ContractInstance, prelude::*, types::ValueOrArray};
-use tokio::time::timeout;
+use ethers::{
+    abi::{RawLog, Tokenizable},
+    contract::ContractInstance,
+    prelude::*,
+    types::ValueOrArray,
+};
+use futures::{
+    future::BoxFuture,
+    stream::{self, Stream, StreamExt, TryStreamExt},
+    Future,
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+#[cfg(test)]
+use tokio::sync::mpsc;
+use tokio::{
+    sync::{broadcast, Mutex},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    apis::{GetEventsResponse, GetHandShakeResponse, GetPeersResponse},
-    config::{Network, NetworkManager, NodeManager, Peer, TokenInfo},
+    apis::{
+        EventStreams, GetEventsResponse, GetHandShakeResponse, GetPeersResponse,
+        GetReachabilityResponse,
+    },
+    config::{
+        Config, ConnectionPoolConfig, EventScanConfig, NativeToken, Network, NetworkManager,
+        NodeManager, Peer, SyncCursor, TokenInfo,
+    },
+    fp::Fp,
+    helper::parse_address,
 };
 
-impl NodeManager {
-    pub fn add_peer(&mut self, peer: Peer) {
-        if let Some(ext_addr) = self.external_addr.clone() {
-            if peer.addr == ext_addr {
-                return;
-            }
+/// Version of the node-to-node protocol spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version we can talk to. Peers that don't report a version are treated as
+/// version 0, which speaks the same protocol as version 1.
+pub const DEFAULT_MIN_PEER_VERSION: u32 = 0;
+
+/// Sent with every request to peers, e.g. `owshen/0.1.4`.
+pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+/// Header carrying the identity set with `NodeManager::set_node_id`.
+pub const NODE_ID_HEADER: &str = "x-owshen-node-id";
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(1);
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+pub const DEFAULT_PEERS_TIMEOUT: Duration = Duration::from_secs(3);
+pub const DEFAULT_EVENTS_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_MAX_HANDSHAKE_RETRIES: u32 = 2;
+pub const DEFAULT_HANDSHAKE_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+pub const DEFAULT_HANDSHAKE_CONCURRENCY: usize = 16;
+/// A full events page is a few hundred kilobytes, this leaves plenty of headroom.
+pub const DEFAULT_MAX_RESPONSE_BODY: usize = 16 * 1024 * 1024;
+pub const DEFAULT_ELECTION_BLOCK_TOLERANCE: u64 = 2;
+pub const DEFAULT_MAX_PEERS: usize = 64;
+pub const DEFAULT_EVENTS_PAGE_SIZE: usize = 256;
+pub const DEFAULT_SHARD_PEERS: usize = 4;
+const DEFAULT_SEED_PORT: u16 = 8888;
+pub const DEFAULT_RPC_QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_BLACKLIST_DURATION: Duration = Duration::from_secs(60);
+/// Repeated evictions double a peer's ban, up to this long.
+const MAX_BLACKLIST_DURATION: Duration = Duration::from_secs(3600);
+#[cfg(test)]
+pub const DEFAULT_EVENT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+#[cfg(test)]
+const EVENT_SUBSCRIPTION_BUFFER: usize = 256;
+/// Election changes kept for subscribers that haven't caught up yet.
+pub const ELECTION_CHANGE_BUFFER: usize = 16;
+/// Files `NodeManager::shutdown` saves in the state directory.
+pub const STATE_PEERS_FILE: &str = "peers.json";
+pub const STATE_CURSOR_FILE: &str = "cursor.json";
+/// Blocks `NodeManager::block_timestamps` asks for in one JSON-RPC batch, and how many of
+/// those batches it has in flight at once.
+pub const BLOCK_TIMESTAMP_BATCH: usize = 100;
+pub const BLOCK_TIMESTAMP_CONCURRENCY: usize = 4;
+pub const DEFAULT_BREAKER_THRESHOLD: u32 = 3;
+pub const DEFAULT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+pub const DEFAULT_HEALTH_RECOVERY_WINDOW: Duration = Duration::from_secs(600);
+/// How long `NetworkManager::discover_token` gives up on an address after failing on it.
+pub const TOKEN_DISCOVERY_RETRY: Duration = Duration::from_secs(60);
+/// Lower rates given to `NodeManager::set_max_requests_per_second` are raised to this one.
+pub const MIN_REQUESTS_PER_SECOND: f64 = 0.001;
+
+/// Running totals of network activity, shared by every clone of a `NodeManager`.
+#[derive(Debug, Default)]
+pub struct SyncCounters {
+    handshakes_attempted: AtomicU64,
+    handshakes_succeeded: AtomicU64,
+    handshakes_failed: AtomicU64,
+    peers_added: AtomicU64,
+    peers_removed: AtomicU64,
+    spend_events_fetched: AtomicU64,
+    sent_events_fetched: AtomicU64,
+    rpc_timeouts: AtomicU64,
+}
+
+type EventCacheKey = (String, u64, u64);
+
+/// Least-recently-used cache of decoded events, keyed by event name and block range. A
+/// capacity of zero disables it.
+#[derive(Debug, Default)]
+pub struct EventCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<EventCacheKey, (u64, Arc<dyn Any + Send + Sync>)>,
+}
+
+impl EventCache {
+    fn get<E: Clone + 'static>(&mut self, key: &EventCacheKey) -> Option<Vec<E>> {
+        self.tick += 1;
+        let (used, events) = self.entries.get_mut(key)?;
+        *used = self.tick;
+        events.downcast_ref::<Vec<E>>().cloned()
+    }
+
+    fn insert<E: Send + Sync + 'static>(&mut self, key: EventCacheKey, events: Vec<E>) {
+        if self.capacity == 0 {
+            return;
         }
+        self.tick += 1;
+        self.entries.insert(key, (self.tick, Arc::new(events)));
+        self.evict();
+    }
 
-        if !self.peers.contains(&peer) {
-            self.peers.push(peer);
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => self.entries.remove(&key),
+                None => break,
+            };
         }
     }
 
-    pub fn get_peers(&self) -> Vec<Peer> {
-        self.peers.clone()
+    #[cfg(test)]
+    fn clear(&mut self) {
+        self.entries.clear();
     }
+}
 
-    pub fn remove_peer(&mut self, peer: Peer) {
-        self.peers.retain(|p| p.addr != peer.addr);
+/// Point-in-time copy of `SyncCounters`, see `NodeManager::metrics`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SyncMetrics {
+    pub handshakes_attempted: u64,
+    pub handshakes_succeeded: u64,
+    pub handshakes_failed: u64,
+    pub peers_added: u64,
+    pub peers_removed: u64,
+    pub spend_events_fetched: u64,
+    pub sent_events_fetched: u64,
+    pub rpc_timeouts: u64,
+}
+
+fn bump(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, atomic::Ordering::Relaxed);
+}
+
+impl SyncCounters {
+    pub fn snapshot(&self) -> SyncMetrics {
+        let get = |c: &AtomicU64| c.load(atomic::Ordering::Relaxed);
+        SyncMetrics {
+            handshakes_attempted: get(&self.handshakes_attempted),
+            handshakes_succeeded: get(&self.handshakes_succeeded),
+            handshakes_failed: get(&self.handshakes_failed),
+            peers_added: get(&self.peers_added),
+            peers_removed: get(&self.peers_removed),
+            spend_events_fetched: get(&self.spend_events_fetched),
+            sent_events_fetched: get(&self.sent_events_fetched),
+            rpc_timeouts: get(&self.rpc_timeouts),
+        }
+    }
+}
+
+/// An event along with the block it was emitted in, see
+/// `NodeManager::query_events_with_timestamps`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimestampedEvent<E> {
+    pub event: E,
+    pub block_number: u64,
+    /// Unix time of the block, in seconds.
+    pub timestamp: u64,
+}
+
+/// The elected peer changed, see `NodeManager::subscribe_elections`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElectionChange {
+    pub previous: Option<Peer>,
+    pub elected: Option<Peer>,
+}
+
+/// Outcome of a single `sync_with_peers` round.
+#[derive(Clone, Debug, Default)]
+pub struct SyncReport {
+    /// Number of peers that answered the handshake.
+    pub reached: usize,
+    /// Peers that were removed from the peer list during the round.
+    pub dropped: Vec<Peer>,
+    pub elected: Option<Peer>,
+    pub max_block: u64,
+    /// Peers demoted for committing to different events than the majority at their height.
+    pub divergent: Vec<Peer>,
+    /// Whether the round was cut short by `sync_round_timeout`. Peers that hadn't answered by
+    /// then are left as they were and aren't counted anywhere in the report.
+    pub timed_out: bool,
+}
+
+/// Order of the peer list, see `NodeManager::set_peer_order`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PeerOrder {
+    /// The order peers were added in.
+    #[default]
+    Insertion,
+    Address,
+    /// Healthiest first, peers of equal health by address.
+    HealthThenAddress,
+}
+
+impl std::str::FromStr for PeerOrder {
+    type Err = eyre::Report;
+
+    /// Parses `insertion`, `address` or `health-then-address`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "insertion" => Ok(PeerOrder::Insertion),
+            "address" => Ok(PeerOrder::Address),
+            "health-then-address" => Ok(PeerOrder::HealthThenAddress),
+            _ => Err(eyre::eyre!("Unknown peer order: {}", s)),
+        }
+    }
+}
+
+/// One page of events fetched from the elected peer, see `NodeManager::event_batches`.
+#[derive(Clone, Debug)]
+pub struct EventBatch {
+    pub spend_events: Vec<SpendFilter>,
+    pub sent_events: Vec<SentFilter>,
+    /// Where to resume fetching after this batch.
+    pub cursor: SyncCursor,
+}
+
+/// How far `NodeManager::event_batches` has paged through the elected peer's events.
+struct EventPager {
+    peer: Peer,
+    cursor: SyncCursor,
+    streams: EventStreams,
+    step: usize,
+    max_step: usize,
+    seen_spends: HashSet<U256>,
+    last_sent: Option<SentFilter>,
+    peer_block: Option<u64>,
+    successes: u32,
+    done: bool,
+}
+
+/// Events of one shard of `NodeManager::get_events_sharded`.
+struct Shard {
+    spend_events: Vec<SpendFilter>,
+    sent_events: Vec<SentFilter>,
+    /// Positions past the shard's events.
+    cursor: SyncCursor,
+    /// Whether the peer ran out of spend, respectively sent, events within the shard.
+    spend_exhausted: bool,
+    sent_exhausted: bool,
+    /// Height of the peer that ran out of events, if one did.
+    tip: Option<u64>,
+}
+
+/// What a single handshake told about a peer, see `NodeManager::peer_status`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub addr: SocketAddr,
+    pub reachable: bool,
+    /// Height the peer reported, `None` if it couldn't be reached.
+    pub current_block: Option<u64>,
+    /// Round trip of the handshake, `None` if it couldn't be reached.
+    pub latency: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub enum NetworkError {
+    /// No provider network is configured for direct RPC queries.
+    ProviderNotSet,
+    /// An event scan gave up after too many consecutive failed queries. `from` is the first
+    /// block that wasn't scanned.
+    ScanAborted { from: u64, failures: u32 },
+    /// No peer has been elected yet; `sync_with_peers` has to run first.
+    NoElectedPeer,
+    /// Events served by the peer didn't match what the provider reports.
+    PeerVerificationFailed(SocketAddr),
+    /// A peer answered with a non-success HTTP status.
+    PeerStatus(reqwest::StatusCode),
+    /// A request to a peer didn't complete within its timeout.
+    Timeout(String),
+    /// A request to a peer failed before a response was received.
+    Request(String),
+    /// A peer answered with a body that couldn't be decoded.
+    Parse(String),
+    /// A peer's response at the given URL was longer than `max_response_body` allows.
+    BodyTooLarge { url: String, limit: usize },
+    /// The request needs our external address, which only nodes have.
+    NotANode,
+    /// Handshakes failed with every known peer.
+    AllPeersUnreachable,
+    /// No peer in the list has the given address.
+    #[cfg(test)]
+    UnknownPeer(String),
+    /// A provider query made on behalf of a peer operation failed.
+    Provider(String),
+    /// A peer at the given URL refused our credentials with a 401.
+    Unauthorized(String),
+    /// A peer served an events page that failed validation, see `validate_events_page`.
+    InvalidEventsPage(SocketAddr, String),
+    /// The elected peer failed `breaker_threshold` event requests in a row and is left out of
+    /// elections for `breaker_cooldown`.
+    PeerTripped(SocketAddr),
+    /// A capped event scan from the given block can't move forward because the block doesn't
+    /// have `confirmations` confirmations yet.
+    NotConfirmed(u64),
+}
+
+impl NetworkError {
+    fn request(url: &str, e: reqwest::Error) -> NetworkError {
+        if e.is_timeout() {
+            NetworkError::Timeout(url.to_string())
+        } else {
+            NetworkError::Request(format!("{}: {}", url, e))
+        }
     }
 
-    fn update_peer(&mut self, peer: Peer) {
-        self.remove_peer(peer.clone());
-        self.add_peer(peer);
+    fn parse(url: &str, e: serde_json::Error) -> NetworkError {
+        NetworkError::Parse(format!("{}: {}", url, e))
     }
 
-    pub async fn sync_with_peers(&mut self) -> Result<(), eyre::Report> {
-        let mut elected_peer: Option<Peer> = None;
-        let mut max_length: u64 = 0;
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(1))
-            .build()?;
+    /// Short stable name of the variant, logged as the `error.kind` field.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NetworkError::ProviderNotSet => "provider_not_set",
+            NetworkError::ScanAborted { .. } => "scan_aborted",
+            NetworkError::NoElectedPeer => "no_elected_peer",
+            NetworkError::PeerVerificationFailed(_) => "peer_verification_failed",
+            NetworkError::PeerStatus(_) => "peer_status",
+            NetworkError::Timeout(_) => "timeout",
+            NetworkError::Request(_) => "request",
+            NetworkError::Parse(_) => "parse",
+            NetworkError::BodyTooLarge { .. } => "body_too_large",
+            NetworkError::NotANode => "not_a_node",
+            NetworkError::AllPeersUnreachable => "all_peers_unreachable",
+            #[cfg(test)]
+            NetworkError::UnknownPeer(_) => "unknown_peer",
+            NetworkError::Provider(_) => "provider",
+            NetworkError::Unauthorized(_) => "unauthorized",
+            NetworkError::InvalidEventsPage(..) => "invalid_events_page",
+            NetworkError::PeerTripped(_) => "peer_tripped",
+            NetworkError::NotConfirmed(_) => "not_confirmed",
+        }
+    }
+}
 
-        for mut peer in self.get_peers() {
-            let mut url = format!(
-                "http://{}/handshake?is_client={}",
-                peer.addr, self.is_client
-            );
-            if !self.is_client {
-                url = format!(
-                    "{}&addr={}",
-                    url,
-                    self.external_addr
-                        .clone()
-                        .ok_or(eyre::eyre!("Caller not a node!"))?
-                );
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkError::ProviderNotSet => write!(f, "Provider is not set"),
+            NetworkError::ScanAborted { from, failures } => write!(
+                f,
+                "Event scan gave up at block {} after {} consecutive failed queries",
+                from, failures
+            ),
+            NetworkError::NoElectedPeer => write!(f, "No peer has been elected"),
+            NetworkError::PeerVerificationFailed(addr) => {
+                write!(f, "Events from peer {} failed verification", addr)
             }
-            let resp = client.get(&url).send().await;
-
-            if let Ok(resp) = resp {
-                if resp.status().is_success() {
-                    let body = resp.text().await;
-                    if let Ok(body) = body {
-                        let handshake: GetHandShakeResponse = serde_json::from_str(&body)?;
-                        log::info!(
-                            "Synced with peer: {} - {}",
-                            url,
-                            handshake.current_block_number
-                        );
-                        peer.current_block = handshake.current_block_number;
-                        self.update_peer(peer.clone());
+            NetworkError::PeerStatus(status) => write!(f, "Peer responded with {}", status),
+            NetworkError::Timeout(url) => write!(f, "Request to {} timed out", url),
+            NetworkError::Request(reason) => write!(f, "Request failed: {}", reason),
+            NetworkError::Parse(reason) => write!(f, "Invalid response: {}", reason),
+            NetworkError::BodyTooLarge { url, limit } => {
+                write!(f, "Response from {} is larger than {} bytes", url, limit)
+            }
+            NetworkError::NotANode => write!(f, "Caller not a node!"),
+            NetworkError::AllPeersUnreachable => write!(f, "No peer could be reached"),
+            #[cfg(test)]
+            NetworkError::UnknownPeer(addr) => write!(f, "{} is not a known peer", addr),
+            NetworkError::Provider(reason) => write!(f, "Provider query failed: {}", reason),
+            NetworkError::Unauthorized(url) => {
+                write!(f, "Peer at {} rejected our credentials", url)
+            }
+            NetworkError::InvalidEventsPage(addr, reason) => {
+                write!(f, "Peer {} served an invalid events page: {}", addr, reason)
+            }
+            NetworkError::PeerTripped(addr) => {
+                write!(f, "Peer {} failed too many event requests in a row", addr)
+            }
+            NetworkError::NotConfirmed(block) => {
+                write!(f, "Block {} isn't confirmed yet", block)
+            }
+        }
+    }
+}
 
-                        if handshake.current_block_number >= max_length {
-                            elected_peer = Some(peer.clone());
-                            max_length = handshake.current_block_number;
-                        }
+impl std::error::Error for NetworkError {}
 
-                        self._add_batch_peer_peers(peer.clone()).await?;
-                    } else {
-                        log::error!("Failed to parse response from peer: {}", url);
-                        self.remove_peer(peer.clone());
-                    }
-                } else {
-                    log::error!("Failed to sync with peer: {}", url);
-                    self.remove_peer(peer.clone());
+/// Provider helpers still report through eyre; a `NetworkError` they carry is kept as is.
+impl From<eyre::Report> for NetworkError {
+    fn from(e: eyre::Report) -> Self {
+        e.downcast::<NetworkError>()
+            .unwrap_or_else(|e| NetworkError::Provider(e.to_string()))
+    }
+}
+
+/// Status and body of a peer's answer to a GET request.
+#[derive(Clone, Debug)]
+pub struct PeerResponse {
+    pub status: reqwest::StatusCode,
+    pub body: String,
+}
+
+/// How requests reach peers. `reqwest::Client` is used unless another transport is set
+/// with `NodeManager::set_transport`.
+pub trait PeerTransport: std::fmt::Debug + Send + Sync {
+    /// Sends a GET request to `url`, with `bearer` as the `Authorization` token if given,
+    /// failing with `NetworkError::Timeout` if no full response arrives within `timeout` and
+    /// with `NetworkError::BodyTooLarge` as soon as the body grows past `max_body` bytes.
+    fn get<'a>(
+        &'a self,
+        url: &'a str,
+        bearer: Option<&'a str>,
+        timeout: Duration,
+        max_body: usize,
+    ) -> BoxFuture<'a, Result<PeerResponse, NetworkError>>;
+}
+
+impl PeerTransport for reqwest::Client {
+    fn get<'a>(
+        &'a self,
+        url: &'a str,
+        bearer: Option<&'a str>,
+        timeout: Duration,
+        max_body: usize,
+    ) -> BoxFuture<'a, Result<PeerResponse, NetworkError>> {
+        Box::pin(async move {
+            let mut request = reqwest::Client::get(self, url).timeout(timeout);
+            if let Some(token) = bearer {
+                request = request.bearer_auth(token);
+            }
+            let mut resp = request
+                .send()
+                .await
+                .map_err(|e| NetworkError::request(url, e))?;
+            let status = resp.status();
+            let too_large = || NetworkError::BodyTooLarge {
+                url: url.to_string(),
+                limit: max_body,
+            };
+            // The length header is only a hint, the body is still counted as it streams in.
+            if resp
+                .content_length()
+                .is_some_and(|len| len > max_body as u64)
+            {
+                return Err(too_large());
+            }
+            let mut body = Vec::new();
+            while let Some(chunk) = resp
+                .chunk()
+                .await
+                .map_err(|e| NetworkError::request(url, e))?
+            {
+                if body.len() + chunk.len() > max_body {
+                    return Err(too_large());
                 }
-            } else {
-                log::error!("Failed to handshake with peer: {}", url);
-                self.remove_peer(peer.clone());
+                body.extend_from_slice(&chunk);
             }
+            Ok(PeerResponse {
+                status,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            })
+        })
+    }
+}
+
+/// Source of the current time for peer score decay, handshake latencies, blacklists and circuit
+/// breakers. The system clock is used unless another one is set with `NodeManager::set_clock`.
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Token bucket spacing out requests to peers, shared by every clone of a `NodeManager`.
+/// The rate is passed in on each call so that it can be changed at any time.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    bucket: std::sync::Mutex<TokenBucket>,
+}
+
+#[derive(Debug, Default)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Waits until a request may be sent at `rate` requests per second. Up to a second's
+    /// worth of requests may go out at once; `None` never waits.
+    async fn acquire(&self, rate: Option<f64>) {
+        let rate = match rate {
+            Some(rate) if rate > 0.0 => rate.max(MIN_REQUESTS_PER_SECOND),
+            _ => return,
+        };
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let capacity = rate.max(1.0);
+            let refilled = match bucket.updated {
+                Some(updated) => bucket.tokens + (now - updated).as_secs_f64() * rate,
+                None => capacity,
+            };
+            // Taking a token that isn't there yet reserves it, so waiting callers are
+            // served in the order they arrived.
+            bucket.tokens = refilled.min(capacity) - 1.0;
+            bucket.updated = Some(now);
+            Duration::try_from_secs_f64((-bucket.tokens).max(0.0) / rate).unwrap_or(Duration::MAX)
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
         }
-        if let Some(elected_peer) = elected_peer {
-            log::info!("Elected peer: {}", elected_peer.addr);
-            self.elected_peer = Some(elected_peer);
+    }
+}
+
+/// Handle to a loop started by `NodeManager::spawn_sync_loop`.
+pub struct SyncLoopHandle {
+    cancel: CancellationToken,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl SyncLoopHandle {
+    /// Stops the loop, letting a round that's already running finish, and waits for it to
+    /// exit.
+    pub async fn stop(self) {
+        self.cancel.cancel();
+        if let Err(e) = self.task.await {
+            log::error!(error:% = e; "Sync loop panicked");
         }
+    }
+}
 
-        log::info!("Synced with peers: {}", self.get_peers().len());
+/// Runs `fut` to completion, or until `deadline` if there is one, giving `None` if that
+/// comes first.
+async fn until_deadline<F: Future>(
+    deadline: Option<tokio::time::Instant>,
+    fut: F,
+) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
 
-        Ok(())
+/// The HTTP status a peer rejected a request with, if that's why it failed.
+fn peer_status(e: &NetworkError) -> Option<reqwest::StatusCode> {
+    match e {
+        NetworkError::PeerStatus(status) => Some(*status),
+        _ => None,
     }
+}
 
-    async fn _add_batch_peer_peers(&mut self, peer: Peer) -> Result<(), eyre::Report> {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(1))
-            .build()?;
+struct HandshakeOutcome {
+    result: Result<GetHandShakeResponse, NetworkError>,
+    failures: u32,
+    latency: Option<Duration>,
+}
 
-        let url = format!("http://{}/get-peers", peer.addr);
-        let resp = client.get(&url).send().await;
+/// Consecutive successful queries needed before an event scan doubles its block range.
+const SCAN_GROWTH_STREAK: u32 = 2;
 
-        if let Ok(resp) = resp {
-            if resp.status().is_success() {
-                let body = resp.text().await;
-                if let Ok(body) = body {
-                    let peers: GetPeersResponse = serde_json::from_str(&body)?;
-                    for p in peers.peers {
-                        self.add_peer(p);
-                    }
-                } else {
-                    log::error!("Failed to parse response from peer: {}", url);
-                    self.remove_peer(peer);
+/// Weight given to the newest sample in a peer's rolling health and latency.
+const HEALTH_SMOOTHING: f64 = 0.3;
+
+impl Peer {
+    /// Folds the outcome of a handshake round (`failures` failed attempts, followed by a
+    /// successful one if `latency` is set) into the peer's rolling health and latency.
+    pub fn record_handshake(&mut self, failures: u32, latency: Option<Duration>) {
+        for _ in 0..failures {
+            self.health *= 1.0 - HEALTH_SMOOTHING;
+        }
+        if let Some(latency) = latency {
+            self.health = self.health * (1.0 - HEALTH_SMOOTHING) + HEALTH_SMOOTHING;
+            self.latency = Some(match self.latency {
+                Some(prev) => {
+                    prev.mul_f64(1.0 - HEALTH_SMOOTHING) + latency.mul_f64(HEALTH_SMOOTHING)
                 }
-            } else {
-                log::error!("Failed to get peers with peer: {}", url);
-                self.remove_peer(peer);
-            }
-        } else {
-            log::error!("Failed to get peers with peer: {}", url);
-            self.remove_peer(peer);
+                None => latency,
+            });
         }
-        Ok(())
     }
 
-    pub fn set_provider_network(&mut self, provider_network: Network) {
-        self.network = Some(provider_network);
+    /// The address used to identify the peer. IPv4-mapped IPv6 addresses are folded back
+    /// to plain IPv4 and IPv6 flow info and scope ids are dropped, so a node advertised in
+    /// different forms is still recognised as one.
+    pub fn normalized_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr.ip().to_canonical(), self.addr.port())
     }
 
-    pub fn get_provider_network(&self) -> Option<Network> {
-        self.network.clone()
+    /// Base URL of the peer's API, without a trailing slash. IPv6 hosts are bracketed, and
+    /// any zone id is left out since URLs can't carry it unescaped.
+    pub fn base_url(&self) -> String {
+        match self.addr {
+            SocketAddr::V4(addr) => format!("{}://{}", self.scheme, addr),
+            SocketAddr::V6(addr) => format!("{}://[{}]:{}", self.scheme, addr.ip(), addr.port()),
+        }
     }
 
-    pub async fn get_events_from_elected_peer(
-        &self,
-        mut from_spend: usize,
-        mut from_sent: usize,
-    ) -> Result<(Vec<SpendFilter>, Vec<SentFilter>, u64), eyre::Report> {
-        if let Some(elected_peer) = self.elected_peer.clone() {
-            let step: usize = 256;
-            let mut spend_events = Vec::new();
-            let mut sent_events = Vec::new();
+    /// Whether the address can actually be dialed. `Peer` addresses are already parsed socket
+    /// addresses, but gossip can still carry wildcard, multicast or port-zero entries which
+    /// would fail every handshake.
+    pub fn has_valid_addr(&self) -> bool {
+        let ip_ok = match self.addr.ip() {
+            IpAddr::V4(ip) => !ip.is_unspecified() && !ip.is_multicast() && !ip.is_broadcast(),
+            IpAddr::V6(ip) => !ip.is_unspecified() && !ip.is_multicast(),
+        };
+        ip_ok && self.addr.port() != 0
+    }
+}
 
-            loop {
-                let url = format!(
-                    "http://{}/events?from_spend={}&from_sent={}&length={}",
-                    elected_peer.addr, from_spend, from_sent, step
-                );
+/// Latencies closer than this are treated as equal when electing a peer, so that measurement
+/// noise doesn't decide between otherwise equal peers.
+const ELECTION_LATENCY_BUCKET: Duration = Duration::from_millis(10);
 
-                let client = reqwest::Client::builder()
-                    .timeout(Duration::from_secs(1))
-                    .build()?;
-                let resp = client.get(&url).send().await;
+fn election_rank(a: &Peer, b: &Peer) -> Ordering {
+    let bucket = |p: &Peer| {
+        p.latency.map_or(u128::MAX, |l| {
+            l.as_millis() / ELECTION_LATENCY_BUCKET.as_millis()
+        })
+    };
+    a.health
+        .partial_cmp(&b.health)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| bucket(b).cmp(&bucket(a)))
+        .then_with(|| a.current_block.cmp(&b.current_block))
+}
 
-                if let Ok(resp) = resp {
-                    if resp.status().is_success() {
-                        let body = resp.text().await;
-                        if let Ok(body) = body {
-                            let json_resp: GetEventsResponse = serde_json::from_str(&body)?;
-                            if json_resp.spend_events.is_empty() && json_resp.sent_events.is_empty()
-                            {
-                                break;
-                            }
+/// Chooses the peer to fetch events from. Among the peers within `tolerance` blocks of the
+/// highest reported block, the healthiest wins, then the one with the lowest latency. Peers
+/// that are still tied are picked between at random, so that the load spreads across them.
+fn elect_peer<R: Rng + ?Sized>(candidates: &[Peer], tolerance: u64, rng: &mut R) -> Option<Peer> {
+    let max_block = candidates.iter().map(|p| p.current_block).max()?;
+    let eligible: Vec<&Peer> = candidates
+        .iter()
+        .filter(|p| p.current_block.saturating_add(tolerance) >= max_block)
+        .collect();
+    let best = *eligible.iter().max_by(|a, b| election_rank(a, b))?;
+    let tied: Vec<&Peer> = eligible
+        .into_iter()
+        .filter(|p| election_rank(p, best) == Ordering::Equal)
+        .collect();
+    tied.choose(rng).map(|p| (*p).clone())
+}
 
-                            spend_events.extend(json_resp.spend_events);
-                            sent_events.extend(json_resp.sent_events);
+/// Chooses the peer to fetch events from, see `NodeManager::set_election_strategy`. The
+/// candidates have already answered a handshake and passed the manager's own filters, such as
+/// circuit breakers and `min_elect_block`. `tolerance` is the manager's
+/// `election_block_tolerance` and `rng` its election generator, seeded by
+/// `set_election_seed`, for strategies that pick at random.
+pub trait ElectionStrategy: std::fmt::Debug + Send + Sync {
+    fn elect(&self, candidates: &[Peer], tolerance: u64, rng: &mut dyn RngCore) -> Option<Peer>;
+}
 
-                            from_spend += step;
-                            from_sent += step;
-                        } else {
-                            log::error!("Failed to parse response from peer: {}", url);
-                        }
-                    } else {
-                        log::error!("Failed to get spend events with peer: {}", url);
-                    }
-                } else {
-                    log::error!("Failed to get spend events with peer: {}", url);
-                    break;
-                }
-            }
-            Ok((spend_events, sent_events, elected_peer.current_block))
-        } else {
-            log::error!("Elected peer is not set");
-            Ok((vec![], vec![], 0))
-        }
+/// The default election, see `elect_peer`.
+#[derive(Debug, Default)]
+pub struct HealthiestNearTip;
+
+impl ElectionStrategy for HealthiestNearTip {
+    fn elect(&self, candidates: &[Peer], tolerance: u64, rng: &mut dyn RngCore) -> Option<Peer> {
+        elect_peer(candidates, tolerance, rng)
+    }
+}
+
+/// Rolling hash of a node's event streams, advertised in the handshake so that peers at the
+/// same height can be checked for serving the same events. Each stream is folded separately,
+/// `keccak256(previous || abi_encode(event))` per event, so the result only depends on the
+/// events and their order within their stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EventsCommitment {
+    spend: H256,
+    sent: H256,
+}
+
+impl EventsCommitment {
+    /// Folds newly appended events into the commitment.
+    pub fn extend(&mut self, spend_events: &[SpendFilter], sent_events: &[SentFilter]) {
+        self.spend = fold_events(self.spend, spend_events);
+        self.sent = fold_events(self.sent, sent_events);
+    }
+
+    /// The commitment to both streams.
+    pub fn root(&self) -> H256 {
+        H256(ethers::utils::keccak256(
+            [self.spend.as_bytes(), self.sent.as_bytes()].concat(),
+        ))
     }
+}
+
+fn fold_events<E: Tokenizable + Clone>(acc: H256, events: &[E]) -> H256 {
+    events.iter().fold(acc, |acc, event| {
+        let encoded = ethers::abi::encode(&[event.clone().into_token()]);
+        H256(ethers::utils::keccak256(
+            [acc.as_bytes(), &encoded].concat(),
+        ))
+    })
+}
 
-    pub async fn get_spend_events(&self, mut from: u64, to: u64) -> Vec<SpendFilter> {
-        let network = self.get_provider_network();
-        if let Some(network) = network {
-            let contract: ContractInstance<Arc<Provider<Http>>, _> = Contract::new(
-                network.config.owshen_contract_address,
-                network.config.owshen_contract_abi,
-                network.provider.clone(),
+/// Peers that claim the same height as others but commit to different events than the
+/// majority at that height. Heights where no commitment is shared by more than half of the
+/// peers reporting one are left alone, as are peers that don't report one.
+fn divergent_peers(commitments: &[(SocketAddr, u64, H256)]) -> Vec<SocketAddr> {
+    let mut by_height: HashMap<u64, Vec<(SocketAddr, H256)>> = HashMap::new();
+    for (addr, height, commitment) in commitments {
+        by_height
+            .entry(*height)
+            .or_default()
+            .push((*addr, *commitment));
+    }
+    let mut divergent = Vec::new();
+    for group in by_height.values() {
+        let mut votes: HashMap<H256, usize> = HashMap::new();
+        for (_, commitment) in group {
+            *votes.entry(*commitment).or_default() += 1;
+        }
+        let majority = votes
+            .into_iter()
+            .find(|(_, count)| count * 2 > group.len())
+            .map(|(commitment, _)| commitment);
+        if let Some(majority) = majority {
+            divergent.extend(
+                group
+                    .iter()
+                    .filter(|(_, commitment)| *commitment != majority)
+                    .map(|(addr, _)| *addr),
             );
+        }
+    }
+    divergent
+}
 
-            let mut step = 1024;
-            let mut events = Vec::new();
-
-            while from < to {
-                log::info!("{} {}", from, to);
-                if let Some(new_spent_events) = timeout(std::time::Duration::from_secs(10), async {
-                    contract
-                        .event::<SpendFilter>()
-                        .from_block(from)
-                        .to_block(from + step)
-                        .address(ValueOrArray::Value(contract.address()))
-                        .query()
-                        .await
-                })
-                .await
-                .map(|r| r.ok())
-                .ok()
-                .unwrap_or_default()
-                {
-                    events.extend(new_spent_events);
-                    from += step;
-                    if step < 1024 {
-                        step = step * 2;
-                    }
-                } else {
-                    step = step / 2;
-                }
+/// Checks the events a peer served against the provider's view of a sample block range.
+/// Every event the provider reports in the sample must have been served unchanged; zero
+/// nullifiers are ignored as they don't identify a spend.
+fn events_consistent(
+    peer_spends: &[SpendFilter],
+    peer_sents: &[SentFilter],
+    provider_spends: &[SpendFilter],
+    provider_sents: &[SentFilter],
+) -> bool {
+    let served_nullifiers: HashSet<_> = peer_spends.iter().map(|e| e.nullifier).collect();
+    let served_sents: HashMap<_, _> = peer_sents.iter().map(|e| (e.index, e)).collect();
+    provider_spends
+        .iter()
+        .all(|e| e.nullifier.is_zero() || served_nullifiers.contains(&e.nullifier))
+        && provider_sents
+            .iter()
+            .all(|e| served_sents.get(&e.index) == Some(&e))
+}
+
+/// How far in the future a served event's timestamp may be before it's considered bogus.
+const MAX_EVENT_CLOCK_DRIFT: u64 = 15 * 60;
+
+/// Checks a page of events served by a peer before it's accepted. Each stream must fit in
+/// the requested `length`, spend nullifiers and sent commitments and ephemeral keys must be
+/// field elements, non-zero nullifiers must not repeat, and sent events must continue the
+/// deposit index sequence from `last_sent` with timestamps that don't go backwards or lie in
+/// the future. The events don't carry block numbers, so timestamps stand in for them.
+///
+/// On the first page there is no `last_sent`; its first sent event must then have the index
+/// `first_sent`, if that's known, so a peer can't skip ahead of the requested position.
+fn validate_events_page(
+    page: &GetEventsResponse,
+    length: usize,
+    last_sent: Option<&SentFilter>,
+    first_sent: Option<U256>,
+    seen_spends: &HashSet<U256>,
+) -> Result<(), String> {
+    if page.spend_events.len() > length || page.sent_events.len() > length {
+        return Err(format!("more than the requested {} events", length));
+    }
+    let is_field_element = |v: U256| Fp::try_from(v).is_ok();
+
+    let mut nullifiers = HashSet::new();
+    for e in page.spend_events.iter() {
+        if !is_field_element(e.nullifier) {
+            return Err(format!("nullifier {} is out of range", e.nullifier));
+        }
+        if !e.nullifier.is_zero()
+            && (seen_spends.contains(&e.nullifier) || !nullifiers.insert(e.nullifier))
+        {
+            return Err(format!("duplicate nullifier {}", e.nullifier));
+        }
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    if let (None, Some(first), Some(expected)) = (last_sent, page.sent_events.first(), first_sent) {
+        if first.index != expected {
+            return Err(format!(
+                "sent event {} served where {} was asked for",
+                first.index, expected
+            ));
+        }
+    }
+    let mut prev = last_sent;
+    for e in page.sent_events.iter() {
+        if ![e.commitment, e.ephemeral.x, e.ephemeral.y]
+            .into_iter()
+            .all(is_field_element)
+        {
+            return Err(format!("sent event {} has out of range fields", e.index));
+        }
+        if e.timestamp > U256::from(now + MAX_EVENT_CLOCK_DRIFT) {
+            return Err(format!(
+                "sent event {} is timestamped in the future",
+                e.index
+            ));
+        }
+        if let Some(prev) = prev {
+            if e.index != prev.index + 1 {
+                return Err(format!(
+                    "sent event {} doesn't follow {}",
+                    e.index, prev.index
+                ));
+            }
+            if e.timestamp < prev.timestamp {
+                return Err(format!("sent event {} goes back in time", e.index));
             }
-            events
-        } else {
-            log::error!("Provider is not set");
-            vec![]
         }
+        prev = Some(e);
     }
+    Ok(())
+}
 
-    pub async fn get_sent_events(&self, mut from: u64, to: u64) -> Vec<SentFilter> {
-        let network = self.get_provider_network();
-        if let Some(network) = network {
-            let contract: ContractInstance<Arc<Provider<Http>>, _> = Contract::new(
-                network.config.owshen_contract_address,
-                network.config.owshen_contract_abi,
-                network.provider.clone(),
-            );
+/// Transport a provider is queried for events over.
+#[derive(Clone, Debug)]
+pub enum EventProvider {
+    Http(Arc<Provider<Http>>),
+    Ws(Arc<Provider<Ws>>),
+}
 
-            let mut step = 1024;
-            let mut events = Vec::new();
-
-            while from < to {
-                if let Some(new_sent_events) = timeout(std::time::Duration::from_secs(10), async {
-                    contract
-                        .event::<SentFilter>()
-                        .from_block(from)
-                        .to_block(from + step)
-                        .address(ValueOrArray::Value(contract.address()))
-                        .query()
-                        .await
-                })
-                .await
-                .map(|r| r.ok())
-                .ok()
-                .unwrap_or_default()
-                {
-                    events.extend(new_sent_events);
-                    from += step;
-                    if step < 1024 {
-                        step = step * 2;
-                    }
-                } else {
-                    step = step / 2;
-                }
-            }
-            events
-        } else {
-            log::error!("Provider is not set");
-            vec![]
+impl Network {
+    /// Connects to the HTTP `endpoint`, sending `config.rpc_headers` with every request, and
+    /// uses `ws_provider` for events if given.
+    pub fn connect(
+        endpoint: &str,
+        ws_provider: Option<Arc<Provider<Ws>>>,
+        config: Config,
+    ) -> Result<Network, eyre::Report> {
+        let rpc_client = rpc_client(&config.rpc_headers)?;
+        let provider = Provider::new(Http::new_with_client(
+            reqwest::Url::parse(endpoint)?,
+            rpc_client.clone(),
+        ));
+        Ok(Network {
+            provider: Arc::new(provider),
+            ws_provider,
+            rpc_client,
+            config,
+        })
+    }
+
+    /// The WebSocket provider if one is configured, the HTTP one otherwise.
+    pub fn event_provider(&self) -> EventProvider {
+        match &self.ws_provider {
+            Some(ws) => EventProvider::Ws(ws.clone()),
+            None => EventProvider::Http(self.provider.clone()),
         }
     }
+
+    /// Block the Owshen contract was deployed at. No event can be older, so scans start here.
+    pub fn deployment_block(&self) -> u64 {
+        self.config.owshen_contract_deployment_block_number.as_u64()
+    }
+
+    fn owshen_contract<M: Middleware>(&self, provider: Arc<M>) -> ContractInstance<Arc<M>, M> {
+        Contract::new(
+            self.config.owshen_contract_address,
+            self.config.owshen_contract_abi.clone(),
+            provider,
+        )
+    }
 }
 
-impl NetworkManager {
-    pub fn new() -> NetworkManager {
-        let mut networks: HashMap<String, Vec<TokenInfo>> = HashMap::new();
+impl NodeManager {
+    pub fn new(
+        external_addr: Option<SocketAddr>,
+        network: Option<Network>,
+        peers: Vec<Peer>,
+        is_peer2peer: bool,
+        is_client: bool,
+    ) -> Result<NodeManager, eyre::Report> {
+        Ok(NodeManager {
+            external_addr,
+            network,
+            backup_networks: vec![],
+            bootstrap_peers: peers.clone(),
+            seeds: vec![],
+            peers,
+            elected_peer: None,
+            elected_at: None,
+            max_election_age: None,
+            is_peer2peer,
+            is_client,
+            transport: Arc::new(build_http_client(
+                DEFAULT_HTTP_TIMEOUT,
+                None,
+                &ConnectionPoolConfig::default(),
+                &[],
+            )?),
+            http_timeout: DEFAULT_HTTP_TIMEOUT,
+            connection_pool: ConnectionPoolConfig::default(),
+            pinned_certificates: vec![],
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            peers_timeout: DEFAULT_PEERS_TIMEOUT,
+            events_timeout: DEFAULT_EVENTS_TIMEOUT,
+            max_handshake_retries: DEFAULT_MAX_HANDSHAKE_RETRIES,
+            sync_retry_budget: None,
+            handshake_retry_backoff: DEFAULT_HANDSHAKE_RETRY_BACKOFF,
+            peer_failures: HashMap::new(),
+            clock: Arc::new(SystemClock),
+            health_recovery_window: DEFAULT_HEALTH_RECOVERY_WINDOW,
+            last_decay: None,
+            blacklist: HashMap::new(),
+            blacklist_strikes: HashMap::new(),
+            blacklist_duration: DEFAULT_BLACKLIST_DURATION,
+            breaker_threshold: DEFAULT_BREAKER_THRESHOLD,
+            breaker_cooldown: DEFAULT_BREAKER_COOLDOWN,
+            tripped_peers: HashMap::new(),
+            handshake_concurrency: DEFAULT_HANDSHAKE_CONCURRENCY,
+            election_block_tolerance: DEFAULT_ELECTION_BLOCK_TOLERANCE,
+            min_elect_block: 0,
+            election_changes: broadcast::channel(ELECTION_CHANGE_BUFFER).0,
+            event_scan: EventScanConfig::default(),
+            rpc_query_timeout: DEFAULT_RPC_QUERY_TIMEOUT,
+            #[cfg(test)]
+            event_poll_interval: DEFAULT_EVENT_POLL_INTERVAL,
+            max_peers: DEFAULT_MAX_PEERS,
+            peer_order: PeerOrder::default(),
+            events_page_size: DEFAULT_EVENTS_PAGE_SIZE,
+            shard_peers: DEFAULT_SHARD_PEERS,
+            verify_sample_blocks: None,
+            provider_fallback: true,
+            min_peer_version: DEFAULT_MIN_PEER_VERSION,
+            event_cache: Arc::new(std::sync::Mutex::new(EventCache::default())),
+            block_timestamps: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            confirmations: 0,
+            max_blocks_per_call: None,
+            stale_peer_lag: None,
+            sync_round_timeout: None,
+            auth_token: None,
+            node_id: None,
+            max_requests_per_second: None,
+            max_response_body: DEFAULT_MAX_RESPONSE_BODY,
+            state_dir: None,
+            last_cursor: Arc::new(std::sync::Mutex::new(None)),
+            rate_limiter: Arc::new(RateLimiter::default()),
+            election_rng: Arc::new(std::sync::Mutex::new(StdRng::from_entropy())),
+            election_strategy: Arc::new(HealthiestNearTip),
+            counters: Arc::new(SyncCounters::default()),
+        })
+    }
 
-        networks.insert(
-            "Goerli".to_string(),
-            vec![TokenInfo {
-                token_address: H160::from_str("0xdD69DB25F6D620A7baD3023c5d32761D353D3De9")
-                    .unwrap(),
-                symbol: "WETH".to_string(),
-            }],
-        );
+    /// Seeds the generator used to pick between equally good peers, making elections
+    /// reproducible.
+    pub fn set_election_seed(&mut self, seed: u64) {
+        self.election_rng = Arc::new(std::sync::Mutex::new(StdRng::seed_from_u64(seed)));
+    }
 
-        NetworkManager { networks }
+    /// Elects peers with `strategy` instead of `HealthiestNearTip`.
+    #[cfg(test)]
+    pub fn set_election_strategy(&mut self, strategy: Arc<dyn ElectionStrategy>) {
+        self.election_strategy = strategy;
     }
 
-    // pub fn set(&mut self, data: HashMap<String, Vec<TokenInfo>>, expand: bool) {
-    //     if expand {
-    //         self.networks.extend(data);
-    //     } else {
-    //         self.networks = data;
-    //     }
-    // }
+    /// Keeps peers below `block` out of elections, e.g. freshly started peers that are still
+    /// catching up while the network bootstraps. They are still handshaked and tracked like
+    /// any other peer, and `force_elect` can still pick them.
+    pub fn set_min_elect_block(&mut self, block: u64) {
+        self.min_elect_block = block;
+    }
 
-    pub fn add_network(&mut self, network: String, token_info: Vec<TokenInfo>) {
-        self.networks.insert(network, token_info);
+    /// Notifies of every change of the elected peer from now on, see `ElectionChange`. A
+    /// receiver that falls more than `ELECTION_CHANGE_BUFFER` changes behind skips the oldest.
+    /// Every clone of this manager notifies the same subscribers.
+    #[cfg(test)]
+    pub fn subscribe_elections(&self) -> broadcast::Receiver<ElectionChange> {
+        self.election_changes.subscribe()
     }
 
-    // pub fn get(&self, network: &str) -> Option<&Vec<TokenInfo>> {
-    //     self.networks.get(network)
-    // }
+    /// Makes fetching from the elected peer run `sync_with_peers` first if the peer was elected
+    /// more than `max_age` ago, so that events aren't fetched from a peer that has since gone
+    /// offline or fallen behind the others, or stops doing so with `None`.
+    pub fn set_max_election_age(&mut self, max_age: Option<Duration>) {
+        self.max_election_age = max_age;
+    }
 
-    // pub fn has(&self, network: &str, symbol: &str) -> bool {
-    //     self.get(network).map_or(false, |tokens| {
-    //         tokens.iter().any(|token_info| token_info.symbol == symbol)
-    //     })
-    // }
+    /// Replaces the elected peer, notifying subscribers if it is a different peer. Re-electing
+    /// the same peer restarts its election age.
+    fn set_elected_peer(&mut self, elected: Option<Peer>) {
+        self.elected_at = elected.as_ref().map(|_| self.clock.now());
+        let previous = std::mem::replace(&mut self.elected_peer, elected);
+        if previous.as_ref().map(|p| p.addr) != self.elected_peer.as_ref().map(|p| p.addr) {
+            // Sending only fails when nobody is subscribed.
+            let _ = self.election_changes.send(ElectionChange {
+                previous,
+                elected: self.elected_peer.clone(),
+            });
+        }
+    }
+
+    fn elect(&self, candidates: &[Peer]) -> Option<Peer> {
+        let eligible: Vec<Peer> = candidates
+            .iter()
+            .filter(|p| p.current_block >= self.min_elect_block)
+            .cloned()
+            .collect();
+        let mut rng = self.election_rng.lock().unwrap();
+        self.election_strategy
+            .elect(&eligible, self.election_block_tolerance, &mut *rng)
+    }
+
+    /// Sets the per-request timeouts used for handshakes, peer lists and event pages.
+    /// These override the shared client's timeout on every subsequent sync.
+    pub fn set_request_timeouts(&mut self, handshake: Duration, peers: Duration, events: Duration) {
+        self.handshake_timeout = handshake;
+        self.peers_timeout = peers;
+        self.events_timeout = events;
+    }
+
+    /// Sets how many events of each kind are requested per page. Peers that advertise a
+    /// smaller maximum are asked for no more than that.
+    pub fn set_events_page_size(&mut self, page_size: usize) {
+        self.events_page_size = page_size.max(1);
+    }
+
+    /// Sets how many of the best peers `get_events_sharded` fetches from at once.
+    pub fn set_shard_peers(&mut self, peers: usize) {
+        self.shard_peers = peers.max(1);
+    }
+
+    /// Sets the oldest protocol version a peer may speak to be kept.
+    pub fn set_min_peer_version(&mut self, version: u32) {
+        self.min_peer_version = version;
+    }
+
+    pub fn is_compatible_version(&self, version: u32) -> bool {
+        version >= self.min_peer_version
+    }
+
+    /// Whether we talk to peers as a client, which doesn't advertise an address of its own,
+    /// rather than as a node.
+    #[cfg(test)]
+    pub fn is_client(&self) -> bool {
+        self.is_client
+    }
+
+    /// Switches between client and node mode. Nodes advertise `external_addr` in their
+    /// handshakes, so switching to node mode without one fails with `NetworkError::NotANode`
+    /// and leaves the mode as it was.
+    #[cfg(test)]
+    pub fn set_client_mode(&mut self, is_client: bool) -> Result<(), NetworkError> {
+        if !is_client && self.external_addr.is_none() {
+            return Err(NetworkError::NotANode);
+        }
+        self.is_client = is_client;
+        Ok(())
+    }
+
+    /// Only return provider events from blocks at least `confirmations` deep. Events in more
+    /// recent blocks won't show up until they reach that depth, which keeps results from
+    /// changing under reorgs.
+    pub fn set_confirmations(&mut self, confirmations: u64) {
+        self.confirmations = confirmations;
+    }
+
+    /// Caps how many blocks `query_events_capped` and the `get_*_events_capped` queries scan
+    /// in one call, so that long scans leave room for other work on the provider between
+    /// calls. `None` lifts the cap.
+    pub fn set_max_blocks_per_call(&mut self, max_blocks: Option<u64>) {
+        self.max_blocks_per_call = max_blocks.map(|max| max.max(1));
+    }
+
+    /// The most recent block that has reached the confirmation depth.
+    pub async fn latest_confirmed_block(&self) -> Result<u64, eyre::Report> {
+        Ok(self
+            .provider_block_number()
+            .await?
+            .saturating_sub(self.confirmations))
+    }
+
+    /// The chain head according to the first provider that answers.
+    async fn provider_block_number(&self) -> Result<u64, eyre::Report> {
+        let mut last_err: eyre::Report = NetworkError::ProviderNotSet.into();
+        for network in self.provider_networks() {
+            match network.provider.get_block_number().await {
+                Ok(head) => return Ok(head.as_u64()),
+                Err(e) => {
+                    log::warn!(
+                        provider = network.config.endpoint.as_str(), error:% = e;
+                        "Provider failed"
+                    );
+                    last_err = e.into();
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Caches up to `capacity` decoded `query_events` results so repeated queries for the same
+    /// range skip the provider. Zero, the default, disables the cache.
+    pub fn set_event_cache_capacity(&mut self, capacity: usize) {
+        self.event_cache.lock().unwrap().set_capacity(capacity);
+    }
+
+    /// Drops every cached event range, e.g. when a reorg is suspected.
+    #[cfg(test)]
+    pub fn clear_event_cache(&self) {
+        self.event_cache.lock().unwrap().clear();
+    }
+
+    /// Whether event fetches fall back to querying the provider when no peer is elected.
+    pub fn set_provider_fallback(&mut self, enabled: bool) {
+        self.provider_fallback = enabled;
+    }
+
+    /// Enables cross-checking the elected peer's events against the provider in
+    /// `get_verified_events`, sampling the last `sample_blocks` blocks of every fetch.
+    /// `None` turns verification off.
+    pub fn set_event_verification(&mut self, sample_blocks: Option<u64>) {
+        self.verify_sample_blocks = sample_blocks;
+    }
+
+    /// Rebuilds the shared HTTP client with the given timeout. This replaces a transport set
+    /// with `set_transport`.
+    pub fn set_http_timeout(&mut self, timeout: Duration) -> Result<(), eyre::Report> {
+        self.transport = Arc::new(build_http_client(
+            timeout,
+            self.node_id.as_deref(),
+            &self.connection_pool,
+            &self.pinned_certificates,
+        )?);
+        self.http_timeout = timeout;
+        Ok(())
+    }
+
+    /// Bounds the idle connections the shared HTTP client keeps to peers, see
+    /// `ConnectionPoolConfig` for the defaults. Like `set_http_timeout`, this rebuilds the
+    /// client.
+    pub fn set_connection_pool(&mut self, pool: ConnectionPoolConfig) -> Result<(), eyre::Report> {
+        self.transport = Arc::new(build_http_client(
+            self.http_timeout,
+            self.node_id.as_deref(),
+            &pool,
+            &self.pinned_certificates,
+        )?);
+        self.connection_pool = pool;
+        Ok(())
+    }
+
+    /// Identifies this node to peers through the `NODE_ID_HEADER` header, or stops sending
+    /// it with `None`. Like `set_http_timeout`, this rebuilds the shared HTTP client.
+    pub fn set_node_id(&mut self, node_id: Option<String>) -> Result<(), eyre::Report> {
+        self.transport = Arc::new(build_http_client(
+            self.http_timeout,
+            node_id.as_deref(),
+            &self.connection_pool,
+            &self.pinned_certificates,
+        )?);
+        self.node_id = node_id;
+        Ok(())
+    }
+
+    /// Pins the TLS certificates of https peers: a peer is only talked to if it presents one
+    /// of `pins`, whatever CA signed it, so self-signed certificates work too. The pins apply
+    /// to every https peer, so each of them needs its certificate listed. An empty list goes
+    /// back to the usual CA checks. Like `set_http_timeout`, this rebuilds the shared HTTP
+    /// client.
+    pub fn set_pinned_certificates(
+        &mut self,
+        pins: Vec<CertFingerprint>,
+    ) -> Result<(), eyre::Report> {
+        self.transport = Arc::new(build_http_client(
+            self.http_timeout,
+            self.node_id.as_deref(),
+            &self.connection_pool,
+            &pins,
+        )?);
+        self.pinned_certificates = pins;
+        Ok(())
+    }
+
+    /// Reads the time for peer score decay, handshake latencies, blacklists and circuit
+    /// breakers from `clock` instead of the system clock.
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Sets how long it takes a peer to earn back full health from zero. Lowered health is
+    /// recovered in proportion to the time passed, on each `sync_with_peers`.
+    pub fn set_health_recovery_window(&mut self, window: Duration) {
+        self.health_recovery_window = window;
+    }
+
+    /// Gives every peer back the share of its lost health that the time since the last call
+    /// earns it, so that old failures and demotions are forgiven: over a whole
+    /// `health_recovery_window`, any peer returns to full health. Failure counts of fully
+    /// recovered peers are forgotten. The first call only starts the clock.
+    fn decay_peer_penalties(&mut self) {
+        let now = self.clock.now();
+        let elapsed = match self.last_decay.replace(now) {
+            Some(last) => now.saturating_duration_since(last),
+            None => return,
+        };
+        let recovered = if self.health_recovery_window.is_zero() {
+            1.0
+        } else {
+            elapsed.as_secs_f64() / self.health_recovery_window.as_secs_f64()
+        };
+        for peer in self.peers.iter_mut() {
+            peer.health = (peer.health + recovered).min(1.0);
+            if peer.health >= 1.0 {
+                self.peer_failures.remove(&peer.addr);
+            }
+        }
+    }
+
+    /// Sends requests to peers through `transport` instead of the default HTTP client.
+    #[cfg(test)]
+    pub fn set_transport(&mut self, transport: Arc<dyn PeerTransport>) {
+        self.transport = transport;
+    }
+
+    /// Sets the token sent as `Authorization: Bearer` to peers that don't carry their own.
+    pub fn set_auth_token(&mut self, token: Option<String>) {
+        self.auth_token = token;
+    }
+
+    fn bearer_for<'a>(&'a self, peer: &'a Peer) -> Option<&'a str> {
+        peer.auth_token.as_deref().or(self.auth_token.as_deref())
+    }
+
+    /// The peer's own timeout if it has one, `default` otherwise.
+    fn timeout_for(&self, peer: &Peer, default: Duration) -> Duration {
+        peer.timeout.unwrap_or(default)
+    }
+
+    /// Limits requests to peers to `rate` per second across all clones of this manager, or
+    /// lifts the limit with `None`. Rates below `MIN_REQUESTS_PER_SECOND` are taken as that.
+    pub fn set_max_requests_per_second(&mut self, rate: Option<f64>) {
+        self.max_requests_per_second = rate;
+    }
+
+    /// Largest response body, in bytes, read from a peer. Longer responses are cut off while
+    /// streaming in and fail with `NetworkError::BodyTooLarge`, so a peer can't exhaust our
+    /// memory; they count as a failed request to that peer.
+    pub fn set_max_response_body(&mut self, bytes: usize) {
+        self.max_response_body = bytes;
+    }
+
+    async fn peer_get(
+        &self,
+        url: &str,
+        bearer: Option<&str>,
+        timeout: Duration,
+    ) -> Result<PeerResponse, NetworkError> {
+        self.rate_limiter
+            .acquire(self.max_requests_per_second)
+            .await;
+        let resp = self
+            .transport
+            .get(url, bearer, timeout, self.max_response_body)
+            .await;
+        if let Err(NetworkError::BodyTooLarge { limit, .. }) = &resp {
+            log::warn!(url, limit; "Dropping peer response larger than the body size limit");
+        }
+        resp
+    }
+
+    /// Normalizes the address of `peer` and tells whether it may be in the peer list at all:
+    /// its address must be valid and it can't be ourselves or blacklisted.
+    fn admit_peer(&self, peer: &mut Peer) -> bool {
+        peer.addr = peer.normalized_addr();
+        if !peer.has_valid_addr() {
+            log::warn!("peer.addr":% = peer.addr; "Ignoring peer with invalid address");
+            return false;
+        }
+
+        if self.is_self(peer) {
+            return false;
+        }
+        if self.is_blacklisted(peer.addr) {
+            log::debug!("peer.addr":% = peer.addr; "Ignoring blacklisted peer");
+            return false;
+        }
+        true
+    }
+
+    /// Adds `peer` if it is admitted and not known yet. Peers are told apart by their
+    /// normalized address, so adding one again, also as an IPv4-mapped IPv6 address, changes
+    /// nothing. Like every change to the peer list this takes `&mut self`: tasks that share a
+    /// manager hold it behind a lock, as the node and `sync_shared` do, so the check and
+    /// the insertion can't be interleaved with another task's.
+    pub fn add_peer(&mut self, mut peer: Peer) {
+        if !self.admit_peer(&mut peer) {
+            return;
+        }
+
+        if self.peers.contains(&peer) {
+            return;
+        }
+        self.insert_peer(peer);
+    }
+
+    /// Adds a batch of peers in one pass. Each is admitted as in `add_peer`, so invalid,
+    /// blacklisted and our own addresses are skipped, and duplicates, within the batch or of
+    /// known peers, are found through a set of addresses built once rather than by scanning
+    /// the peer list for every peer.
+    pub fn add_peers(&mut self, peers: Vec<Peer>) {
+        let mut known: HashSet<SocketAddr> =
+            self.peers.iter().map(|p| p.normalized_addr()).collect();
+        for mut peer in peers {
+            if !self.admit_peer(&mut peer) || !known.insert(peer.addr) {
+                continue;
+            }
+            if let Some(evicted) = self.insert_peer(peer) {
+                known.remove(&evicted.normalized_addr());
+            }
+        }
+    }
+
+    /// Appends an admitted peer that isn't known yet, subject to `max_peers`. Returns the peer
+    /// it replaced, if any.
+    fn insert_peer(&mut self, peer: Peer) -> Option<Peer> {
+        if self.peers.len() < self.max_peers {
+            self.peers.push(peer);
+            bump(&self.counters.peers_added, 1);
+            return None;
+        }
+
+        // At capacity a newcomer may only take the place of a strictly less healthy peer.
+        // The elected peer is never evicted.
+        let worst = self
+            .peers
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| Some(*p) != self.elected_peer.as_ref())
+            .min_by(|(_, a), (_, b)| a.health.partial_cmp(&b.health).unwrap_or(Ordering::Equal))
+            .map(|(i, p)| (i, p.health));
+        match worst {
+            Some((i, health)) if health < peer.health => {
+                log::info!(
+                    "peer.addr":% = peer.addr, "peer.replaced":% = self.peers[i].addr;
+                    "Peer limit reached, replacing the least healthy peer"
+                );
+                bump(&self.counters.peers_added, 1);
+                bump(&self.counters.peers_removed, 1);
+                Some(std::mem::replace(&mut self.peers[i], peer))
+            }
+            _ => {
+                log::debug!("peer.addr":% = peer.addr; "Peer limit reached, ignoring peer");
+                None
+            }
+        }
+    }
+
+    /// Replaces the whole peer list with `peers` in one step. Peers are admitted as in
+    /// `add_peer` and duplicates are dropped; past `max_peers`, the least healthy go first.
+    /// Peers already known keep their health, latency and page size observations. If the
+    /// elected peer is not in the new list, another one is elected right away.
+    #[cfg(test)]
+    pub fn set_peers(&mut self, peers: Vec<Peer>) {
+        let mut next: Vec<Peer> = Vec::with_capacity(peers.len());
+        for mut peer in peers {
+            if !self.admit_peer(&mut peer) || next.contains(&peer) {
+                continue;
+            }
+            if let Some(known) = self.peers.iter().find(|p| **p == peer) {
+                peer.health = known.health;
+                peer.latency = known.latency;
+                peer.max_events_page = known.max_events_page;
+            }
+            next.push(peer);
+        }
+        if next.len() > self.max_peers {
+            next.sort_by(|a, b| b.health.partial_cmp(&a.health).unwrap_or(Ordering::Equal));
+            next.truncate(self.max_peers);
+        }
+
+        let added = next.iter().filter(|p| !self.peers.contains(p)).count();
+        let removed = self.peers.iter().filter(|p| !next.contains(p)).count();
+        bump(&self.counters.peers_added, added as u64);
+        bump(&self.counters.peers_removed, removed as u64);
+        self.peers = next;
+        self.reelect_if_removed();
+    }
+
+    /// Elects another peer if the elected one is no longer in the peer list.
+    fn reelect_if_removed(&mut self) {
+        if let Some(elected) = &self.elected_peer {
+            if !self.peers.contains(elected) {
+                let candidates: Vec<Peer> = self
+                    .get_peers()
+                    .into_iter()
+                    .filter(|p| p.health > 0.0)
+                    .collect();
+                self.set_elected_peer(self.elect(&candidates));
+            }
+        }
+    }
+
+    /// Caps the number of peers kept. Excess peers are dropped least healthy first, except
+    /// the elected peer, which is kept unless `max_peers` is zero. The remaining peers keep
+    /// their order.
+    pub fn set_max_peers(&mut self, max_peers: usize) {
+        self.max_peers = max_peers;
+        if self.peers.len() <= max_peers {
+            return;
+        }
+        let elected = self
+            .elected_peer
+            .as_ref()
+            .filter(|elected| max_peers > 0 && self.peers.contains(elected));
+        let mut others: Vec<&Peer> = self.peers.iter().filter(|p| Some(*p) != elected).collect();
+        others.sort_by(|a, b| b.health.partial_cmp(&a.health).unwrap_or(Ordering::Equal));
+        let keep = max_peers - usize::from(elected.is_some());
+        let evicted: Vec<Peer> = others.split_off(keep).into_iter().cloned().collect();
+        self.peers.retain(|p| !evicted.contains(p));
+        bump(&self.counters.peers_removed, evicted.len() as u64);
+        self.reelect_if_removed();
+    }
+
+    /// Removes peers more than `max_lag` blocks behind the elected peer; a peer exactly
+    /// `max_lag` behind is kept. Does nothing while no peer is elected. Returns the number of
+    /// peers removed.
+    pub fn prune_stale_peers(&mut self, max_lag: u64) -> usize {
+        let tip = match &self.elected_peer {
+            Some(elected) => elected.current_block,
+            None => return 0,
+        };
+        let before = self.peers.len();
+        self.peers.retain(|p| {
+            let keep = p.current_block.saturating_add(max_lag) >= tip;
+            if !keep {
+                log::info!(
+                    "peer.addr":% = p.addr, block = p.current_block, lag = tip - p.current_block;
+                    "Pruning peer behind the tip"
+                );
+            }
+            keep
+        });
+        let pruned = before - self.peers.len();
+        bump(&self.counters.peers_removed, pruned as u64);
+        pruned
+    }
+
+    /// Makes every `sync_with_peers` round end with `prune_stale_peers(max_lag)`, or stops
+    /// doing so with `None`.
+    pub fn set_stale_peer_lag(&mut self, max_lag: Option<u64>) {
+        self.stale_peer_lag = max_lag;
+    }
+
+    /// Resolves DNS seed hostnames and adds every address they point to as a peer. Seeds
+    /// without an explicit port use the default node port. Seeds that fail to resolve are
+    /// logged and skipped. Returns the number of peers added.
+    ///
+    /// The seeds are remembered and resolved again whenever the node runs out of peers, see
+    /// `sync_with_peers`.
+    pub async fn bootstrap_from_seeds(&mut self, seeds: &[String]) -> usize {
+        for seed in seeds {
+            if !self.seeds.contains(seed) {
+                self.seeds.push(seed.clone());
+            }
+        }
+        self.resolve_seeds(seeds).await
+    }
+
+    async fn resolve_seeds(&mut self, seeds: &[String]) -> usize {
+        let before = self.peers.len();
+        for seed in seeds {
+            let target = if seed.contains(':') {
+                seed.clone()
+            } else {
+                format!("{}:{}", seed, DEFAULT_SEED_PORT)
+            };
+            match tokio::net::lookup_host(target).await {
+                Ok(addrs) => self.add_peers(addrs.map(Peer::new).collect()),
+                Err(e) => log::error!(seed = seed.as_str(), error:% = e; "Failed to resolve seed"),
+            }
+        }
+        self.peers.len().saturating_sub(before)
+    }
+
+    /// Writes the peer list to `path` as JSON.
+    pub fn save_peers(&self, path: &Path) -> Result<(), eyre::Report> {
+        std::fs::write(path, serde_json::to_string(&self.peers)?)
+            .map_err(|e| eyre::eyre!("Error saving peers: {}", e))
+    }
+
+    /// Adds the peers saved at `path` through `add_peers`. Their block heights are reset, as
+    /// they're stale until the next `sync_with_peers`. A missing or unreadable file is logged
+    /// and leaves the peer list as it is. Returns the number of peers added.
+    pub fn load_peers(&mut self, path: &Path) -> usize {
+        let peers: Vec<Peer> = match std::fs::read_to_string(path)
+            .map_err(eyre::Report::from)
+            .and_then(|s| serde_json::from_str(&s).map_err(eyre::Report::from))
+        {
+            Ok(peers) => peers,
+            Err(e) => {
+                log::warn!(path:% = path.display(), error:% = e; "Could not load peers");
+                return 0;
+            }
+        };
+        let before = self.peers.len();
+        self.add_peers(
+            peers
+                .into_iter()
+                .map(|mut peer| {
+                    peer.current_block = 0;
+                    peer
+                })
+                .collect(),
+        );
+        self.peers.len().saturating_sub(before)
+    }
+
+    /// Keeps the peer list and the last sync cursor in `dir`, as `STATE_PEERS_FILE` and
+    /// `STATE_CURSOR_FILE`, after every round of the sync loop and when the manager is shut
+    /// down, so that `restore` can pick up from there on the next start. `None` keeps
+    /// nothing.
+    pub fn set_state_dir(&mut self, dir: Option<PathBuf>) {
+        self.state_dir = dir;
+    }
+
+    /// Remembers how far events have been fetched, to be saved by `shutdown`. `sync_events`
+    /// records its cursor by itself; callers fetching events otherwise record theirs here.
+    pub fn record_cursor(&self, cursor: &SyncCursor) {
+        *self.last_cursor.lock().unwrap() = Some(cursor.clone());
+    }
+
+    /// Saves the peer list and the last recorded cursor to the state directory, see
+    /// `set_state_dir`. Does nothing without one.
+    pub fn save_state(&self) -> Result<(), eyre::Report> {
+        let dir = match &self.state_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        self.save_peers(&dir.join(STATE_PEERS_FILE))?;
+        if let Some(cursor) = self.last_cursor.lock().unwrap().as_ref() {
+            std::fs::write(dir.join(STATE_CURSOR_FILE), serde_json::to_string(cursor)?)
+                .map_err(|e| eyre::eyre!("Error saving sync cursor: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Saves the state like `save_state`, for the last time before the manager goes away.
+    /// Stop the sync loop first, see `SyncLoopHandle::stop`, so that no round is left half
+    /// done.
+    pub fn shutdown(&self) -> Result<(), eyre::Report> {
+        self.save_state()?;
+        if let Some(dir) = &self.state_dir {
+            log::info!(dir:% = dir.display(), peers = self.peers.len(); "Saved state");
+        }
+        Ok(())
+    }
+
+    /// Loads what `shutdown` saved in the state directory: the peers are added back, see
+    /// `load_peers`, and the cursor is returned for the caller to resume fetching from. A
+    /// missing or unreadable cursor is logged and gives `None`.
+    pub fn restore(&mut self) -> Option<SyncCursor> {
+        let dir = self.state_dir.clone()?;
+        self.load_peers(&dir.join(STATE_PEERS_FILE));
+        let cursor: SyncCursor = match std::fs::read_to_string(dir.join(STATE_CURSOR_FILE))
+            .map_err(eyre::Report::from)
+            .and_then(|s| serde_json::from_str(&s).map_err(eyre::Report::from))
+        {
+            Ok(cursor) => cursor,
+            Err(e) => {
+                log::warn!(dir:% = dir.display(), error:% = e; "Could not load sync cursor");
+                return None;
+            }
+        };
+        self.record_cursor(&cursor);
+        Some(cursor)
+    }
+
+    /// Whether `peer` refers to this node. Besides our advertised address in any equivalent
+    /// form, a loopback address on our port is treated as ourselves, since dialing it would
+    /// reach this node.
+    fn is_self(&self, peer: &Peer) -> bool {
+        match self.external_addr {
+            Some(ext_addr) => {
+                let ext = Peer::new(ext_addr);
+                *peer == ext
+                    || (peer.normalized_addr().ip().is_loopback()
+                        && peer.addr.port() == ext_addr.port())
+            }
+            None => false,
+        }
+    }
+
+    /// The known peers, in the order set with `set_peer_order`.
+    pub fn get_peers(&self) -> Vec<Peer> {
+        let mut peers = self.peers.clone();
+        match self.peer_order {
+            PeerOrder::Insertion => {}
+            PeerOrder::Address => peers.sort_by_key(|p| p.normalized_addr()),
+            PeerOrder::HealthThenAddress => peers.sort_by(|a, b| {
+                b.health
+                    .partial_cmp(&a.health)
+                    .unwrap_or(Ordering::Equal)
+                    .then_with(|| a.normalized_addr().cmp(&b.normalized_addr()))
+            }),
+        }
+        peers
+    }
+
+    /// Sets the order in which peers are listed, handshaked and considered for election.
+    /// Insertion order, the default, depends on when gossip happened to arrive; the other
+    /// orders make a round reproducible, together with `set_election_seed`.
+    pub fn set_peer_order(&mut self, order: PeerOrder) {
+        self.peer_order = order;
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    #[cfg(test)]
+    pub fn elected_peer(&self) -> Option<&Peer> {
+        self.elected_peer.as_ref()
+    }
+
+    /// The peer in the list at `addr`, which is parsed like a `Peer`.
+    #[cfg(test)]
+    fn known_peer(&self, addr: &str) -> Result<Peer, NetworkError> {
+        let unknown = || NetworkError::UnknownPeer(addr.to_string());
+        let normalized = addr
+            .parse::<Peer>()
+            .map_err(|_| unknown())?
+            .normalized_addr();
+        self.peers
+            .iter()
+            .find(|p| p.normalized_addr() == normalized)
+            .cloned()
+            .ok_or_else(unknown)
+    }
+
+    /// Elects the known peer at `addr` whatever its height or health, e.g. to reproduce a
+    /// problem with one particular peer. `addr` is parsed like a `Peer`. Events are fetched
+    /// from that peer until the next `sync_with_peers` elects again.
+    #[cfg(test)]
+    pub fn force_elect(&mut self, addr: &str) -> Result<(), eyre::Report> {
+        let peer = self.known_peer(addr)?;
+        log::info!("peer.addr":% = peer.addr; "Forcing election of peer");
+        self.set_elected_peer(Some(peer));
+        Ok(())
+    }
+
+    /// Roughly how far a sync resumed from `cursor` has come, as the fraction of blocks up to
+    /// the elected peer's height that the cursor covers, counted from the contract's
+    /// deployment block when a provider network is configured. Without an elected peer, or
+    /// one at block 0, this is 0.0.
+    #[cfg(test)]
+    pub fn sync_progress(&self, cursor: &SyncCursor) -> f64 {
+        let tip = match &self.elected_peer {
+            Some(peer) if peer.current_block > 0 => peer.current_block,
+            _ => return 0.0,
+        };
+        let start = self.network.as_ref().map_or(0, Network::deployment_block);
+        if tip <= start {
+            return if cursor.last_block >= tip { 1.0 } else { 0.0 };
+        }
+        let done = cursor.last_block.saturating_sub(start) as f64;
+        (done / (tip - start) as f64).clamp(0.0, 1.0)
+    }
+
+    pub fn remove_peer(&mut self, peer: Peer) {
+        let before = self.peers.len();
+        self.peers.retain(|p| *p != peer);
+        bump(
+            &self.counters.peers_removed,
+            (before - self.peers.len()) as u64,
+        );
+    }
+
+    /// Drops the peer at `addr` and keeps it from being added again, whether directly or
+    /// through gossip, for `duration`. An elected peer is replaced by the next best one.
+    pub fn blacklist_peer(&mut self, addr: SocketAddr, duration: Duration) {
+        let mut peer = Peer::new(addr);
+        peer.addr = peer.normalized_addr();
+        self.blacklist
+            .insert(peer.addr, self.clock.now() + duration);
+        if self.elected_peer.as_ref() == Some(&peer) {
+            self.demote_elected_peer();
+        }
+        self.remove_peer(peer);
+    }
+
+    pub fn is_blacklisted(&self, addr: SocketAddr) -> bool {
+        self.blacklist
+            .get(&Peer::new(addr).normalized_addr())
+            .is_some_and(|until| *until > self.clock.now())
+    }
+
+    /// Whether the circuit breaker of the peer at `addr` tripped less than `breaker_cooldown`
+    /// ago. Such a peer stays in the peer list but can't be elected.
+    pub fn is_tripped(&self, addr: SocketAddr) -> bool {
+        self.tripped_peers
+            .get(&Peer::new(addr).normalized_addr())
+            .is_some_and(|until| *until > self.clock.now())
+    }
+
+    /// Applies the consequences of a failed fetch from the elected peer: a peer that served
+    /// an invalid page is demoted, one that tripped its circuit breaker is also kept out of
+    /// elections for `breaker_cooldown`.
+    fn handle_elected_peer_error(&mut self, e: &NetworkError) {
+        match e {
+            NetworkError::InvalidEventsPage(..) => self.demote_elected_peer(),
+            NetworkError::PeerTripped(addr) => {
+                log::warn!(
+                    "peer.addr":% = addr, cooldown:? = self.breaker_cooldown;
+                    "Circuit breaker tripped for the elected peer"
+                );
+                self.tripped_peers.insert(
+                    Peer::new(*addr).normalized_addr(),
+                    self.clock.now() + self.breaker_cooldown,
+                );
+                self.demote_elected_peer();
+            }
+            _ => {}
+        }
+    }
+
+    /// Evicts a peer whose handshakes keep failing. Every eviction bans it for twice as long
+    /// as the previous one, starting from `blacklist_duration`.
+    fn evict_failing_peer(&mut self, peer: Peer) {
+        let strikes = self.blacklist_strikes.entry(peer.addr).or_insert(0);
+        let duration = self
+            .blacklist_duration
+            .saturating_mul(1 << (*strikes).min(16))
+            .min(MAX_BLACKLIST_DURATION);
+        *strikes += 1;
+        log::info!("peer.addr":% = peer.addr, duration:? = duration; "Blacklisting peer");
+        self.peer_failures.remove(&peer.addr);
+        self.blacklist_peer(peer.addr, duration);
+    }
+
+    pub fn metrics(&self) -> SyncMetrics {
+        self.counters.snapshot()
+    }
+
+    /// Replaces the entry for the same node in place, keeping its position in the list.
+    fn update_peer(&mut self, mut peer: Peer) {
+        if let Some(existing) = self.peers.iter_mut().find(|p| **p == peer) {
+            peer.addr = existing.addr;
+            *existing = peer;
+        } else {
+            self.add_peer(peer);
+        }
+    }
+
+    /// Handshakes with every known peer and elects one of those that answered. Fails with
+    /// `NetworkError::AllPeersUnreachable` if there were peers but none could be reached; the
+    /// peer list is updated either way.
+    ///
+    /// A round that leaves no peers at all re-bootstraps, so the node doesn't stay stranded:
+    /// the peers it was created with and the DNS seeds given to `bootstrap_from_seeds` are
+    /// added again, to be handshaked next round. Blacklisted ones stay out until their ban
+    /// runs out, which a later empty round then picks up.
+    ///
+    /// Peers that report the same height but a different events commitment than most others
+    /// at that height are demoted and not elected, see `EventsCommitment`.
+    ///
+    /// With a `sync_retry_budget`, the handshakes of a round retry no more than that many times
+    /// in total; once it is used up, a failed handshake counts as final like one that ran out
+    /// of `max_handshake_retries`.
+    ///
+    /// With a `sync_round_timeout`, a round that runs out of time stops waiting on its
+    /// outstanding requests and goes on with what it has: peers that answered are updated and
+    /// elected from as usual, while the ones that hadn't answered yet are left untouched
+    /// rather than counted as failures. The report then has `timed_out` set and is returned
+    /// even if no peer answered in time.
+    ///
+    /// A node (non-client) without an external address fails with `NetworkError::NotANode`
+    /// before any peer is contacted.
+    pub async fn sync_with_peers(&mut self) -> Result<SyncReport, NetworkError> {
+        if !self.is_client && self.external_addr.is_none() {
+            return Err(NetworkError::NotANode);
+        }
+
+        let deadline = self
+            .sync_round_timeout
+            .map(|timeout| tokio::time::Instant::now() + timeout);
+        let mut report = SyncReport::default();
+        let initial_peers = self.get_peers();
+        let now = self.clock.now();
+        self.blacklist.retain(|_, until| *until > now);
+        self.tripped_peers.retain(|_, until| *until > now);
+        self.decay_peer_penalties();
+
+        let mut targets = Vec::new();
+        for peer in self.get_peers() {
+            let url = self.handshake_url(&peer)?;
+            targets.push((peer, url));
+        }
+
+        // Handshakes run concurrently, but results are applied in the original peer order so
+        // that election and peer-list mutations stay deterministic.
+        let this = &*self;
+        let contacted = targets.len();
+        let retry_budget = self.sync_retry_budget.map(AtomicU32::new);
+        let retry_budget = retry_budget.as_ref();
+        let mut results = stream::iter(targets.into_iter().enumerate())
+            .map(|(i, (peer, url))| async move {
+                let timeout = this.timeout_for(&peer, this.handshake_timeout);
+                let outcome = this
+                    .handshake_with_retries(&url, this.bearer_for(&peer), timeout, retry_budget)
+                    .await;
+                (i, peer, outcome)
+            })
+            .buffer_unordered(self.handshake_concurrency.max(1))
+            .take_until(until_deadline(deadline, std::future::pending::<()>()))
+            .collect::<Vec<_>>()
+            .await;
+        results.sort_by_key(|(i, ..)| *i);
+        report.timed_out = results.len() < contacted;
+
+        let mut reached = Vec::new();
+        let mut commitments = Vec::new();
+        for (_, mut peer, outcome) in results {
+            match outcome.result {
+                Ok(handshake)
+                    if !self.is_compatible_version(handshake.version.unwrap_or_default()) =>
+                {
+                    log::error!(
+                        "peer.addr":% = peer.addr, version = handshake.version.unwrap_or_default();
+                        "Peer speaks an incompatible protocol version"
+                    );
+                    self.peer_failures.remove(&peer.addr);
+                    self.remove_peer(peer);
+                }
+                Ok(handshake) => {
+                    log::info!(
+                        "peer.addr":% = peer.addr, block = handshake.current_block_number;
+                        "Synced with peer"
+                    );
+                    if outcome.failures > 0 {
+                        self.peer_failures.insert(peer.addr, outcome.failures);
+                    } else {
+                        self.peer_failures.remove(&peer.addr);
+                    }
+                    self.blacklist_strikes.remove(&peer.addr);
+                    peer.current_block = handshake.current_block_number;
+                    peer.max_events_page = handshake.max_events_page;
+                    peer.record_handshake(outcome.failures, outcome.latency);
+                    self.update_peer(peer.clone());
+                    reached.push(peer.clone());
+                    if let Some(commitment) = handshake.events_commitment {
+                        commitments.push((peer.addr, peer.current_block, commitment));
+                    }
+
+                    match until_deadline(deadline, self._add_batch_peer_peers(peer.clone())).await {
+                        Some(result) => result?,
+                        None => report.timed_out = true,
+                    }
+                }
+                // Our token won't become valid by asking again, but the peer isn't
+                // misbehaving either, so it's dropped without being blacklisted.
+                Err(NetworkError::Unauthorized(_)) => {
+                    log::error!("peer.addr":% = peer.addr; "Peer rejected our credentials");
+                    self.peer_failures.remove(&peer.addr);
+                    self.remove_peer(peer);
+                }
+                // A 5xx means the peer is up but overloaded, so it's kept with its health
+                // lowered. Anything else means it can't serve us and it's dropped.
+                Err(e) => match peer_status(&e) {
+                    Some(status) if status.is_server_error() => {
+                        log::warn!("peer.addr":% = peer.addr, status:% = status; "Peer is temporarily unavailable");
+                        self.peer_failures.insert(peer.addr, outcome.failures);
+                        peer.record_handshake(outcome.failures, None);
+                        self.update_peer(peer);
+                    }
+                    Some(status) if status.is_client_error() => {
+                        log::error!("peer.addr":% = peer.addr, status:% = status; "Peer rejected the handshake");
+                        self.evict_failing_peer(peer);
+                    }
+                    Some(status) => {
+                        log::error!("peer.addr":% = peer.addr, status:% = status; "Unexpected status from peer");
+                        self.evict_failing_peer(peer);
+                    }
+                    None => {
+                        log::error!(
+                            "peer.addr":% = peer.addr, "error.kind" = e.kind(), error:% = e;
+                            "Failed to handshake with peer"
+                        );
+                        self.evict_failing_peer(peer);
+                    }
+                },
+            }
+        }
+
+        report.reached = reached.len();
+        report.max_block = reached.iter().map(|p| p.current_block).max().unwrap_or(0);
+        // Peers dropped after their handshake, e.g. for a garbled peer list, can't be elected,
+        // and neither can peers whose circuit breaker is still cooling down.
+        reached.retain(|p| self.peers.contains(p) && !self.is_tripped(p.addr));
+        let divergent = divergent_peers(&commitments);
+        for addr in &divergent {
+            if let Some(peer) = self.peers.iter_mut().find(|p| p.addr == *addr) {
+                log::warn!(
+                    "peer.addr":% = addr, block = peer.current_block;
+                    "Peer's events diverge from the majority at its height"
+                );
+                peer.health = 0.0;
+                report.divergent.push(peer.clone());
+            }
+        }
+        let elected_diverged = self
+            .elected_peer
+            .as_ref()
+            .is_some_and(|p| divergent.contains(&p.addr));
+        reached.retain(|p| !divergent.contains(&p.addr));
+        match self.elect(&reached) {
+            Some(elected_peer) => {
+                log::info!(
+                    "peer.addr":% = elected_peer.addr, block = elected_peer.current_block;
+                    "Elected peer"
+                );
+                self.set_elected_peer(Some(elected_peer.clone()));
+                report.elected = Some(elected_peer);
+            }
+            None if elected_diverged => self.set_elected_peer(None),
+            None => {}
+        }
+        if let Some(max_lag) = self.stale_peer_lag {
+            self.prune_stale_peers(max_lag);
+        }
+
+        report.dropped = initial_peers
+            .into_iter()
+            .filter(|p| !self.peers.contains(p))
+            .collect();
+
+        log::info!(peers = self.peers.len(); "Synced with peers");
+        if self.peers.is_empty() && until_deadline(deadline, self.rebootstrap()).await.is_none() {
+            report.timed_out = true;
+        }
+
+        if report.timed_out {
+            log::warn!(
+                reached = report.reached, contacted = contacted;
+                "Sync round ran out of time, peers that hadn't answered are left as they were"
+            );
+            return Ok(report);
+        }
+        if report.reached == 0 && (!report.dropped.is_empty() || !self.peers.is_empty()) {
+            return Err(NetworkError::AllPeersUnreachable);
+        }
+        Ok(report)
+    }
+
+    /// Caps how long a whole `sync_with_peers` round may take, handshakes and peer lists
+    /// included, or lifts the cap with `None`. See `sync_with_peers` for what a round that
+    /// runs out of time does.
+    pub fn set_sync_round_timeout(&mut self, timeout: Option<Duration>) {
+        self.sync_round_timeout = timeout;
+    }
+
+    /// Refills an empty peer list from the bootstrap peers and DNS seeds. Returns the number
+    /// of peers added.
+    async fn rebootstrap(&mut self) -> usize {
+        if self.bootstrap_peers.is_empty() && self.seeds.is_empty() {
+            return 0;
+        }
+        log::warn!(
+            bootstrap_peers = self.bootstrap_peers.len(), seeds = self.seeds.len();
+            "Peer list is empty, re-bootstrapping"
+        );
+        self.add_peers(self.bootstrap_peers.clone());
+        let seeds = self.seeds.clone();
+        self.resolve_seeds(&seeds).await;
+        self.peers.len()
+    }
+
+    /// Does a single handshake with the elected peer and returns its current block number.
+    /// Fails with `NetworkError::NoElectedPeer` if no peer has been elected yet.
+    #[cfg(test)]
+    pub async fn ping_elected(&self) -> Result<u64, NetworkError> {
+        let peer = self
+            .elected_peer
+            .as_ref()
+            .ok_or(NetworkError::NoElectedPeer)?;
+        let url = self.handshake_url(peer)?;
+        bump(&self.counters.handshakes_attempted, 1);
+        let timeout = self.timeout_for(peer, self.handshake_timeout);
+        match self.handshake(&url, self.bearer_for(peer), timeout).await {
+            Ok(handshake) => {
+                bump(&self.counters.handshakes_succeeded, 1);
+                Ok(handshake.current_block_number)
+            }
+            Err(e) => {
+                bump(&self.counters.handshakes_failed, 1);
+                Err(e)
+            }
+        }
+    }
+
+    /// Handshakes every known peer once, concurrently, and reports what each answered, in
+    /// peer list order. Nothing is changed: no peer is added, dropped or elected, and their
+    /// recorded health is left alone.
+    pub async fn peer_status(&self) -> Vec<PeerStatus> {
+        stream::iter(self.get_peers())
+            .map(|peer| async move {
+                let started = self.clock.now();
+                let handshake = match self.handshake_url(&peer) {
+                    Ok(url) => {
+                        bump(&self.counters.handshakes_attempted, 1);
+                        let timeout = self.timeout_for(&peer, self.handshake_timeout);
+                        self.handshake(&url, self.bearer_for(&peer), timeout).await
+                    }
+                    Err(e) => Err(e),
+                };
+                match handshake {
+                    Ok(handshake) => {
+                        bump(&self.counters.handshakes_succeeded, 1);
+                        PeerStatus {
+                            addr: peer.addr,
+                            reachable: true,
+                            current_block: Some(handshake.current_block_number),
+                            latency: Some(self.clock.now().saturating_duration_since(started)),
+                        }
+                    }
+                    Err(e) => {
+                        log::debug!(
+                            "peer.addr":% = peer.addr, "error.kind" = e.kind(), error:% = e;
+                            "Peer status check failed"
+                        );
+                        bump(&self.counters.handshakes_failed, 1);
+                        PeerStatus {
+                            addr: peer.addr,
+                            reachable: false,
+                            current_block: None,
+                            latency: None,
+                        }
+                    }
+                }
+            })
+            .buffered(self.handshake_concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Asks a peer to handshake us back at our `external_addr` and reports whether it got
+    /// through. The elected peer is asked first, then the other peers in order, until one of
+    /// them answers. A node that isn't reachable can still sync, but no peer can connect to it
+    /// and gossip won't spread its address, so a warning is logged in that case.
+    pub async fn verify_reachability(&self) -> Result<bool, eyre::Report> {
+        let addr = self.external_addr.ok_or(NetworkError::NotANode)?;
+        let candidates = self.elected_peer.iter().cloned().chain(
+            self.get_peers()
+                .into_iter()
+                .filter(|p| Some(p.addr) != self.elected_peer.as_ref().map(|e| e.addr)),
+        );
+        for peer in candidates {
+            let url = format!("{}/reachability?addr={}", peer.base_url(), addr);
+            // The peer has to finish its own handshake with us before it can answer.
+            let timeout = self.timeout_for(&peer, self.peers_timeout);
+            let reachable = match self.peer_get(&url, self.bearer_for(&peer), timeout).await {
+                Ok(resp) if resp.status.is_success() => {
+                    serde_json::from_str::<GetReachabilityResponse>(&resp.body)
+                        .map(|r| r.reachable)
+                        .map_err(|e| NetworkError::parse(&url, e))
+                }
+                Ok(resp) => Err(NetworkError::PeerStatus(resp.status)),
+                Err(e) => Err(e),
+            };
+            match reachable {
+                Ok(true) => {
+                    log::info!("external_addr":% = addr, "peer.addr":% = peer.addr; "Node is reachable");
+                    return Ok(true);
+                }
+                Ok(false) => {
+                    log::warn!(
+                        "external_addr":% = addr, "peer.addr":% = peer.addr;
+                        "Node is not reachable at its external address, peers can't connect to it and gossip won't propagate it"
+                    );
+                    return Ok(false);
+                }
+                Err(e) => {
+                    log::debug!(
+                        "peer.addr":% = peer.addr, "error.kind" = e.kind(), error:% = e;
+                        "Peer couldn't check our reachability"
+                    );
+                }
+            }
+        }
+        Err(eyre::eyre!(
+            "No peer could check whether {} is reachable",
+            addr
+        ))
+    }
+
+    /// Whether a node answers a handshake at `addr`. This is the check behind a peer's
+    /// `verify_reachability`; we handshake as a client so the node doesn't add us as a peer.
+    pub async fn is_reachable(&self, addr: SocketAddr) -> bool {
+        let peer = Peer::new(addr);
+        if !peer.has_valid_addr() {
+            return false;
+        }
+        let url = format!(
+            "{}/handshake?is_client=true&version={}",
+            peer.base_url(),
+            PROTOCOL_VERSION
+        );
+        self.handshake(&url, None, self.handshake_timeout)
+            .await
+            .is_ok()
+    }
+
+    fn handshake_url(&self, peer: &Peer) -> Result<String, NetworkError> {
+        let mut url = format!(
+            "{}/handshake?is_client={}&version={}",
+            peer.base_url(),
+            self.is_client,
+            PROTOCOL_VERSION
+        );
+        if !self.is_client {
+            url = format!(
+                "{}&addr={}",
+                url,
+                self.external_addr.ok_or(NetworkError::NotANode)?
+            );
+        }
+        Ok(url)
+    }
+
+    /// Runs `sync_with_peers` on a copy of the manager `manager` picks out of `shared`. The
+    /// lock is only held to take the copy and to merge the outcome back with `merge_synced`,
+    /// so the manager stays usable while handshakes are in flight.
+    pub async fn sync_shared<C>(
+        shared: &Mutex<C>,
+        manager: fn(&mut C) -> &mut NodeManager,
+    ) -> Result<SyncReport, NetworkError> {
+        let before = manager(&mut *shared.lock().await).clone();
+        let mut synced = before.clone();
+        let result = synced.sync_with_peers().await;
+        // Even a failed round updates the peer list, so it's merged anyway.
+        manager(&mut *shared.lock().await).merge_synced(&before, synced);
+        result
+    }
+
+    /// Runs `sync_shared` every `interval`, give or take up to a fifth of it so that nodes
+    /// started together don't all hit their peers at once. The first round starts right
+    /// away, and after each one the state is saved with `save_state`, so a crash loses at
+    /// most a round.
+    pub fn spawn_sync_loop<C: Send + 'static>(
+        shared: Arc<Mutex<C>>,
+        manager: fn(&mut C) -> &mut NodeManager,
+        interval: Duration,
+    ) -> SyncLoopHandle {
+        let cancel = CancellationToken::new();
+        let token = cancel.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let started = Instant::now();
+                match NodeManager::sync_shared(&shared, manager).await {
+                    Ok(report) => log::info!(
+                        elapsed:? = started.elapsed(), reached = report.reached,
+                        dropped = report.dropped.len();
+                        "Synced with peers"
+                    ),
+                    Err(e) => log::error!(
+                        "error.kind" = e.kind(), error:% = e;
+                        "Error occurred while syncing with peers"
+                    ),
+                }
+                if let Err(e) = manager(&mut *shared.lock().await).save_state() {
+                    log::error!(error:% = e; "Failed to save state");
+                }
+
+                let jitter = rand::thread_rng().gen_range(0.8..1.2);
+                tokio::select! {
+                    _ = token.cancelled() => break,
+                    _ = tokio::time::sleep(interval.mul_f64(jitter)) => {}
+                }
+            }
+        });
+        SyncLoopHandle { cancel, task }
+    }
+
+    /// Takes over the outcome of a `sync_with_peers` round that ran on `synced`, a copy of
+    /// this manager taken as `before`, keeping what other tasks changed here in the meantime:
+    /// peers they added or removed, health they lowered, bans, and a peer they elected.
+    fn merge_synced(&mut self, before: &NodeManager, mut synced: NodeManager) {
+        let added: Vec<Peer> = self
+            .peers
+            .iter()
+            .filter(|p| !before.peers.contains(p))
+            .cloned()
+            .collect();
+        synced
+            .peers
+            .retain(|p| self.peers.contains(p) || !before.peers.contains(p));
+        for peer in synced.peers.iter_mut() {
+            let ours = self.peers.iter().find(|p| *p == peer);
+            let old = before.peers.iter().find(|p| *p == peer);
+            if let (Some(ours), Some(old)) = (ours, old) {
+                if ours.health != old.health {
+                    peer.health = peer.health.min(ours.health);
+                }
+            }
+        }
+        self.peers = synced.peers;
+        self.peer_failures = synced.peer_failures;
+        self.last_decay = synced.last_decay;
+        for (addr, until) in synced.blacklist {
+            let ours = self.blacklist.entry(addr).or_insert(until);
+            *ours = (*ours).max(until);
+        }
+        for (addr, strikes) in synced.blacklist_strikes {
+            let ours = self.blacklist_strikes.entry(addr).or_insert(strikes);
+            *ours = (*ours).max(strikes);
+        }
+        self.add_peers(added);
+
+        let elected = |m: &NodeManager| m.elected_peer.as_ref().map(|p| p.addr);
+        if elected(self) == elected(before) {
+            self.elected_peer = synced.elected_peer;
+            self.elected_at = synced.elected_at;
+        } else if elected(self) != synced.elected_peer.as_ref().map(|p| p.addr) {
+            // Subscribers were told about the round's election, which doesn't stand.
+            let _ = self.election_changes.send(ElectionChange {
+                previous: synced.elected_peer,
+                elected: self.elected_peer.clone(),
+            });
+        }
+        self.reelect_if_removed();
+    }
+
+    /// Handshakes with a peer, retrying up to `max_handshake_retries` times with exponential
+    /// backoff. Each retry also takes one from `retry_budget`, if given, and once that is used
+    /// up the handshake fails without retrying. The outcome also carries the number of failed
+    /// attempts, which the caller records as the peer's failure counter, and the latency of
+    /// the successful attempt.
+    async fn handshake_with_retries(
+        &self,
+        url: &str,
+        bearer: Option<&str>,
+        timeout: Duration,
+        retry_budget: Option<&AtomicU32>,
+    ) -> HandshakeOutcome {
+        let mut backoff = self.handshake_retry_backoff;
+        let mut failures = 0;
+        loop {
+            let started = self.clock.now();
+            bump(&self.counters.handshakes_attempted, 1);
+            match self.handshake(url, bearer, timeout).await {
+                Ok(handshake) => {
+                    bump(&self.counters.handshakes_succeeded, 1);
+                    return HandshakeOutcome {
+                        result: Ok(handshake),
+                        failures,
+                        latency: Some(self.clock.now().saturating_duration_since(started)),
+                    };
+                }
+                Err(e) => {
+                    bump(&self.counters.handshakes_failed, 1);
+                    failures += 1;
+                    // Client errors won't go away by asking again.
+                    let retryable = !matches!(e, NetworkError::Unauthorized(_))
+                        && !peer_status(&e).is_some_and(|s| s.is_client_error());
+                    let budgeted = || {
+                        retry_budget.is_none_or(|budget| {
+                            budget
+                                .fetch_update(
+                                    atomic::Ordering::Relaxed,
+                                    atomic::Ordering::Relaxed,
+                                    |left| left.checked_sub(1),
+                                )
+                                .is_ok()
+                        })
+                    };
+                    if failures > self.max_handshake_retries || !retryable || !budgeted() {
+                        return HandshakeOutcome {
+                            result: Err(e),
+                            failures,
+                            latency: None,
+                        };
+                    }
+                    log::warn!(
+                        url = url, attempt = failures, backoff:? = backoff, "error.kind" = e.kind();
+                        "Handshake with peer failed, retrying"
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+
+    async fn handshake(
+        &self,
+        url: &str,
+        bearer: Option<&str>,
+        timeout: Duration,
+    ) -> Result<GetHandShakeResponse, NetworkError> {
+        let resp = self.peer_get(url, bearer, timeout).await?;
+        if resp.status == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(NetworkError::Unauthorized(url.to_string()));
+        }
+        if !resp.status.is_success() {
+            return Err(NetworkError::PeerStatus(resp.status));
+        }
+        serde_json::from_str(&resp.body).map_err(|e| NetworkError::parse(url, e))
+    }
+
+    async fn _add_batch_peer_peers(&mut self, peer: Peer) -> Result<(), NetworkError> {
+        let url = format!("{}/get-peers", peer.base_url());
+        let resp = self
+            .peer_get(
+                &url,
+                self.bearer_for(&peer),
+                self.timeout_for(&peer, self.peers_timeout),
+            )
+            .await;
+
+        if let Ok(resp) = resp {
+            if resp.status.is_success() {
+                // A garbled peer list only costs us that peer, not the rest of the sync.
+                match serde_json::from_str::<GetPeersResponse>(&resp.body) {
+                    Ok(peers) => {
+                        let (valid, invalid): (Vec<Peer>, Vec<Peer>) =
+                            peers.peers.into_iter().partition(|p| p.has_valid_addr());
+                        for p in invalid {
+                            log::warn!(
+                                "peer.addr":% = peer.addr, advertised:% = p.addr;
+                                "Peer advertised an invalid address"
+                            );
+                        }
+                        // Whether we keep the peer itself is up to its handshakes, not to
+                        // what it says about itself.
+                        let advertised = valid
+                            .into_iter()
+                            .filter(|p| {
+                                p.normalized_addr() != peer.normalized_addr() && !self.is_self(p)
+                            })
+                            .collect();
+                        self.add_peers(advertised);
+                    }
+                    Err(e) => {
+                        let e = NetworkError::parse(&url, e);
+                        log::error!(
+                            "peer.addr":% = peer.addr, "error.kind" = e.kind(), error:% = e;
+                            "Peer sent an invalid peer list"
+                        );
+                        self.remove_peer(peer);
+                    }
+                }
+            } else if resp.status == reqwest::StatusCode::UNAUTHORIZED {
+                log::error!("peer.addr":% = peer.addr; "Peer rejected our credentials");
+                self.remove_peer(peer);
+            } else if resp.status.is_server_error() {
+                log::warn!(
+                    "peer.addr":% = peer.addr, status:% = resp.status;
+                    "Peer is temporarily unavailable"
+                );
+            } else if resp.status.is_client_error() {
+                log::error!(
+                    "peer.addr":% = peer.addr, status:% = resp.status;
+                    "Peer rejected the peers request"
+                );
+                self.remove_peer(peer);
+            } else {
+                log::error!(
+                    "peer.addr":% = peer.addr, status:% = resp.status;
+                    "Unexpected status from peer"
+                );
+                self.remove_peer(peer);
+            }
+        } else {
+            log::error!("peer.addr":% = peer.addr; "Failed to get peers from peer");
+            self.remove_peer(peer);
+        }
+        Ok(())
+    }
+
+    /// Deposit index of the first sent event of the contract, if the network config has it.
+    fn first_sent_index(&self) -> Option<u64> {
+        self.network.as_ref()?.config.genesis_deposit_index
+    }
+
+    /// Makes `provider_network` the only provider, dropping any backups.
+    pub fn set_provider_network(&mut self, provider_network: Network) {
+        self.network = Some(provider_network);
+        self.backup_networks.clear();
+    }
+
+    /// Adds a provider to fail over to. The first one added becomes the primary.
+    #[cfg(test)]
+    pub fn add_provider_network(&mut self, provider_network: Network) {
+        if self.network.is_none() {
+            self.network = Some(provider_network);
+        } else {
+            self.backup_networks.push(provider_network);
+        }
+    }
+
+    /// The primary provider followed by the backups, in the order they're tried.
+    pub fn provider_networks(&self) -> impl Iterator<Item = &Network> {
+        self.network.iter().chain(self.backup_networks.iter())
+    }
+
+    /// Drops the primary provider and any backups, e.g. when switching to peer-only mode.
+    /// Provider queries then fail with `NetworkError::ProviderNotSet`, as if none had been
+    /// set.
+    #[cfg(test)]
+    pub fn clear_provider_network(&mut self) {
+        self.network = None;
+        self.backup_networks.clear();
+    }
+
+    pub fn get_provider_network(&self) -> Option<Network> {
+        self.network.clone()
+    }
+
+    /// Like `get_events_with_cursor`, but when verification is enabled and a provider is
+    /// configured, the elected peer's claimed height and a sample of the fetched range are
+    /// checked against the provider. A peer that fails the check, serves an invalid page or
+    /// trips its circuit breaker is demoted, a new peer is elected, and
+    /// `NetworkError::PeerVerificationFailed` (or `InvalidEventsPage`, `PeerTripped`) is
+    /// returned without advancing the cursor. Like `get_events_from_elected_peer`, an
+    /// election older than `max_election_age` is renewed first.
+    pub async fn get_verified_events(
+        &mut self,
+        cursor: &SyncCursor,
+        cancel: &CancellationToken,
+    ) -> Result<(Vec<SpendFilter>, Vec<SentFilter>, SyncCursor), eyre::Report> {
+        self.refresh_stale_election().await?;
+        let result = self.get_events_with_cursor(cursor, cancel).await;
+        if let Err(e) = &result {
+            self.handle_elected_peer_error(e);
+        }
+        let (spend_events, sent_events, next) = result?;
+        let (sample_blocks, peer) = match (self.verify_sample_blocks, self.elected_peer.clone()) {
+            (Some(sample_blocks), Some(peer)) if self.network.is_some() => (sample_blocks, peer),
+            _ => return Ok((spend_events, sent_events, next)),
+        };
+
+        let head = self.provider_block_number().await?;
+        let mut consistent =
+            peer.current_block <= head.saturating_add(self.election_block_tolerance);
+        let start = if cursor.last_block > 0 {
+            cursor.last_block + 1
+        } else {
+            0
+        }
+        .max(peer.current_block.saturating_sub(sample_blocks));
+        if consistent && start < peer.current_block {
+            let provider_spends = self.get_spend_events(start, peer.current_block).await?;
+            let provider_sents = self.get_sent_events(start, peer.current_block).await?;
+            consistent = events_consistent(
+                &spend_events,
+                &sent_events,
+                &provider_spends,
+                &provider_sents,
+            );
+        }
+
+        if consistent {
+            Ok((spend_events, sent_events, next))
+        } else {
+            log::warn!("peer.addr":% = peer.addr; "Peer served events that failed verification");
+            self.demote_elected_peer();
+            Err(NetworkError::PeerVerificationFailed(peer.addr).into())
+        }
+    }
+
+    /// Runs `sync_with_peers` if the elected peer was elected more than `max_election_age`
+    /// ago. Its error is returned rather than falling back to the old election.
+    async fn refresh_stale_election(&mut self) -> Result<(), NetworkError> {
+        let age = match (self.max_election_age, self.elected_at) {
+            (Some(max_age), Some(elected_at)) => {
+                let age = self.clock.now().saturating_duration_since(elected_at);
+                if age <= max_age {
+                    return Ok(());
+                }
+                age
+            }
+            _ => return Ok(()),
+        };
+        log::info!(age:? = age; "Election is too old, syncing with peers before fetching");
+        self.sync_with_peers().await?;
+        Ok(())
+    }
+
+    /// Drops the elected peer's health to zero and elects the best of the remaining peers,
+    /// skipping any that were demoted before.
+    fn demote_elected_peer(&mut self) {
+        if let Some(demoted) = self.elected_peer.take() {
+            if let Some(p) = self.peers.iter_mut().find(|p| **p == demoted) {
+                p.health = 0.0;
+            }
+            let candidates: Vec<Peer> = self
+                .get_peers()
+                .into_iter()
+                .filter(|p| *p != demoted && p.health > 0.0 && !self.is_tripped(p.addr))
+                .collect();
+            self.set_elected_peer(self.elect(&candidates));
+        }
+    }
+
+    /// Used when no peer is elected: with `provider_fallback` set and a provider configured,
+    /// the events since `cursor.last_block` are queried over RPC. Otherwise there is nothing to
+    /// fetch from and `NetworkError::NoElectedPeer` is returned.
+    async fn get_events_without_peer(
+        &self,
+        cursor: SyncCursor,
+        streams: EventStreams,
+    ) -> Result<(Vec<SpendFilter>, Vec<SentFilter>, SyncCursor), NetworkError> {
+        let network = match self.get_provider_network() {
+            Some(network) if self.provider_fallback => network,
+            _ => {
+                log::error!("Elected peer is not set");
+                return Err(NetworkError::NoElectedPeer);
+            }
+        };
+
+        let head = self.provider_block_number().await?;
+        let from = if cursor.last_block > 0 {
+            cursor.last_block + 1
+        } else {
+            network.deployment_block()
+        };
+        if from > head {
+            return Ok((vec![], vec![], cursor));
+        }
+        log::info!(
+            "block.from" = from, "block.to" = head;
+            "No elected peer, fetching events from the provider"
+        );
+        let mut spend_events = if streams.includes_spend() {
+            self.get_spend_events(from, head + 1).await?
+        } else {
+            vec![]
+        };
+        let mut sent_events = if streams.includes_sent() {
+            self.get_sent_events(from, head + 1).await?
+        } else {
+            vec![]
+        };
+        if cursor.last_block == 0 {
+            // Without a block to resume from the scan starts at deployment, so the positions
+            // the caller already has are skipped like a peer would.
+            spend_events.drain(..cursor.from_spend.min(spend_events.len()));
+            sent_events.drain(..cursor.from_sent.min(sent_events.len()));
+        }
+        let next = SyncCursor {
+            from_spend: cursor.from_spend + spend_events.len(),
+            from_sent: cursor.from_sent + sent_events.len(),
+            last_block: head,
+        };
+        Ok((spend_events, sent_events, next))
+    }
+
+    /// Fetches every event past the given positions, demoting the elected peer if it serves
+    /// an invalid page. Alongside the events comes the block they reach: the height the peer
+    /// reported with its last page, or the provider's head when there's no elected peer and
+    /// the provider is used instead. Without either, `NetworkError::NoElectedPeer` is
+    /// returned rather than a block number.
+    ///
+    /// A successful result is always complete. If a page can't be fetched, the events from
+    /// the pages before it are dropped along with it and an error is returned, so callers can
+    /// retry from the same positions instead of mistaking a truncated result for the whole.
+    ///
+    /// An election older than `max_election_age` is renewed with `sync_with_peers` before
+    /// fetching, and if that fails its error is returned.
+    #[cfg(test)]
+    pub async fn get_events_from_elected_peer(
+        &mut self,
+        from_spend: usize,
+        from_sent: usize,
+    ) -> Result<(Vec<SpendFilter>, Vec<SentFilter>, u64), NetworkError> {
+        self.get_event_streams_from_elected_peer(from_spend, from_sent, EventStreams::Both)
+            .await
+    }
+
+    /// Like `get_events_from_elected_peer`, but only fetches the selected `streams`. The
+    /// events of a stream that isn't selected come back empty, and its position is ignored.
+    #[cfg(test)]
+    pub async fn get_event_streams_from_elected_peer(
+        &mut self,
+        from_spend: usize,
+        from_sent: usize,
+        streams: EventStreams,
+    ) -> Result<(Vec<SpendFilter>, Vec<SentFilter>, u64), NetworkError> {
+        self.refresh_stale_election().await?;
+        let cursor = SyncCursor {
+            from_spend,
+            from_sent,
+            last_block: 0,
+        };
+        let result = self
+            .get_event_streams_with_cursor(&cursor, streams, &CancellationToken::new())
+            .await;
+        if let Err(e) = &result {
+            self.handle_elected_peer_error(e);
+        }
+        let (spend_events, sent_events, cursor) = result?;
+        Ok((spend_events, sent_events, cursor.last_block))
+    }
+
+    /// Fetches every event past `cursor` from the elected peer and returns them together with
+    /// the cursor to resume from next time. Without an elected peer nothing is fetched and the
+    /// cursor is returned unchanged.
+    ///
+    /// Pages are checked with `validate_events_page`; if one fails, nothing is returned and
+    /// the error is `NetworkError::InvalidEventsPage`. A failed request is retried, but after
+    /// `breaker_threshold` failures in a row nothing is returned and the error is
+    /// `NetworkError::PeerTripped`. A page that doesn't parse, e.g. a truncated large
+    /// response, is retried with half the page size; like the block range scanners, the size
+    /// grows back after a streak of good pages.
+    ///
+    /// Cancelling `cancel` stops the paging loop, abandoning any in-flight request, and
+    /// returns the pages completed so far with the cursor pointing just past them. This is the
+    /// only way to get an incomplete result; callers that cancel should resume from the
+    /// returned cursor rather than treat the events as everything the peer has.
+    pub async fn get_events_with_cursor(
+        &self,
+        cursor: &SyncCursor,
+        cancel: &CancellationToken,
+    ) -> Result<(Vec<SpendFilter>, Vec<SentFilter>, SyncCursor), NetworkError> {
+        self.get_event_streams_with_cursor(cursor, EventStreams::Both, cancel)
+            .await
+    }
+
+    /// Like `get_events_with_cursor`, but only fetches the selected `streams`. The cursor
+    /// position of a stream that isn't selected is returned as it was given.
+    pub async fn get_event_streams_with_cursor(
+        &self,
+        cursor: &SyncCursor,
+        streams: EventStreams,
+        cancel: &CancellationToken,
+    ) -> Result<(Vec<SpendFilter>, Vec<SentFilter>, SyncCursor), NetworkError> {
+        let mut batches = Box::pin(self.event_batches(cursor, streams));
+        let mut spend_events = Vec::new();
+        let mut sent_events = Vec::new();
+        let mut next = cursor.clone();
+        if let Some(elected_peer) = &self.elected_peer {
+            next.last_block = elected_peer.current_block;
+        }
+
+        while !cancel.is_cancelled() {
+            let batch = tokio::select! {
+                _ = cancel.cancelled() => {
+                    log::info!("Event fetch cancelled");
+                    break;
+                }
+                batch = batches.next() => batch,
+            };
+            match batch {
+                Some(batch) => {
+                    let batch = batch?;
+                    spend_events.extend(batch.spend_events);
+                    sent_events.extend(batch.sent_events);
+                    next = batch.cursor;
+                }
+                None => break,
+            }
+        }
+        Ok((spend_events, sent_events, next))
+    }
+
+    /// Fetches the events past `cursor` from the elected peer page by page, yielding each page
+    /// as an `EventBatch` as soon as it has been fetched and checked, so that callers can
+    /// process a long sync incrementally. The next page is only requested once the stream is
+    /// polled again. The last batch is the peer's empty page past its final events, carrying
+    /// no events but the peer's latest height.
+    ///
+    /// Pages are fetched, retried and checked as described for `get_events_with_cursor`. An
+    /// error ends the stream; the batches yielded before it stay valid and the cursor of the
+    /// last one is where to resume. Without an elected peer, the provider fallback is a single
+    /// batch.
+    pub fn event_batches(
+        &self,
+        cursor: &SyncCursor,
+        streams: EventStreams,
+    ) -> impl Stream<Item = Result<EventBatch, NetworkError>> + '_ {
+        let elected_peer = match self.elected_peer.clone() {
+            Some(peer) => peer,
+            None => {
+                let cursor = cursor.clone();
+                return stream::once(async move {
+                    let (spend_events, sent_events, cursor) =
+                        self.get_events_without_peer(cursor, streams).await?;
+                    Ok(EventBatch {
+                        spend_events,
+                        sent_events,
+                        cursor,
+                    })
+                })
+                .left_stream();
+            }
+        };
+        self.peer_event_batches(elected_peer, cursor, streams)
+            .right_stream()
+    }
+
+    /// Pages the events past `cursor` from `peer`, for `event_batches` and
+    /// `get_events_from_peer`.
+    fn peer_event_batches(
+        &self,
+        peer: Peer,
+        cursor: &SyncCursor,
+        streams: EventStreams,
+    ) -> impl Stream<Item = Result<EventBatch, NetworkError>> + '_ {
+        let max_step = peer
+            .max_events_page
+            .map_or(self.events_page_size, |max| max.min(self.events_page_size))
+            .max(1);
+        let pager = EventPager {
+            peer,
+            cursor: cursor.clone(),
+            streams,
+            step: max_step,
+            max_step,
+            seen_spends: HashSet::new(),
+            last_sent: None,
+            peer_block: None,
+            successes: 0,
+            done: false,
+        };
+        stream::unfold(Some(pager), move |pager| async move {
+            let mut pager = pager?;
+            match self.next_event_batch(&mut pager).await {
+                Ok(batch) => {
+                    let more = !pager.done;
+                    Some((Ok(batch), more.then_some(pager)))
+                }
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+
+    /// Fetches every event past `cursor` from the known peer at `addr`, e.g. to compare two
+    /// peers' views of the same range, and returns them with the cursor to resume from. Pages
+    /// are fetched, retried and checked as in `get_events_with_cursor`, but the election is
+    /// left alone: the elected peer stays as it is and a failing peer isn't tripped. Fails
+    /// with `NetworkError::UnknownPeer` if no peer in the list has that address.
+    #[cfg(test)]
+    pub async fn get_events_from_peer(
+        &self,
+        addr: &str,
+        cursor: &SyncCursor,
+    ) -> Result<(Vec<SpendFilter>, Vec<SentFilter>, SyncCursor), NetworkError> {
+        let peer = self.known_peer(addr)?;
+        let mut next = cursor.clone();
+        next.last_block = peer.current_block;
+        let mut batches = Box::pin(self.peer_event_batches(peer, cursor, EventStreams::Both));
+        let mut spend_events = Vec::new();
+        let mut sent_events = Vec::new();
+        while let Some(batch) = batches.next().await {
+            let batch = batch?;
+            spend_events.extend(batch.spend_events);
+            sent_events.extend(batch.sent_events);
+            next = batch.cursor;
+        }
+        Ok((spend_events, sent_events, next))
+    }
+
+    /// Fetches the next `total_range` positions of each stream past `cursor`, split into
+    /// shards of a page each that are spread round-robin across the `shard_peers` best
+    /// healthy peers and fetched concurrently, at most one shard per peer at a time. Shards
+    /// are merged in order and events repeated between them are dropped. A peer that fails
+    /// part way through a shard, e.g. by tripping its breaker or serving an invalid page,
+    /// hands the rest of the shard to the next peer; only when every peer failed it is the
+    /// error returned.
+    ///
+    /// A stream a peer runs out of ends at that shard, since its events in later shards would
+    /// leave a gap; the other stream is still merged. Once both streams ran out the cursor
+    /// carries the lowest height of the peers that ran out. Otherwise `cursor.last_block` is
+    /// kept, as the events don't reach any peer's tip. Like
+    /// `get_events_from_peer`, the election is left alone. Fails with
+    /// `NetworkError::NoElectedPeer` if there is no healthy peer to fetch from.
+    pub async fn get_events_sharded(
+        &self,
+        total_range: usize,
+        cursor: &SyncCursor,
+    ) -> Result<(Vec<SpendFilter>, Vec<SentFilter>, SyncCursor), NetworkError> {
+        let mut peers: Vec<Peer> = self
+            .get_peers()
+            .into_iter()
+            .filter(|p| p.health > 0.0 && !self.is_tripped(p.addr) && !self.is_blacklisted(p.addr))
+            .collect();
+        peers.sort_by(|a, b| election_rank(b, a));
+        peers.truncate(self.shard_peers.max(1));
+        if peers.is_empty() {
+            return Err(NetworkError::NoElectedPeer);
+        }
+
+        let shard_len = self.events_page_size.max(1);
+        let peers = &peers;
+        let mut shards = stream::iter((0..total_range).step_by(shard_len).enumerate())
+            .map(|(i, offset)| {
+                let start = SyncCursor {
+                    from_spend: cursor.from_spend + offset,
+                    from_sent: cursor.from_sent + offset,
+                    last_block: cursor.last_block,
+                };
+                self.fetch_shard(peers, i, start, shard_len.min(total_range - offset))
+            })
+            .buffered(peers.len());
+
+        let mut spend_events = Vec::new();
+        let mut sent_events: Vec<SentFilter> = Vec::new();
+        let mut seen_spends = HashSet::new();
+        let mut next = cursor.clone();
+        let (mut spend_done, mut sent_done) = (false, false);
+        let mut tip: Option<u64> = None;
+        while let Some(shard) = shards.next().await {
+            let shard = shard?;
+            if !spend_done {
+                spend_events.extend(
+                    shard
+                        .spend_events
+                        .into_iter()
+                        .filter(|e| e.nullifier.is_zero() || seen_spends.insert(e.nullifier)),
+                );
+                next.from_spend = shard.cursor.from_spend;
+                spend_done = shard.spend_exhausted;
+            }
+            if !sent_done {
+                for e in shard.sent_events {
+                    if sent_events.last().is_none_or(|last| e.index > last.index) {
+                        sent_events.push(e);
+                    }
+                }
+                next.from_sent = shard.cursor.from_sent;
+                sent_done = shard.sent_exhausted;
+            }
+            if let Some(shard_tip) = shard.tip {
+                tip = Some(tip.map_or(shard_tip, |tip| tip.min(shard_tip)));
+            }
+            if spend_done && sent_done {
+                if let Some(tip) = tip {
+                    next.last_block = tip.max(cursor.last_block);
+                }
+                break;
+            }
+        }
+        Ok((spend_events, sent_events, next))
+    }
+
+    /// Fetches `len` positions of each stream from `start` for `get_events_sharded`, starting
+    /// with `peers[first]` and moving on to the following peers if it fails.
+    async fn fetch_shard(
+        &self,
+        peers: &[Peer],
+        first: usize,
+        start: SyncCursor,
+        len: usize,
+    ) -> Result<Shard, NetworkError> {
+        let spend_end = start.from_spend + len;
+        let sent_end = start.from_sent + len;
+        let mut spend_events: Vec<SpendFilter> = Vec::new();
+        let mut sent_events: Vec<SentFilter> = Vec::new();
+        let (mut spend_exhausted, mut sent_exhausted) = (false, false);
+        let mut tip = None;
+        let mut last_error = NetworkError::NoElectedPeer;
+        for peer in peers.iter().cycle().skip(first).take(peers.len()) {
+            let max_step = peer
+                .max_events_page
+                .map_or(self.events_page_size, |max| max.min(self.events_page_size))
+                .clamp(1, len.max(1));
+            let mut pager = EventPager {
+                peer: peer.clone(),
+                cursor: SyncCursor {
+                    from_spend: start.from_spend + spend_events.len(),
+                    from_sent: start.from_sent + sent_events.len(),
+                    last_block: start.last_block,
+                },
+                streams: EventStreams::Both,
+                step: max_step,
+                max_step,
+                seen_spends: spend_events
+                    .iter()
+                    .map(|e| e.nullifier)
+                    .filter(|n| !n.is_zero())
+                    .collect(),
+                last_sent: sent_events.last().cloned(),
+                peer_block: None,
+                successes: 0,
+                done: false,
+            };
+            loop {
+                let spend_left = if spend_exhausted {
+                    0
+                } else {
+                    spend_end.saturating_sub(pager.cursor.from_spend)
+                };
+                let sent_left = if sent_exhausted {
+                    0
+                } else {
+                    sent_end.saturating_sub(pager.cursor.from_sent)
+                };
+                pager.streams = match (spend_left > 0, sent_left > 0) {
+                    (true, true) => EventStreams::Both,
+                    (true, false) => EventStreams::Spend,
+                    (false, true) => EventStreams::Sent,
+                    (false, false) => {
+                        return Ok(Shard {
+                            cursor: SyncCursor {
+                                from_spend: start.from_spend + spend_events.len(),
+                                from_sent: start.from_sent + sent_events.len(),
+                                last_block: start.last_block,
+                            },
+                            spend_events,
+                            sent_events,
+                            spend_exhausted,
+                            sent_exhausted,
+                            tip,
+                        });
+                    }
+                };
+                pager.step = pager.step.min(spend_left.max(sent_left));
+                match self.next_event_batch(&mut pager).await {
+                    Ok(batch) => {
+                        // The last page may reach into the next shard.
+                        spend_events.extend(batch.spend_events.into_iter().take(spend_left));
+                        sent_events.extend(batch.sent_events.into_iter().take(sent_left));
+                        // Only the streams that were asked for ran out; the other one may
+                        // still have events left in the shard.
+                        if pager.done {
+                            spend_exhausted |= pager.streams.includes_spend();
+                            sent_exhausted |= pager.streams.includes_sent();
+                            tip = Some(batch.cursor.last_block);
+                            pager.done = false;
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "peer.addr":% = peer.addr, error:% = e,
+                            from_spend = pager.cursor.from_spend, from_sent = pager.cursor.from_sent;
+                            "Peer failed part way through a shard, handing it to the next peer"
+                        );
+                        last_error = e;
+                        break;
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Requests pages from `pager.peer` until one parses and passes `validate_events_page`, or
+    /// the circuit breaker trips, and advances `pager` past it.
+    async fn next_event_batch(&self, pager: &mut EventPager) -> Result<EventBatch, NetworkError> {
+        let peer = pager.peer.clone();
+        let streams = pager.streams;
+        let mut failures = 0;
+        loop {
+            let url = format!(
+                "{}/events?from_spend={}&from_sent={}&length={}",
+                peer.base_url(),
+                pager.cursor.from_spend,
+                pager.cursor.from_sent,
+                pager.step
+            );
+            let url = match streams {
+                EventStreams::Both => url,
+                EventStreams::Spend => format!("{}&streams=spend", url),
+                EventStreams::Sent => format!("{}&streams=sent", url),
+            };
+
+            let resp = self
+                .peer_get(
+                    &url,
+                    self.bearer_for(&peer),
+                    self.timeout_for(&peer, self.events_timeout),
+                )
+                .await;
+
+            match resp {
+                Ok(resp) if resp.status.is_success() => {
+                    let page: Result<GetEventsResponse, _> = serde_json::from_str(&resp.body);
+                    match page {
+                        Err(e) => {
+                            pager.step = (pager.step / 2).max(1);
+                            log::warn!(
+                                "peer.addr":% = peer.addr, error:% = e, step = pager.step;
+                                "Failed to parse events page, shrinking the page size"
+                            );
+                            pager.successes = 0;
+                            failures += 1;
+                        }
+                        Ok(mut json_resp) => {
+                            // Older peers serve both streams whatever we ask for.
+                            if !streams.includes_spend() {
+                                json_resp.spend_events.clear();
+                            }
+                            if !streams.includes_sent() {
+                                json_resp.sent_events.clear();
+                            }
+                            if let Some(block) = json_resp.current_block_number {
+                                pager.peer_block =
+                                    Some(pager.peer_block.map_or(block, |b: u64| b.max(block)));
+                            }
+                            // Peers that don't report their height with each page fall back to
+                            // the one from the handshake, which may be behind the events just
+                            // fetched.
+                            pager.cursor.last_block =
+                                pager.peer_block.unwrap_or(peer.current_block);
+                            if json_resp.spend_events.is_empty() && json_resp.sent_events.is_empty()
+                            {
+                                pager.done = true;
+                                return Ok(EventBatch {
+                                    spend_events: vec![],
+                                    sent_events: vec![],
+                                    cursor: pager.cursor.clone(),
+                                });
+                            }
+                            if let Err(reason) = validate_events_page(
+                                &json_resp,
+                                pager.step,
+                                pager.last_sent.as_ref(),
+                                self.first_sent_index()
+                                    .map(|base| U256::from(base) + pager.cursor.from_sent),
+                                &pager.seen_spends,
+                            ) {
+                                log::warn!(
+                                    "peer.addr":% = peer.addr, reason = reason.as_str();
+                                    "Peer served an invalid events page"
+                                );
+                                return Err(NetworkError::InvalidEventsPage(peer.addr, reason));
+                            }
+
+                            // The peer pages both streams independently and may return
+                            // fewer events than requested, so each cursor only moves by what
+                            // was actually received for its stream.
+                            pager.cursor.from_spend += json_resp.spend_events.len();
+                            pager.cursor.from_sent += json_resp.sent_events.len();
+
+                            pager.seen_spends.extend(
+                                json_resp
+                                    .spend_events
+                                    .iter()
+                                    .map(|e| e.nullifier)
+                                    .filter(|n| !n.is_zero()),
+                            );
+                            if let Some(last) = json_resp.sent_events.last() {
+                                pager.last_sent = Some(last.clone());
+                            }
+                            pager.successes += 1;
+                            if pager.successes >= SCAN_GROWTH_STREAK {
+                                pager.step = pager.step.saturating_mul(2).min(pager.max_step);
+                                pager.successes = 0;
+                            }
+                            bump(
+                                &self.counters.spend_events_fetched,
+                                json_resp.spend_events.len() as u64,
+                            );
+                            bump(
+                                &self.counters.sent_events_fetched,
+                                json_resp.sent_events.len() as u64,
+                            );
+                            return Ok(EventBatch {
+                                spend_events: json_resp.spend_events,
+                                sent_events: json_resp.sent_events,
+                                cursor: pager.cursor.clone(),
+                            });
+                        }
+                    }
+                }
+                Ok(resp) if resp.status == reqwest::StatusCode::UNAUTHORIZED => {
+                    log::error!("peer.addr":% = peer.addr; "Peer rejected our credentials");
+                    return Err(NetworkError::Unauthorized(url));
+                }
+                Ok(resp) => {
+                    log::error!(
+                        "peer.addr":% = peer.addr, status:% = resp.status;
+                        "Failed to get events from peer"
+                    );
+                    failures += 1;
+                }
+                Err(e) => {
+                    log::error!(
+                        "peer.addr":% = peer.addr, "error.kind" = e.kind(), error:% = e;
+                        "Failed to get events from peer"
+                    );
+                    failures += 1;
+                }
+            }
+            if failures >= self.breaker_threshold.max(1) {
+                return Err(NetworkError::PeerTripped(peer.addr));
+            }
+        }
+    }
+
+    /// Catches up from `cursor` the fast way: the bulk of the events comes from the elected
+    /// peer, which has them indexed, and the blocks between the peer's height and the
+    /// provider's confirmed head are then queried from the provider directly. If filling that
+    /// tail fails, the peer's events are still returned with the cursor at the peer's height.
+    #[cfg(test)]
+    pub async fn sync_events(
+        &self,
+        cursor: &SyncCursor,
+    ) -> Result<(Vec<SpendFilter>, Vec<SentFilter>, SyncCursor), NetworkError> {
+        let (mut spend_events, mut sent_events, mut next) = self
+            .get_events_with_cursor(cursor, &CancellationToken::new())
+            .await?;
+        // A peer behind the provider doesn't take back blocks the cursor already covers.
+        next.last_block = next.last_block.max(cursor.last_block);
+        if self.network.is_none() {
+            return Ok((spend_events, sent_events, next));
+        }
+
+        let from = next.last_block + 1;
+        let tail = async {
+            let head = self.latest_confirmed_block().await?;
+            if head < from {
+                return Ok::<_, eyre::Report>(None);
+            }
+            let spends = self.get_spend_events(from, head + 1).await?;
+            let sents = self.get_sent_events(from, head + 1).await?;
+            Ok(Some((spends, sents, head)))
+        }
+        .await;
+        match tail {
+            Ok(Some((spends, sents, head))) => {
+                log::info!(
+                    spent = spends.len(), sent = sents.len(), "block.from" = from, "block.to" = head;
+                    "Fetched events past the peer's height from the provider"
+                );
+                next.from_spend += spends.len();
+                next.from_sent += sents.len();
+                next.last_block = head;
+                spend_events.extend(spends);
+                sent_events.extend(sents);
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!(
+                block = from - 1, error:% = e;
+                "Failed to fetch events past the peer's height"
+            ),
+        }
+        self.record_cursor(&next);
+        Ok((spend_events, sent_events, next))
+    }
+
+    /// Spend and sent events in `[deployment block, to)`, skipping the empty range before the
+    /// contract existed. Fails with `NetworkError::ProviderNotSet` without a provider.
+    #[cfg(test)]
+    pub async fn scan_from_deployment(
+        &self,
+        to: u64,
+    ) -> Result<(Vec<SpendFilter>, Vec<SentFilter>), eyre::Report> {
+        let from = self
+            .network
+            .as_ref()
+            .ok_or(NetworkError::ProviderNotSet)?
+            .deployment_block();
+        if from >= to {
+            return Ok((vec![], vec![]));
+        }
+        let spend_events = self.get_spend_events(from, to).await?;
+        let sent_events = self.get_sent_events(from, to).await?;
+        Ok((spend_events, sent_events))
+    }
+
+    pub async fn get_spend_events(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<SpendFilter>, eyre::Report> {
+        self.get_spend_events_with_progress(from, to, None).await
+    }
+
+    /// There is no token filter: `Sent` has no indexed parameters, and its token address is
+    /// only a hint masked with the recipient's shared secret, so the provider can't match on
+    /// it. Recipients have to decode each event, see `extract_token_amount`.
+    pub async fn get_sent_events(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<SentFilter>, eyre::Report> {
+        self.get_sent_events_with_progress(from, to, None).await
+    }
+
+    /// Like `get_spend_events`, scanning at most `max_blocks_per_call` blocks, see
+    /// `query_events_capped`.
+    pub async fn get_spend_events_capped(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<(Vec<SpendFilter>, u64), eyre::Report> {
+        let (events, reached) = self.query_events_capped::<SpendFilter>(from, to).await?;
+        bump(&self.counters.spend_events_fetched, events.len() as u64);
+        Ok((events, reached))
+    }
+
+    /// Like `get_sent_events`, scanning at most `max_blocks_per_call` blocks, see
+    /// `query_events_capped`.
+    pub async fn get_sent_events_capped(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<(Vec<SentFilter>, u64), eyre::Report> {
+        let (events, reached) = self.query_events_capped::<SentFilter>(from, to).await?;
+        bump(&self.counters.sent_events_fetched, events.len() as u64);
+        Ok((events, reached))
+    }
+
+    /// Like `get_spend_events`, with block numbers and timestamps, see
+    /// `query_events_with_timestamps`.
+    pub async fn get_spend_events_with_timestamps(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<TimestampedEvent<SpendFilter>>, eyre::Report> {
+        let events = self.query_events_with_timestamps(from, to).await?;
+        bump(&self.counters.spend_events_fetched, events.len() as u64);
+        Ok(events)
+    }
+
+    /// Like `get_sent_events`, with block numbers and timestamps, see
+    /// `query_events_with_timestamps`.
+    pub async fn get_sent_events_with_timestamps(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<TimestampedEvent<SentFilter>>, eyre::Report> {
+        let events = self.query_events_with_timestamps(from, to).await?;
+        bump(&self.counters.sent_events_fetched, events.len() as u64);
+        Ok(events)
+    }
+
+    /// Like `get_spend_events`, reporting progress as in `query_events_with_progress`.
+    pub async fn get_spend_events_with_progress(
+        &self,
+        from: u64,
+        to: u64,
+        progress: Option<ScanProgress<'_>>,
+    ) -> Result<Vec<SpendFilter>, eyre::Report> {
+        let events = self
+            .query_events_with_progress::<SpendFilter>(from, to, progress)
+            .await?;
+        bump(&self.counters.spend_events_fetched, events.len() as u64);
+        Ok(events)
+    }
+
+    /// Like `get_sent_events`, reporting progress as in `query_events_with_progress`.
+    pub async fn get_sent_events_with_progress(
+        &self,
+        from: u64,
+        to: u64,
+        progress: Option<ScanProgress<'_>>,
+    ) -> Result<Vec<SentFilter>, eyre::Report> {
+        let events = self
+            .query_events_with_progress::<SentFilter>(from, to, progress)
+            .await?;
+        bump(&self.counters.sent_events_fetched, events.len() as u64);
+        Ok(events)
+    }
+
+    /// Queries the Owshen contract for events of type `E` in `[from, to)` through the
+    /// provider network. An empty result always means the whole range was scanned; a range
+    /// that couldn't be scanned fully is reported as an error.
+    ///
+    /// Providers are tried in turn: when one gives up part way, the next continues from the
+    /// first block that wasn't scanned. The query only fails once every provider has.
+    ///
+    /// With `confirmations` set, `to` is capped just past `latest_confirmed_block`, so callers
+    /// advancing their own cursor should use that block rather than the chain head.
+    #[cfg(test)]
+    pub async fn query_events<E: EthEvent + Clone + 'static>(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<E>, eyre::Report> {
+        self.query_events_with_progress(from, to, None).await
+    }
+
+    /// Like `query_events`, calling `progress` with the number of blocks scanned so far and
+    /// the total number of blocks to scan every time the scan moves forward.
+    pub async fn query_events_with_progress<E: EthEvent + Clone + 'static>(
+        &self,
+        from: u64,
+        to: u64,
+        progress: Option<ScanProgress<'_>>,
+    ) -> Result<Vec<E>, eyre::Report> {
+        let (events, _) = self.query_events_upto(from, to, None, progress).await?;
+        Ok(events.into_iter().map(|(event, _)| event).collect())
+    }
+
+    /// Like `query_events`, but scans no further than `max_blocks_per_call` blocks past
+    /// `from`. Returns the events along with the block the scan stopped before, so callers
+    /// covering a long range call again from there until it reaches `to`. Besides the cap,
+    /// the confirmation depth can stop the scan short of `to` too; when `from` itself isn't
+    /// confirmed yet, so that the scan couldn't move at all, this fails with
+    /// `NetworkError::NotConfirmed` and the caller should wait for more blocks.
+    pub async fn query_events_capped<E: EthEvent + Clone + 'static>(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<(Vec<E>, u64), eyre::Report> {
+        let max_blocks = self.max_blocks_per_call.unwrap_or(u64::MAX).max(1);
+        let (events, next) = self
+            .query_events_upto(from, to, Some(max_blocks), None)
+            .await?;
+        if next <= from && from < to {
+            return Err(NetworkError::NotConfirmed(from).into());
+        }
+        Ok((events.into_iter().map(|(event, _)| event).collect(), next))
+    }
+
+    /// Like `query_events`, with the number and timestamp of the block each event was
+    /// emitted in, see `block_timestamps`.
+    pub async fn query_events_with_timestamps<E: EthEvent + Clone + 'static>(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<TimestampedEvent<E>>, eyre::Report> {
+        let (events, _) = self.query_events_upto::<E>(from, to, None, None).await?;
+        let blocks: Vec<u64> = events.iter().map(|(_, block)| *block).collect();
+        let timestamps = self.block_timestamps(&blocks).await?;
+        Ok(events
+            .into_iter()
+            .map(|(event, block_number)| TimestampedEvent {
+                event,
+                block_number,
+                timestamp: timestamps[&block_number],
+            })
+            .collect())
+    }
+
+    /// Timestamps of the given blocks, keyed by block number. Each distinct block is looked
+    /// up once, in JSON-RPC batches of up to `BLOCK_TIMESTAMP_BATCH` blocks with up to
+    /// `BLOCK_TIMESTAMP_CONCURRENCY` batches in flight, and blocks at or below
+    /// `latest_confirmed_block` are cached across calls and clones of this manager since
+    /// their timestamps can't change anymore.
+    pub async fn block_timestamps(
+        &self,
+        blocks: &[u64],
+    ) -> Result<HashMap<u64, u64>, eyre::Report> {
+        let mut timestamps = HashMap::new();
+        let mut missing = Vec::new();
+        {
+            let cache = self.block_timestamps.lock().unwrap();
+            for block in blocks {
+                match cache.get(block) {
+                    Some(timestamp) => {
+                        timestamps.insert(*block, *timestamp);
+                    }
+                    None if !missing.contains(block) => missing.push(*block),
+                    None => {}
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(timestamps);
+        }
+
+        let confirmed = self.latest_confirmed_block().await?;
+        let fetched: Vec<HashMap<u64, u64>> =
+            stream::iter(missing.chunks(BLOCK_TIMESTAMP_BATCH).map(<[u64]>::to_vec))
+                .map(|blocks| async move { self.block_timestamp_batch(&blocks).await })
+                .buffer_unordered(BLOCK_TIMESTAMP_CONCURRENCY)
+                .try_collect()
+                .await?;
+        let mut cache = self.block_timestamps.lock().unwrap();
+        for (block, timestamp) in fetched.into_iter().flatten() {
+            if block <= confirmed {
+                cache.insert(block, timestamp);
+            }
+            timestamps.insert(block, timestamp);
+        }
+        Ok(timestamps)
+    }
+
+    /// Spend and sent events the Owshen contract emitted in the transaction `tx_hash`, read
+    /// from its receipt rather than by scanning blocks. Like other provider queries, each
+    /// configured provider is tried in turn. Fails if none has a receipt for it, e.g. because
+    /// the transaction isn't mined yet.
+    #[cfg(test)]
+    pub async fn get_events_for_tx(
+        &self,
+        tx_hash: H256,
+    ) -> Result<(Vec<SpendFilter>, Vec<SentFilter>), eyre::Report> {
+        let mut last_err: eyre::Report = NetworkError::ProviderNotSet.into();
+        for network in self.provider_networks() {
+            match network.provider.get_transaction_receipt(tx_hash).await {
+                Ok(Some(receipt)) => {
+                    return receipt_events(&receipt, network.config.owshen_contract_address)
+                }
+                Ok(None) => last_err = eyre::eyre!("No receipt for transaction {:?}", tx_hash),
+                Err(e) => last_err = e.into(),
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Looks up `blocks` in one batch, see `rpc_block_timestamps`, trying each configured
+    /// provider in turn.
+    async fn block_timestamp_batch(
+        &self,
+        blocks: &[u64],
+    ) -> Result<HashMap<u64, u64>, eyre::Report> {
+        let mut last_err: eyre::Report = NetworkError::ProviderNotSet.into();
+        for network in self.provider_networks() {
+            match timeout(
+                self.rpc_query_timeout,
+                rpc_block_timestamps(network, blocks),
+            )
+            .await
+            {
+                Ok(Ok(timestamps)) => return Ok(timestamps),
+                Ok(Err(e)) => last_err = e,
+                Err(_) => {
+                    bump(&self.counters.rpc_timeouts, 1);
+                    last_err = eyre::eyre!("Block timestamp lookup timed out");
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Events of type `E` in `[from, to)` along with their block numbers, see
+    /// `query_events_capped`.
+    async fn query_events_upto<E: EthEvent + Clone + 'static>(
+        &self,
+        from: u64,
+        to: u64,
+        max_blocks: Option<u64>,
+        mut progress: Option<ScanProgress<'_>>,
+    ) -> Result<(Vec<(E, u64)>, u64), eyre::Report> {
+        if self.network.is_none() {
+            return Err(NetworkError::ProviderNotSet.into());
+        }
+        let to = if self.confirmations > 0 {
+            to.min(self.latest_confirmed_block().await? + 1)
+        } else {
+            to
+        };
+        let to = match max_blocks {
+            Some(max_blocks) => to.min(from.saturating_add(max_blocks)),
+            None => to,
+        };
+        if from >= to {
+            return Ok((vec![], from));
+        }
+
+        let key = (E::name().into_owned(), from, to);
+        if let Some(events) = self.event_cache.lock().unwrap().get(&key) {
+            return Ok((events, to));
+        }
+
+        let mut events = Vec::new();
+        let mut next = from;
+        let mut last_err = None;
+        for network in self.provider_networks() {
+            let mut report = |position: u64, _: u64| {
+                if let Some(progress) = progress.as_mut() {
+                    progress(position - from, to - from);
+                }
+            };
+            let (found, err) = match network.event_provider() {
+                EventProvider::Http(provider) => {
+                    scan_events(
+                        &network.owshen_contract(provider),
+                        next,
+                        to,
+                        &self.event_scan,
+                        self.rpc_query_timeout,
+                        &self.counters,
+                        Some(&mut report),
+                    )
+                    .await
+                }
+                EventProvider::Ws(provider) => {
+                    scan_events(
+                        &network.owshen_contract(provider),
+                        next,
+                        to,
+                        &self.event_scan,
+                        self.rpc_query_timeout,
+                        &self.counters,
+                        Some(&mut report),
+                    )
+                    .await
+                }
+            };
+            events.extend(found);
+            match err {
+                Some(e) => {
+                    log::warn!(
+                        provider = network.config.endpoint.as_str(), "error.kind" = e.kind(), error:% = e;
+                        "Provider failed"
+                    );
+                    if let NetworkError::ScanAborted { from, .. } = e {
+                        next = from;
+                    }
+                    last_err = Some(e);
+                }
+                None => {
+                    self.event_cache.lock().unwrap().insert(key, events.clone());
+                    return Ok((events, to));
+                }
+            }
+        }
+        Err(last_err.unwrap_or(NetworkError::ProviderNotSet).into())
+    }
+
+    /// Streams spend events from block `from` onwards, see `subscribe_events`.
+    #[cfg(test)]
+    pub async fn subscribe_spend_events(
+        &self,
+        from: u64,
+    ) -> Result<mpsc::Receiver<SpendFilter>, NetworkError> {
+        self.subscribe_events(from).await
+    }
+
+    /// Streams contract events from block `from` onwards. Events already on chain are sent
+    /// first, then new ones as they arrive: over a log subscription when the primary provider
+    /// has a WebSocket connection, or by polling `query_events` every `event_poll_interval`
+    /// otherwise. Subscribed events are sent as soon as they're mined, without waiting for
+    /// `confirmations`.
+    ///
+    /// The stream ends when the receiver is dropped, or when the subscription fails.
+    #[cfg(test)]
+    pub async fn subscribe_events<E: EthEvent + Clone + Send + Sync + 'static>(
+        &self,
+        from: u64,
+    ) -> Result<mpsc::Receiver<E>, NetworkError> {
+        let network = self.network.clone().ok_or(NetworkError::ProviderNotSet)?;
+        let (tx, rx) = mpsc::channel(EVENT_SUBSCRIPTION_BUFFER);
+        let manager = self.clone();
+        match network.event_provider() {
+            EventProvider::Ws(provider) => {
+                tokio::spawn(async move {
+                    if let Err(e) = manager.stream_events(&network, &provider, from, &tx).await {
+                        log::error!(error:% = e; "Event subscription failed");
+                    }
+                });
+            }
+            EventProvider::Http(_) => {
+                tokio::spawn(async move { manager.poll_events(from, &tx).await });
+            }
+        }
+        Ok(rx)
+    }
+
+    #[cfg(test)]
+    async fn stream_events<E: EthEvent + Clone + Send + Sync + 'static>(
+        &self,
+        network: &Network,
+        provider: &Provider<Ws>,
+        from: u64,
+        tx: &mpsc::Sender<E>,
+    ) -> Result<(), eyre::Report> {
+        let filter = Filter::new()
+            .address(network.config.owshen_contract_address)
+            .event(&E::abi_signature());
+        // Subscribing before the backfill means nothing mined in between is missed; logs the
+        // backfill already covered are skipped below.
+        let mut logs = provider.subscribe_logs(&filter).await?;
+        let head = provider.get_block_number().await?.as_u64();
+        for event in self.query_events::<E>(from, head + 1).await? {
+            if tx.send(event).await.is_err() {
+                return Ok(());
+            }
+        }
+        loop {
+            let log = tokio::select! {
+                _ = tx.closed() => return Ok(()),
+                log = logs.next() => match log {
+                    Some(log) => log,
+                    None => return Err(eyre::eyre!("Subscription closed by the provider")),
+                },
+            };
+            let block = log.block_number.map_or(0, |b| b.as_u64());
+            if log.removed == Some(true) || block <= head.max(from.saturating_sub(1)) {
+                continue;
+            }
+            let event = E::decode_log(&RawLog::from(log))?;
+            if tx.send(event).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    #[cfg(test)]
+    async fn poll_events<E: EthEvent + Clone + Send + Sync + 'static>(
+        &self,
+        mut next: u64,
+        tx: &mpsc::Sender<E>,
+    ) {
+        loop {
+            let polled = async {
+                let head = self.latest_confirmed_block().await?;
+                let events = self.query_events::<E>(next, head + 1).await?;
+                Ok::<_, eyre::Report>((events, head))
+            }
+            .await;
+            match polled {
+                Ok((events, head)) => {
+                    for event in events {
+                        if tx.send(event).await.is_err() {
+                            return;
+                        }
+                    }
+                    next = next.max(head + 1);
+                }
+                Err(e) => log::warn!(block = next, error:% = e; "Failed to poll for events"),
+            }
+            tokio::select! {
+                _ = tx.closed() => return,
+                _ = tokio::time::sleep(self.event_poll_interval) => {}
+            }
+        }
+    }
+}
+
+/// Decodes the spend and sent events `contract` emitted in a transaction, in the order of its
+/// receipt's logs.
+#[cfg(test)]
+fn receipt_events(
+    receipt: &TransactionReceipt,
+    contract: H160,
+) -> Result<(Vec<SpendFilter>, Vec<SentFilter>), eyre::Report> {
+    let mut spend_events = Vec::new();
+    let mut sent_events = Vec::new();
+    for log in receipt.logs.iter().filter(|log| log.address == contract) {
+        match log.topics.first() {
+            Some(topic) if *topic == SpendFilter::signature() => spend_events.push(
+                <SpendFilter as EthEvent>::decode_log(&RawLog::from(log.clone()))?,
+            ),
+            Some(topic) if *topic == SentFilter::signature() => sent_events.push(
+                <SentFilter as EthEvent>::decode_log(&RawLog::from(log.clone()))?,
+            ),
+            _ => {}
+        }
+    }
+    Ok((spend_events, sent_events))
+}
+
+/// Scans `[from, to)` for contract events of type `E`, see `scan_block_ranges`.
+///
+/// A query that doesn't finish within `query_timeout` counts as a failure: the block range is
+/// halved for the next attempt and the timeout counts towards `max_consecutive_failures`. A
+/// timeout that's too short for the provider therefore fragments the scan into many small
+/// queries, or aborts it altogether. Any other RPC error is taken as transient and the same
+/// range is queried again, up to `transient_retries` times, before it's halved too.
+async fn scan_events<M: Middleware, E: EthEvent>(
+    contract: &ContractInstance<Arc<M>, M>,
+    from: u64,
+    to: u64,
+    config: &EventScanConfig,
+    query_timeout: Duration,
+    counters: &SyncCounters,
+    progress: Option<ScanProgress<'_>>,
+) -> (Vec<(E, u64)>, Option<NetworkError>) {
+    scan_block_ranges(from, to, config, progress, |from, to| async move {
+        let result = timeout(query_timeout, async {
+            let query = contract
+                .event::<E>()
+                .from_block(from)
+                .to_block(to)
+                .address(ValueOrArray::Value(contract.address()));
+            let logs = contract
+                .client()
+                .get_logs(&query.filter)
+                .await
+                .map_err(|e| eyre::eyre!("{}", e))?;
+            logs.into_iter()
+                .map(|log| {
+                    // Only pending logs lack a block, and the scanned range is all mined.
+                    let block = log
+                        .block_number
+                        .ok_or_else(|| eyre::eyre!("Log without a block number"))?
+                        .as_u64();
+                    Ok((E::decode_log(&RawLog::from(log))?, block))
+                })
+                .collect::<Result<Vec<_>, eyre::Report>>()
+        })
+        .await;
+        match result {
+            Ok(Ok(events)) => Ok(events),
+            Ok(Err(e)) => {
+                log::warn!("block.from" = from, "block.to" = to, error:% = e; "Event query failed");
+                Err(ChunkFailure::Transient)
+            }
+            Err(_) => {
+                bump(&counters.rpc_timeouts, 1);
+                Err(ChunkFailure::Timeout)
+            }
+        }
+    })
+    .await
+}
+
+/// Progress callback for event scans, see `NodeManager::query_events_with_progress`.
+pub type ScanProgress<'a> = &'a mut (dyn FnMut(u64, u64) + Send);
+
+/// Why querying a chunk of blocks failed, which decides how it's tried again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkFailure {
+    /// The query took too long, most likely because the range holds too many events, so the
+    /// chunk is shrunk.
+    Timeout,
+    /// The endpoint failed otherwise, possibly just a blip, so the same chunk is retried.
+    Transient,
+}
+
+/// Splits `[from, to)` into adaptively sized chunks for callers that query each chunk
+/// themselves. The chunk starts at `base_step`, is halved (down to `min_step`) after every
+/// failed query, and doubles (up to `max_step`) after consecutive successful ones. A chunk
+/// that failed with a transient error is first retried as is, up to `transient_retries`
+/// times.
+///
+/// Take the next chunk with `next_range` and report how querying it went with
+/// `record_success`, `record_failure` or `record_transient_failure`.
+#[derive(Clone, Debug)]
+pub struct BlockRangeScanner {
+    from: u64,
+    to: u64,
+    min_step: u64,
+    max_step: u64,
+    step: u64,
+    successes: u32,
+    failures: u32,
+    max_consecutive_failures: u32,
+    retries: u32,
+    transient_retries: u32,
+}
+
+impl BlockRangeScanner {
+    pub fn new(from: u64, to: u64, config: &EventScanConfig) -> BlockRangeScanner {
+        let min_step = config.min_step.max(1);
+        let max_step = config.max_step.max(min_step);
+        BlockRangeScanner {
+            from,
+            to,
+            min_step,
+            max_step,
+            step: config.base_step.clamp(min_step, max_step),
+            successes: 0,
+            failures: 0,
+            max_consecutive_failures: config.max_consecutive_failures,
+            retries: 0,
+            transient_retries: config.transient_retries,
+        }
+    }
+
+    /// The next chunk to query, with inclusive bounds, or `None` once the range is covered.
+    /// The same chunk is returned until its outcome is recorded.
+    pub fn next_range(&self) -> Option<(u64, u64)> {
+        if self.from >= self.to {
+            return None;
+        }
+        let end = self.from.saturating_add(self.step).min(self.to);
+        Some((self.from, end - 1))
+    }
+
+    /// The first block that hasn't been covered yet.
+    pub fn position(&self) -> u64 {
+        self.from
+    }
+
+    /// Moves past the current chunk, growing the chunk size after a streak of successes.
+    pub fn record_success(&mut self) {
+        if let Some((_, last)) = self.next_range() {
+            self.from = last + 1;
+        }
+        self.failures = 0;
+        self.retries = 0;
+        self.successes += 1;
+        if self.successes >= SCAN_GROWTH_STREAK {
+            self.step = self.step.saturating_mul(2).min(self.max_step);
+            self.successes = 0;
+        }
+    }
+
+    /// Shrinks the chunk size for the next attempt. After `max_consecutive_failures` failures
+    /// in a row the scan is over and `NetworkError::ScanAborted` is returned.
+    pub fn record_failure(&mut self) -> Result<(), NetworkError> {
+        self.count_failure()?;
+        self.retries = 0;
+        self.step = (self.step / 2).max(self.min_step);
+        Ok(())
+    }
+
+    /// Keeps the chunk size so the same chunk is queried again, unless it was already retried
+    /// `transient_retries` times, in which case this is `record_failure`. Counts towards
+    /// `max_consecutive_failures` either way.
+    pub fn record_transient_failure(&mut self) -> Result<(), NetworkError> {
+        if self.retries >= self.transient_retries {
+            return self.record_failure();
+        }
+        self.count_failure()?;
+        self.retries += 1;
+        Ok(())
+    }
+
+    fn count_failure(&mut self) -> Result<(), NetworkError> {
+        self.successes = 0;
+        self.failures += 1;
+        if self.failures >= self.max_consecutive_failures {
+            return Err(NetworkError::ScanAborted {
+                from: self.from,
+                failures: self.failures,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Walks `[from, to)` with a `BlockRangeScanner`, calling `query` with the inclusive bounds
+/// of each chunk. A query that times out halves the range for the next attempt; any other
+/// error retries the same range up to `transient_retries` times before halving it. After
+/// every chunk that succeeds, `progress` is called with the first block not yet scanned and
+/// `to`.
+///
+/// After `max_consecutive_failures` failed queries in a row the scan gives up, returning the
+/// items gathered so far together with the error.
+async fn scan_block_ranges<T, F, Fut>(
+    from: u64,
+    to: u64,
+    config: &EventScanConfig,
+    mut progress: Option<ScanProgress<'_>>,
+    mut query: F,
+) -> (Vec<T>, Option<NetworkError>)
+where
+    F: FnMut(u64, u64) -> Fut,
+    Fut: Future<Output = Result<Vec<T>, ChunkFailure>>,
+{
+    let mut scanner = BlockRangeScanner::new(from, to, config);
+    let mut items = Vec::new();
+
+    while let Some((start, end)) = scanner.next_range() {
+        log::info!("block.from" = start, "block.to" = end; "Querying blocks");
+        match query(start, end).await {
+            Ok(new_items) => {
+                items.extend(new_items);
+                scanner.record_success();
+                if let Some(progress) = progress.as_mut() {
+                    progress(scanner.position(), to);
+                }
+            }
+            Err(failure) => {
+                let recorded = match failure {
+                    ChunkFailure::Timeout => scanner.record_failure(),
+                    ChunkFailure::Transient => scanner.record_transient_failure(),
+                };
+                if let Err(e) = recorded {
+                    return (items, Some(e));
+                }
+            }
+        }
+    }
+    (items, None)
+}
+
+/// SHA-256 fingerprint of a DER-encoded TLS certificate. Parsed from hex, with or without the
+/// colons `openssl x509 -noout -fingerprint -sha256` prints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CertFingerprint(pub [u8; 32]);
+
+impl CertFingerprint {
+    pub fn of(der: &[u8]) -> CertFingerprint {
+        CertFingerprint(Sha256::digest(der).into())
+    }
+}
+
+impl std::str::FromStr for CertFingerprint {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s.replace(':', ""))?;
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| eyre::eyre!("A certificate fingerprint is 32 bytes long"))?;
+        Ok(CertFingerprint(bytes))
+    }
+}
+
+/// Accepts exactly the server certificates in `pins`, in place of the CA and hostname checks.
+/// Handshake signatures are still checked against the presented certificate.
+struct PinnedCertVerifier {
+    pins: Vec<CertFingerprint>,
+}
+
+impl rustls::client::ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        if self.pins.contains(&CertFingerprint::of(&end_entity.0)) {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
+/// Event pages are large and compress well, so responses are requested gzip or deflate
+/// encoded. Bodies come back decompressed.
+fn build_http_client(
+    timeout: Duration,
+    node_id: Option<&str>,
+    pool: &ConnectionPoolConfig,
+    pinned_certificates: &[CertFingerprint],
+) -> Result<reqwest::Client, eyre::Report> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(node_id) = node_id {
+        headers.insert(NODE_ID_HEADER, node_id.parse()?);
+    }
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .pool_max_idle_per_host(pool.max_idle_per_host)
+        .pool_idle_timeout(pool.idle_timeout)
+        .user_agent(USER_AGENT)
+        .default_headers(headers)
+        .gzip(true)
+        .deflate(true);
+    if !pinned_certificates.is_empty() {
+        let tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                pins: pinned_certificates.to_vec(),
+            }))
+            .with_no_client_auth();
+        builder = builder.use_preconfigured_tls(tls);
+    }
+    Ok(builder.build()?)
+}
+
+/// HTTP client sending `headers` with every RPC request, see `Config::rpc_headers`. Header
+/// values are marked sensitive so they stay out of debug output.
+fn rpc_client(headers: &HashMap<String, String>) -> Result<reqwest::Client, eyre::Report> {
+    let mut header_map = reqwest::header::HeaderMap::new();
+    for (name, value) in headers {
+        let mut value = reqwest::header::HeaderValue::from_str(value)
+            .map_err(|_| eyre::eyre!("Invalid value for RPC header {}", name))?;
+        value.set_sensitive(true);
+        header_map.insert(
+            reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| eyre::eyre!("Invalid RPC header name {}", name))?,
+            value,
+        );
+    }
+    Ok(reqwest::Client::builder()
+        .default_headers(header_map)
+        .build()?)
+}
+
+/// Asks `network`'s HTTP endpoint for the headers of `blocks` in a single JSON-RPC batch and
+/// returns their timestamps, keyed by block number.
+async fn rpc_block_timestamps(
+    network: &Network,
+    blocks: &[u64],
+) -> Result<HashMap<u64, u64>, eyre::Report> {
+    let batch: Vec<serde_json::Value> = blocks
+        .iter()
+        .enumerate()
+        .map(|(id, block)| {
+            serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": "eth_getBlockByNumber",
+                "params": [U64::from(*block), false],
+            })
+        })
+        .collect();
+    let resp = network
+        .rpc_client
+        .post(network.provider.url().clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&batch)?)
+        .send()
+        .await?
+        .error_for_status()?;
+    let responses: Vec<serde_json::Value> = serde_json::from_slice(&resp.bytes().await?)?;
+
+    let mut timestamps = HashMap::new();
+    for resp in responses {
+        let block = resp["id"]
+            .as_u64()
+            .and_then(|id| blocks.get(id as usize))
+            .ok_or_else(|| eyre::eyre!("Unexpected id in batch response: {}", resp["id"]))?;
+        if !resp["error"].is_null() {
+            return Err(eyre::eyre!(
+                "Block {} lookup failed: {}",
+                block,
+                resp["error"]
+            ));
+        }
+        let header: Option<Block<TxHash>> = serde_json::from_value(resp["result"].clone())?;
+        let header = header.ok_or_else(|| eyre::eyre!("Block {} not found", block))?;
+        timestamps.insert(*block, header.timestamp.as_u64());
+    }
+    if timestamps.len() != blocks.len() {
+        return Err(eyre::eyre!("Batch response is missing blocks"));
+    }
+    Ok(timestamps)
+}
+
+impl NetworkManager {
+    pub fn new() -> Result<NetworkManager, eyre::Report> {
+        let mut networks: HashMap<String, Vec<TokenInfo>> = HashMap::new();
+
+        networks.insert(
+            "Goerli".to_string(),
+            vec![TokenInfo {
+                token_address: parse_address("0xdD69DB25F6D620A7baD3023c5d32761D353D3De9")?,
+                symbol: "WETH".to_string(),
+                decimals: 18,
+                chain_id: Some(5),
+            }],
+        );
+        let mut native_tokens = HashMap::new();
+        native_tokens.insert(
+            "Goerli".to_string(),
+            NativeToken {
+                symbol: "ETH".to_string(),
+                decimals: 18,
+            },
+        );
+
+        Ok(NetworkManager {
+            networks,
+            native_tokens,
+            providers: HashMap::new(),
+            discovery_failures: HashMap::new(),
+        })
+    }
+
+    // pub fn set(&mut self, data: HashMap<String, Vec<TokenInfo>>, expand: bool) {
+    //     if expand {
+    //         self.networks.extend(data);
+    //     } else {
+    //         self.networks = data;
+    //     }
+    // }
+
+    /// Checks a token list without changing anything: symbols must be unique and no token
+    /// may have the zero address.
+    pub fn validate_tokens(token_info: &[TokenInfo]) -> Result<(), eyre::Report> {
+        let mut symbols = HashSet::new();
+        for token in token_info {
+            if token.token_address.is_zero() {
+                return Err(eyre::eyre!("Token {} has the zero address", token.symbol));
+            }
+            if !symbols.insert(token.symbol.as_str()) {
+                return Err(eyre::eyre!("Duplicate token symbol {}", token.symbol));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the token list of `network`, replacing any previous one.
+    pub fn add_network(
+        &mut self,
+        network: String,
+        token_info: Vec<TokenInfo>,
+    ) -> Result<(), eyre::Report> {
+        Self::validate_tokens(&token_info)?;
+        self.networks.insert(network, token_info);
+        Ok(())
+    }
+
+    /// Adds tokens to `network`, creating it if needed. Tokens whose symbol the network
+    /// already has are skipped, so existing entries are never changed.
+    #[cfg(test)]
+    pub fn merge_network(
+        &mut self,
+        network: String,
+        token_info: Vec<TokenInfo>,
+    ) -> Result<(), eyre::Report> {
+        Self::validate_tokens(&token_info)?;
+        let tokens = self.networks.entry(network).or_default();
+        for token in token_info {
+            if !tokens.iter().any(|t| t.symbol == token.symbol) {
+                tokens.push(token);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, network: &str) -> Option<&Vec<TokenInfo>> {
+        self.networks.get(network)
+    }
+
+    /// The token `network` pays fees in, if known.
+    #[cfg(test)]
+    pub fn native_token(&self, network: &str) -> Option<&NativeToken> {
+        self.native_tokens.get(network)
+    }
+
+    /// Sets the native token of `network`, replacing any previous one.
+    #[cfg(test)]
+    pub fn set_native_token(&mut self, network: String, token: NativeToken) {
+        self.native_tokens.insert(network, token);
+    }
+
+    /// Sets the provider `discover_token` queries for tokens of `network`.
+    pub fn set_provider(&mut self, network: String, provider: Arc<Provider<Http>>) {
+        self.providers.insert(network, provider);
+    }
+
+    /// Registers the ERC-20 token at `addr` on `network`, reading its symbol and decimals from
+    /// the contract through the provider set with `set_provider`, and returns it. Tokens that
+    /// are already known are returned without a query. The symbol may not be taken by another
+    /// token of the network; the chain id is taken from the tokens already there.
+    ///
+    /// After a failure, e.g. because `addr` isn't a token contract, the address isn't queried
+    /// again for `TOKEN_DISCOVERY_RETRY` and attempts fail right away.
+    pub async fn discover_token(
+        &mut self,
+        network: &str,
+        addr: H160,
+    ) -> Result<TokenInfo, eyre::Report> {
+        if let Some(token) = self.get_token_by_address(network, addr) {
+            return Ok(token.clone());
+        }
+        let key = (network.to_string(), addr);
+        self.discovery_failures
+            .retain(|_, failed| failed.elapsed() < TOKEN_DISCOVERY_RETRY);
+        if self.discovery_failures.contains_key(&key) {
+            return Err(eyre::eyre!(
+                "Discovering token {:?} on {} failed recently",
+                addr,
+                network
+            ));
+        }
+        let provider = self
+            .providers
+            .get(network)
+            .cloned()
+            .ok_or(eyre::eyre!("No provider set for {}", network))?;
+
+        let result = async {
+            let abi = ethers::abi::parse_abi(&[
+                "function symbol() external view returns (string)",
+                "function decimals() external view returns (uint8)",
+            ])?;
+            let contract = Contract::new(addr, abi, provider);
+            let symbol: String = contract.method("symbol", ())?.call().await?;
+            let decimals: u8 = contract.method("decimals", ())?.call().await?;
+            if symbol.is_empty() {
+                return Err(eyre::eyre!("Token {:?} has an empty symbol", addr));
+            }
+            if let Some(taken) = self.get_token(network, &symbol) {
+                return Err(eyre::eyre!(
+                    "Token symbol {} is already taken by {:?}",
+                    symbol,
+                    taken.token_address
+                ));
+            }
+            let chain_id = self
+                .get(network)
+                .and_then(|tokens| tokens.iter().find_map(|t| t.chain_id));
+            Ok(TokenInfo {
+                token_address: addr,
+                symbol,
+                decimals,
+                chain_id,
+            })
+        }
+        .await;
+
+        match result {
+            Ok(token) => {
+                log::info!(
+                    network = network, symbol = token.symbol.as_str(), decimals = token.decimals;
+                    "Discovered token"
+                );
+                self.discovery_failures.remove(&key);
+                self.networks
+                    .entry(network.to_string())
+                    .or_default()
+                    .push(token.clone());
+                Ok(token)
+            }
+            Err(e) => {
+                log::warn!("token.addr":? = addr, network = network, error:% = e; "Failed to discover token");
+                self.discovery_failures.insert(key, Instant::now());
+                Err(e)
+            }
+        }
+    }
+
+    // Networks only carry a handful of tokens, so a linear scan is cheaper than keeping a
+    // reverse index in sync with the public `networks` map and its deserialized form.
+    fn get_token_by_address(&self, network: &str, addr: H160) -> Option<&TokenInfo> {
+        self.get(network)?
+            .iter()
+            .find(|token_info| token_info.token_address == addr)
+    }
+
+    /// Names of all known networks, sorted.
+    #[cfg(test)]
+    pub fn network_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.networks.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Number of tokens configured for `network`, zero if it's unknown.
+    #[cfg(test)]
+    pub fn token_count(&self, network: &str) -> usize {
+        self.get(network).map_or(0, Vec::len)
+    }
+
+    /// Network names and token symbols are matched case-sensitively.
+    #[cfg(test)]
+    pub fn has(&self, network: &str, symbol: &str) -> bool {
+        self.get_token(network, symbol).is_some()
+    }
+
+    pub fn get_token(&self, network: &str, symbol: &str) -> Option<&TokenInfo> {
+        self.get(network)?
+            .iter()
+            .find(|token_info| token_info.symbol == symbol)
+    }
+
+    #[cfg(test)]
+    pub fn resolve_address(&self, network: &str, symbol: &str) -> Option<H160> {
+        self.get_token(network, symbol)
+            .map(|token_info| token_info.token_address)
+    }
+
+    #[cfg(test)]
+    pub fn resolve_symbol(&self, network: &str, addr: H160) -> Option<&str> {
+        self.get_token_by_address(network, addr)
+            .map(|token_info| token_info.symbol.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apis::{GetEventsRequest, GetHandShakeRequest};
+    use crate::config::{Config, PeerScheme};
+    use crate::mock_peer::{Endpoint, Fault, MockPeer};
+    use axum::{
+        extract::Query,
+        http::StatusCode,
+        routing::{get, post},
+        Json, Router,
+    };
+    use bindings::owshen::Point;
+    use ethers::abi::{Abi, Token, Tokenizable};
+    use std::str::FromStr;
+
+    async fn spawn_mock_peer(app: Router) -> SocketAddr {
+        spawn_mock_peer_on("127.0.0.1:0", app).await
+    }
+
+    async fn spawn_mock_peer_on(bind: &str, app: Router) -> SocketAddr {
+        let listener = std::net::TcpListener::bind(bind).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Answers a request to a URL, given the bearer token it was sent with.
+    type Responder =
+        Box<dyn Fn(&str, Option<&str>) -> Result<PeerResponse, NetworkError> + Send + Sync>;
+
+    /// How long a request to a URL takes to be answered.
+    type Delay = Box<dyn Fn(&str) -> Duration + Send + Sync>;
+
+    /// Answers peer requests from a closure instead of the network and records every URL.
+    struct MockTransport {
+        respond: Responder,
+        delay: Delay,
+        requests: std::sync::Mutex<Vec<String>>,
+        timeouts: std::sync::Mutex<Vec<(String, Duration)>>,
+    }
+
+    impl std::fmt::Debug for MockTransport {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("MockTransport").finish_non_exhaustive()
+        }
+    }
+
+    impl MockTransport {
+        fn new(
+            respond: impl Fn(&str, Option<&str>) -> Result<PeerResponse, NetworkError>
+                + Send
+                + Sync
+                + 'static,
+        ) -> Arc<MockTransport> {
+            MockTransport::with_delay(|_| Duration::ZERO, respond)
+        }
+
+        /// Like `new`, but answers only after `delay` of the URL has passed. A delay longer
+        /// than the request's timeout fails it with `NetworkError::Timeout` once that's up.
+        fn with_delay(
+            delay: impl Fn(&str) -> Duration + Send + Sync + 'static,
+            respond: impl Fn(&str, Option<&str>) -> Result<PeerResponse, NetworkError>
+                + Send
+                + Sync
+                + 'static,
+        ) -> Arc<MockTransport> {
+            Arc::new(MockTransport {
+                respond: Box::new(respond),
+                delay: Box::new(delay),
+                requests: Default::default(),
+                timeouts: Default::default(),
+            })
+        }
+
+        fn requests_to(&self, host: &str) -> usize {
+            let requests = self.requests.lock().unwrap();
+            requests.iter().filter(|url| url.contains(host)).count()
+        }
+
+        fn timeouts_to(&self, host: &str) -> Vec<Duration> {
+            let timeouts = self.timeouts.lock().unwrap();
+            timeouts
+                .iter()
+                .filter(|(url, _)| url.contains(host))
+                .map(|(_, timeout)| *timeout)
+                .collect()
+        }
+    }
+
+    impl PeerTransport for MockTransport {
+        fn get<'a>(
+            &'a self,
+            url: &'a str,
+            bearer: Option<&'a str>,
+            timeout: Duration,
+            _max_body: usize,
+        ) -> BoxFuture<'a, Result<PeerResponse, NetworkError>> {
+            self.requests.lock().unwrap().push(url.to_string());
+            self.timeouts
+                .lock()
+                .unwrap()
+                .push((url.to_string(), timeout));
+            let resp = (self.respond)(url, bearer);
+            let delay = (self.delay)(url);
+            Box::pin(async move {
+                if delay > timeout {
+                    tokio::time::sleep(timeout).await;
+                    return Err(NetworkError::Timeout(url.to_string()));
+                }
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                resp
+            })
+        }
+    }
+
+    fn json_response<T: serde::Serialize>(value: &T) -> Result<PeerResponse, NetworkError> {
+        Ok(PeerResponse {
+            status: StatusCode::OK,
+            body: serde_json::to_string(value).unwrap(),
+        })
+    }
+
+    fn spend_event(nullifier: u64) -> SpendFilter {
+        SpendFilter {
+            nullifier: U256::from(nullifier),
+        }
+    }
+
+    fn sent_event(index: u64) -> SentFilter {
+        SentFilter {
+            ephemeral: Point {
+                x: U256::zero(),
+                y: U256::zero(),
+            },
+            index: U256::from(index),
+            timestamp: U256::zero(),
+            hint_amount: U256::zero(),
+            hint_token_address: U256::zero(),
+            commitment: U256::zero(),
+            memo: String::new(),
+        }
+    }
+
+    fn peer(addr: &str, current_block: u64, health: f64, latency_ms: u64) -> Peer {
+        let mut peer = Peer::from_str(addr).unwrap();
+        peer.current_block = current_block;
+        peer.health = health;
+        peer.latency = Some(Duration::from_millis(latency_ms));
+        peer
+    }
+
+    #[test]
+    fn test_elect_peer_prefers_healthy_near_tip() {
+        let candidates = vec![
+            peer("127.0.0.1:8001", 100, 0.2, 10),
+            peer("127.0.0.1:8002", 99, 0.9, 50),
+            peer("127.0.0.1:8003", 50, 1.0, 5),
+        ];
+        let elected = elect_peer(&candidates, 2, &mut rand::thread_rng()).unwrap();
+        assert_eq!(elected.addr, candidates[1].addr);
+
+        let elected = elect_peer(&candidates, 0, &mut rand::thread_rng()).unwrap();
+        assert_eq!(elected.addr, candidates[0].addr);
+    }
+
+    #[tokio::test]
+    async fn test_custom_election_strategy() {
+        #[derive(Debug)]
+        struct LowestLatency;
+
+        impl ElectionStrategy for LowestLatency {
+            fn elect(&self, candidates: &[Peer], _: u64, _: &mut dyn RngCore) -> Option<Peer> {
+                candidates.iter().min_by_key(|p| p.latency).cloned()
+            }
+        }
+
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(Instant::now())));
+        let transport = MockTransport::new({
+            let clock = clock.clone();
+            move |url, _| {
+                if url.contains("/get-peers") {
+                    return json_response(&GetPeersResponse { peers: vec![] });
+                }
+                // 10.0.0.1 is further ahead, but slower to answer.
+                let fast = url.contains("10.0.0.2");
+                if !fast {
+                    clock.advance(Duration::from_millis(30));
+                }
+                json_response(&GetHandShakeResponse {
+                    current_block_number: if fast { 5 } else { 50 },
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let peers = vec![
+            Peer::from_str("10.0.0.1:8888").unwrap(),
+            Peer::from_str("10.0.0.2:8888").unwrap(),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport);
+        node_manager.set_clock(clock);
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(
+            node_manager.elected_peer().unwrap().addr.to_string(),
+            "10.0.0.1:8888"
+        );
+
+        node_manager.set_election_strategy(Arc::new(LowestLatency));
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(
+            node_manager.elected_peer().unwrap().addr.to_string(),
+            "10.0.0.2:8888"
+        );
+    }
+
+    #[test]
+    fn test_events_consistent() {
+        let spends: Vec<SpendFilter> = (1..=5).map(spend_event).collect();
+        let sents: Vec<SentFilter> = (0..5).map(sent_event).collect();
+        assert!(events_consistent(
+            &spends,
+            &sents,
+            &spends[3..],
+            &sents[2..4]
+        ));
+        assert!(events_consistent(&spends, &sents, &[spend_event(0)], &[]));
+        // Omitted events are caught...
+        assert!(!events_consistent(&spends[..4], &sents, &spends[3..], &[]));
+        assert!(!events_consistent(&spends, &sents[..3], &[], &sents[2..4]));
+        // ...and so are altered ones.
+        let mut forged = sents.clone();
+        forged[3].commitment = U256::from(1234);
+        assert!(!events_consistent(&spends, &forged, &[], &sents[2..4]));
+    }
+
+    #[test]
+    fn test_demote_elected_peer_reelects() {
+        let peers = vec![
+            peer("127.0.0.1:1001", 100, 1.0, 10),
+            peer("127.0.0.1:1002", 99, 0.8, 10),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.elected_peer = node_manager.elect(&node_manager.peers);
+        assert_eq!(node_manager.elected_peer().unwrap().addr.port(), 1001);
+
+        node_manager.demote_elected_peer();
+        assert_eq!(node_manager.elected_peer().unwrap().addr.port(), 1002);
+        assert_eq!(node_manager.get_peers()[0].health, 0.0);
+
+        node_manager.demote_elected_peer();
+        assert!(node_manager.elected_peer().is_none());
+    }
+
+    #[test]
+    fn test_elect_peer_breaks_ties_by_latency() {
+        let candidates = vec![
+            peer("127.0.0.1:8001", 100, 1.0, 80),
+            peer("127.0.0.1:8002", 100, 1.0, 20),
+            peer("127.0.0.1:8003", 100, 1.0, 40),
+        ];
+        let elected = elect_peer(&candidates, 0, &mut rand::thread_rng()).unwrap();
+        assert_eq!(elected.addr, candidates[1].addr);
+        assert!(elect_peer(&[], 0, &mut rand::thread_rng()).is_none());
+    }
+
+    #[test]
+    fn test_elect_peer_spreads_ties() {
+        let candidates = vec![
+            peer("127.0.0.1:8001", 100, 1.0, 21),
+            peer("127.0.0.1:8002", 100, 1.0, 24),
+            peer("127.0.0.1:8003", 100, 1.0, 27),
+            peer("127.0.0.1:8004", 99, 1.0, 20),
+        ];
+        let elections = |seed: u64| {
+            let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+            node_manager.set_election_seed(seed);
+            (0..30)
+                .map(|_| node_manager.elect(&candidates).unwrap().addr.port())
+                .collect::<Vec<_>>()
+        };
+        let picks = elections(7);
+        for port in [8001, 8002, 8003] {
+            assert!(picks.contains(&port));
+        }
+        assert!(!picks.contains(&8004));
+        assert_eq!(picks, elections(7));
+    }
+
+    #[test]
+    fn test_peer_scheme() {
+        let plain = Peer::from_str("10.0.0.1:8888").unwrap();
+        assert_eq!(plain.base_url(), "http://10.0.0.1:8888");
+        let tls = Peer::from_str("https://10.0.0.1:443/").unwrap();
+        assert_eq!(tls.base_url(), "https://10.0.0.1:443");
+        assert!(Peer::from_str("ftp://10.0.0.1:21").is_err());
+
+        // Peers gossiped by nodes that predate the scheme field default to http.
+        let legacy: Peer =
+            serde_json::from_str(r#"{"addr":"10.0.0.1:8888","current_block":5}"#).unwrap();
+        assert_eq!(legacy.scheme, PeerScheme::Http);
+        let json = serde_json::to_string(&tls).unwrap();
+        let decoded: Peer = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.scheme, PeerScheme::Https);
+    }
+
+    #[test]
+    fn test_peer_urls_bracket_ipv6_hosts() {
+        let url = |addr: &str| Peer::from_str(addr).unwrap().base_url();
+        assert_eq!(url("[::1]:8080"), "http://[::1]:8080");
+        assert_eq!(
+            url("https://[2001:db8:85a3::8a2e:370:7334]:443"),
+            "https://[2001:db8:85a3::8a2e:370:7334]:443"
+        );
+        assert_eq!(url("[fe80::1%2]:8888"), "http://[fe80::1]:8888");
+    }
+
+    #[tokio::test]
+    async fn test_sync_with_ipv6_peer() {
+        let app = Router::new()
+            .route(
+                "/handshake",
+                get(|| async {
+                    Json(GetHandShakeResponse {
+                        current_block_number: 3,
+                        max_events_page: None,
+                        version: None,
+                        events_commitment: None,
+                    })
+                }),
+            )
+            .route(
+                "/get-peers",
+                get(|| async { Json(GetPeersResponse { peers: vec![] }) }),
+            )
+            .route(
+                "/events",
+                get(|Query(req): Query<GetEventsRequest>| async move {
+                    Json(GetEventsResponse {
+                        spend_events: (1..=2).map(spend_event).skip(req.from_spend).collect(),
+                        sent_events: vec![],
+                        current_block_number: None,
+                    })
+                }),
+            );
+        let addr = spawn_mock_peer_on("[::1]:0", app).await;
+
+        let mut node_manager =
+            NodeManager::new(None, None, vec![Peer::new(addr)], true, true).unwrap();
+        let report = node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(report.reached, 1);
+        assert_eq!(node_manager.elected_peer().unwrap().addr, addr);
+        let (spend_events, _, _) = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+        assert_eq!(spend_events.len(), 2);
+    }
+
+    #[test]
+    fn test_add_peer_rejects_invalid_addresses() {
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        for addr in [
+            "0.0.0.0:8888",
+            "127.0.0.1:0",
+            "255.255.255.255:8888",
+            "224.0.0.1:8888",
+            "[::]:8888",
+        ] {
+            node_manager.add_peer(addr.parse().unwrap());
+        }
+        assert_eq!(node_manager.peer_count(), 0);
+
+        node_manager.add_peer("127.0.0.1:8888".parse().unwrap());
+        node_manager.add_peer("[::1]:8888".parse().unwrap());
+        assert_eq!(node_manager.peer_count(), 2);
+    }
+
+    #[test]
+    fn test_add_peer_evicts_only_worse_peers_at_capacity() {
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.set_max_peers(2);
+        node_manager.add_peer(peer("127.0.0.1:1001", 0, 1.0, 10));
+        node_manager.add_peer(peer("127.0.0.1:1002", 0, 0.4, 10));
+
+        // Nobody is worse than a peer of equal health, so it isn't let in.
+        node_manager.add_peer(peer("127.0.0.1:1003", 0, 0.4, 10));
+        assert_eq!(node_manager.peer_count(), 2);
+        assert!(!node_manager
+            .get_peers()
+            .iter()
+            .any(|p| p.addr.port() == 1003));
+
+        node_manager.add_peer(peer("127.0.0.1:1004", 0, 0.9, 10));
+        let ports: Vec<u16> = node_manager
+            .get_peers()
+            .iter()
+            .map(|p| p.addr.port())
+            .collect();
+        assert_eq!(ports, vec![1001, 1004]);
+    }
+
+    #[test]
+    fn test_add_peer_deduplicates_equivalent_addresses() {
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.add_peer(peer("127.0.0.1:8888", 10, 1.0, 10));
+        node_manager.add_peer(peer("[::ffff:127.0.0.1]:8888", 20, 1.0, 10));
+        assert_eq!(node_manager.peer_count(), 1);
+
+        node_manager.add_peer(peer("127.0.0.1:9999", 0, 1.0, 10));
+        node_manager.update_peer(peer("[::ffff:127.0.0.1]:8888", 30, 0.5, 10));
+        let peers = node_manager.get_peers();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].addr, "127.0.0.1:8888".parse().unwrap());
+        assert_eq!(peers[0].current_block, 30);
+    }
+
+    /// A clock that only moves when told to.
+    #[derive(Debug)]
+    struct MockClock(std::sync::Mutex<Instant>);
+
+    impl MockClock {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peer_health_decays_back() {
+        let transport = MockTransport::new(|_, _| {
+            Ok(PeerResponse {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                body: String::new(),
+            })
+        });
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(Instant::now())));
+        let peers = vec![peer("10.0.0.1:8888", 0, 0.0, 10)];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport);
+        node_manager.set_clock(clock.clone());
+        node_manager.set_health_recovery_window(Duration::from_secs(600));
+        node_manager.max_handshake_retries = 0;
+        let health = |node_manager: &NodeManager| node_manager.get_peers()[0].health;
+
+        // The first round only starts the clock; the failed handshake keeps health at zero.
+        assert!(node_manager.sync_with_peers().await.is_err());
+        assert_eq!(health(&node_manager), 0.0);
+        assert_eq!(node_manager.peer_failures.len(), 1);
+
+        // Half the window earns half the health back, before this round's failure counts.
+        clock.advance(Duration::from_secs(300));
+        assert!(node_manager.sync_with_peers().await.is_err());
+        assert!((health(&node_manager) - 0.5 * (1.0 - HEALTH_SMOOTHING)).abs() < 1e-9);
+
+        // A peer left alone for a whole window recovers fully and its failures are forgotten.
+        node_manager.peers[0].health = 0.0;
+        clock.advance(Duration::from_secs(600));
+        node_manager.decay_peer_penalties();
+        assert_eq!(health(&node_manager), 1.0);
+        assert!(node_manager.peer_failures.is_empty());
+
+        // No time passed, nothing recovered.
+        node_manager.peers[0].health = 0.2;
+        node_manager.decay_peer_penalties();
+        assert_eq!(health(&node_manager), 0.2);
+    }
+
+    #[tokio::test]
+    async fn test_mock_peer_serves_and_faults() {
+        let mock = MockPeer::new()
+            .current_block(12)
+            .max_events_page(Some(2))
+            .events((1..=5).map(spend_event).collect(), vec![])
+            .spawn();
+        let mut node_manager = NodeManager::new(None, None, vec![mock.peer()], true, true).unwrap();
+        node_manager.set_request_timeouts(
+            Duration::from_millis(200),
+            Duration::from_millis(200),
+            Duration::from_millis(200),
+        );
+        node_manager.sync_with_peers().await.unwrap();
+        let elected = node_manager.elected_peer().unwrap().clone();
+        assert_eq!(elected.addr, mock.addr());
+        assert_eq!(elected.current_block, 12);
+        assert_eq!(mock.requests(Endpoint::Handshake), 1);
+        assert_eq!(mock.requests(Endpoint::GetPeers), 1);
+
+        let cursor = SyncCursor::default();
+        let (spends, sents, next) = node_manager
+            .get_events_with_cursor(&cursor, &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(spends, (1..=5).map(spend_event).collect::<Vec<_>>());
+        assert!(sents.is_empty());
+        assert_eq!(next.from_spend, 5);
+        assert_eq!(next.last_block, 12);
+
+        for fault in [
+            Fault::Hang,
+            Fault::Status(StatusCode::SERVICE_UNAVAILABLE),
+            Fault::MalformedJson,
+        ] {
+            mock.set_fault(Endpoint::Events, Some(fault));
+            let err = node_manager
+                .get_events_with_cursor(&cursor, &Default::default())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, NetworkError::PeerTripped(_)), "{:?}", fault);
+        }
+        mock.set_fault(Endpoint::Events, None);
+        mock.push_events(vec![spend_event(6)], vec![]);
+        let (spends, _, _) = node_manager
+            .get_events_with_cursor(&next, &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(spends, vec![spend_event(6)]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_peer_gossips_peers() {
+        let b = MockPeer::new()
+            .current_block(20)
+            .events_commitment(Some(H256::repeat_byte(1)))
+            .spawn();
+        let a = MockPeer::new()
+            .current_block(10)
+            .version(Some(PROTOCOL_VERSION))
+            .peers(vec![b.peer()])
+            .spawn();
+        let mut node_manager = NodeManager::new(None, None, vec![a.peer()], true, true).unwrap();
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(node_manager.peer_count(), 2);
+        // Gossiped peers are handshaked from the next round on.
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(node_manager.elected_peer().unwrap().addr, b.addr());
+        assert!(b.requests(Endpoint::Handshake) > 0);
+
+        b.set_current_block(5);
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(node_manager.elected_peer().unwrap().addr, a.addr());
+    }
+
+    #[tokio::test]
+    async fn test_mock_peer_lopsided_pages() {
+        let mock = MockPeer::new()
+            .current_block(3)
+            .events(
+                (1..=4).map(spend_event).collect(),
+                (0..4).map(sent_event).collect(),
+            )
+            .fault(Endpoint::Events, Fault::Lopsided)
+            .spawn();
+        let mut node_manager = NodeManager::new(None, None, vec![mock.peer()], true, true).unwrap();
+        node_manager.sync_with_peers().await.unwrap();
+        let (spends, sents, next) = node_manager
+            .get_events_from_peer(&mock.addr().to_string(), &SyncCursor::default())
+            .await
+            .unwrap();
+        assert_eq!(spends, (1..=4).map(spend_event).collect::<Vec<_>>());
+        assert_eq!(sents, (0..4).map(sent_event).collect::<Vec<_>>());
+        assert_eq!((next.from_spend, next.from_sent), (4, 4));
+        // One page for the spends, then one per sent event and the empty page past them.
+        assert_eq!(mock.requests(Endpoint::Events), 5);
+    }
+
+    #[tokio::test]
+    async fn test_stale_election_is_renewed_before_fetching() {
+        // 10.0.0.1 is ahead at first, 10.0.0.2 has the events and moves on later.
+        let behind_block = Arc::new(AtomicU64::new(4));
+        let forbidden = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let transport = MockTransport::new({
+            let (behind_block, forbidden) = (behind_block.clone(), forbidden.clone());
+            move |url, _| {
+                let behind = url.contains("10.0.0.2");
+                let block = if behind {
+                    behind_block.load(atomic::Ordering::Relaxed)
+                } else {
+                    5
+                };
+                if url.contains("/get-peers") {
+                    json_response(&GetPeersResponse { peers: vec![] })
+                } else if url.contains("/events") {
+                    json_response(&GetEventsResponse {
+                        // The one event is only served from the start.
+                        spend_events: if behind && url.contains("from_spend=0&") {
+                            vec![spend_event(1)]
+                        } else {
+                            vec![]
+                        },
+                        sent_events: vec![],
+                        current_block_number: Some(block),
+                    })
+                } else if forbidden.load(atomic::Ordering::Relaxed) {
+                    Ok(PeerResponse {
+                        status: StatusCode::FORBIDDEN,
+                        body: String::new(),
+                    })
+                } else {
+                    json_response(&GetHandShakeResponse {
+                        current_block_number: block,
+                        max_events_page: None,
+                        version: None,
+                        events_commitment: None,
+                    })
+                }
+            }
+        });
+        let peers = vec![
+            Peer::from_str("10.0.0.1:8888").unwrap(),
+            Peer::from_str("10.0.0.2:8888").unwrap(),
+        ];
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(Instant::now())));
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.set_clock(clock.clone());
+        node_manager.set_max_election_age(Some(Duration::from_secs(60)));
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(
+            node_manager.elected_peer().unwrap().addr.to_string(),
+            "10.0.0.1:8888"
+        );
+
+        // Within the age the election stands, even though the other peer has moved on.
+        behind_block.store(9, atomic::Ordering::Relaxed);
+        clock.advance(Duration::from_secs(30));
+        let (spends, _, block) = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+        assert!(spends.is_empty());
+        assert_eq!(block, 5);
+        assert_eq!(transport.requests_to("10.0.0.1:8888/handshake"), 1);
+
+        clock.advance(Duration::from_secs(31));
+        let (spends, _, block) = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            node_manager.elected_peer().unwrap().addr.to_string(),
+            "10.0.0.2:8888"
+        );
+        assert_eq!(spends, vec![spend_event(1)]);
+        assert_eq!(block, 9);
+        assert_eq!(transport.requests_to("10.0.0.1:8888/handshake"), 2);
+
+        // Without any peer left to sync with, nothing is fetched from the old election.
+        forbidden.store(true, atomic::Ordering::Relaxed);
+        clock.advance(Duration::from_secs(61));
+        let err = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NetworkError::AllPeersUnreachable));
+        assert_eq!(transport.requests_to("10.0.0.2:8888/events"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_renewed_election_sticks_on_shared_manager() {
+        let behind_block = Arc::new(AtomicU64::new(4));
+        let transport = MockTransport::new({
+            let behind_block = behind_block.clone();
+            move |url, _| {
+                let block = if url.contains("10.0.0.2") {
+                    behind_block.load(atomic::Ordering::Relaxed)
+                } else {
+                    5
+                };
+                if url.contains("/get-peers") {
+                    json_response(&GetPeersResponse { peers: vec![] })
+                } else if url.contains("/events") {
+                    json_response(&GetEventsResponse {
+                        spend_events: vec![],
+                        sent_events: vec![],
+                        current_block_number: Some(block),
+                    })
+                } else {
+                    json_response(&GetHandShakeResponse {
+                        current_block_number: block,
+                        max_events_page: None,
+                        version: None,
+                        events_commitment: None,
+                    })
+                }
+            }
+        });
+        let peers = vec![
+            Peer::from_str("10.0.0.1:8888").unwrap(),
+            Peer::from_str("10.0.0.2:8888").unwrap(),
+        ];
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(Instant::now())));
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.set_clock(clock.clone());
+        node_manager.set_max_election_age(Some(Duration::from_secs(60)));
+        let manager = Arc::new(Mutex::new(node_manager));
+        NodeManager::sync_shared(&manager, |m| m).await.unwrap();
+
+        // Renewed while fetching through the lock, as the node and the coins API do.
+        behind_block.store(9, atomic::Ordering::Relaxed);
+        clock.advance(Duration::from_secs(61));
+        manager
+            .lock()
+            .await
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+        assert_eq!(transport.requests_to("10.0.0.1:8888/handshake"), 2);
+
+        // The renewal outlives the fetch and a later round, so it isn't renewed again.
+        NodeManager::sync_shared(&manager, |m| m).await.unwrap();
+        clock.advance(Duration::from_secs(30));
+        let mut manager = manager.lock().await;
+        manager.get_events_from_elected_peer(0, 0).await.unwrap();
+        assert_eq!(
+            manager.elected_peer().unwrap().addr.to_string(),
+            "10.0.0.2:8888"
+        );
+        assert_eq!(transport.requests_to("10.0.0.1:8888/handshake"), 3);
+    }
+
+    #[tokio::test]
+    async fn test_peer_order_makes_sync_reproducible() {
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("/get-peers") {
+                json_response(&GetPeersResponse { peers: vec![] })
+            } else {
+                json_response(&GetHandShakeResponse {
+                    current_block_number: 7,
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let addrs = [
+            "10.0.0.3:8888",
+            "10.0.0.1:8888",
+            "10.0.0.2:8888",
+            "10.0.0.4:8888",
+        ];
+        let manager = |addrs: Vec<&str>| {
+            let peers = addrs
+                .into_iter()
+                .map(|addr| Peer::from_str(addr).unwrap())
+                .collect();
+            let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+            node_manager.set_transport(transport.clone());
+            node_manager.set_peer_order(PeerOrder::Address);
+            node_manager.set_election_seed(7);
+            node_manager
+        };
+        let mut forward = manager(addrs.to_vec());
+        let mut backward = manager(addrs.iter().rev().copied().collect());
+
+        let ports = |node_manager: &NodeManager| -> Vec<u8> {
+            node_manager
+                .get_peers()
+                .iter()
+                .map(|p| match p.addr.ip() {
+                    IpAddr::V4(ip) => ip.octets()[3],
+                    IpAddr::V6(_) => unreachable!(),
+                })
+                .collect()
+        };
+        assert_eq!(ports(&forward), vec![1, 2, 3, 4]);
+        assert_eq!(ports(&backward), vec![1, 2, 3, 4]);
+        for _ in 0..5 {
+            let a = forward.sync_with_peers().await.unwrap().elected.unwrap();
+            let b = backward.sync_with_peers().await.unwrap().elected.unwrap();
+            assert_eq!(a.addr, b.addr);
+        }
+
+        // Stored in insertion order: .3, .1, .2, .4.
+        forward.peers[0].health = 0.9;
+        forward.peers[2].health = 0.5;
+        forward.set_peer_order("health-then-address".parse().unwrap());
+        assert_eq!(ports(&forward), vec![1, 4, 3, 2]);
+        assert!("health".parse::<PeerOrder>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_min_elect_block() {
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("/get-peers") {
+                json_response(&GetPeersResponse { peers: vec![] })
+            } else {
+                json_response(&GetHandShakeResponse {
+                    current_block_number: if url.contains("10.0.0.2") { 5_000 } else { 5 },
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let peers = vec![
+            peer("10.0.0.1:8888", 0, 1.0, 10),
+            peer("10.0.0.2:8888", 0, 1.0, 10),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport);
+        // Makes the low peer win on tolerance alone.
+        node_manager.election_block_tolerance = u64::MAX;
+        node_manager.peers[1].health = 0.5;
+
+        node_manager.set_min_elect_block(10_000);
+        let report = node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(report.reached, 2);
+        assert!(report.elected.is_none());
+        assert_eq!(node_manager.peer_count(), 2);
+
+        node_manager.set_min_elect_block(100);
+        let report = node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(report.elected.unwrap().addr.to_string(), "10.0.0.2:8888");
+
+        node_manager.set_min_elect_block(0);
+        let report = node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(report.elected.unwrap().addr.to_string(), "10.0.0.1:8888");
+    }
+
+    #[tokio::test]
+    async fn test_get_events_from_peer() {
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("10.0.0.3") && url.contains("/events") {
+                return Ok(PeerResponse {
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                    body: String::new(),
+                });
+            }
+            if url.contains("/get-peers") {
+                json_response(&GetPeersResponse { peers: vec![] })
+            } else if url.contains("/events") {
+                let spend_events = match (url.contains("10.0.0.1"), url.contains("from_spend=0")) {
+                    (true, true) => vec![spend_event(1), spend_event(2)],
+                    (false, true) => vec![spend_event(3)],
+                    _ => vec![],
+                };
+                json_response(&GetEventsResponse {
+                    spend_events,
+                    sent_events: vec![],
+                    current_block_number: None,
+                })
+            } else {
+                json_response(&GetHandShakeResponse {
+                    current_block_number: if url.contains("10.0.0.1") { 9 } else { 5 },
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let peers = vec![
+            peer("10.0.0.1:8888", 0, 1.0, 10),
+            peer("10.0.0.2:8888", 0, 1.0, 10),
+            peer("10.0.0.3:8888", 0, 1.0, 10),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport);
+        node_manager.sync_with_peers().await.unwrap();
+        let elected = node_manager.elected_peer().unwrap().clone();
+        assert_eq!(elected.addr.to_string(), "10.0.0.1:8888");
+
+        let cursor = SyncCursor::default();
+        let (spends, sents, next) = node_manager
+            .get_events_from_peer("10.0.0.2:8888", &cursor)
+            .await
+            .unwrap();
+        assert_eq!(spends, vec![spend_event(3)]);
+        assert!(sents.is_empty());
+        assert_eq!(
+            next,
+            SyncCursor {
+                from_spend: 1,
+                from_sent: 0,
+                last_block: 5,
+            }
+        );
+        let (spends, _, _) = node_manager
+            .get_events_from_peer("10.0.0.1:8888", &cursor)
+            .await
+            .unwrap();
+        assert_eq!(spends, vec![spend_event(1), spend_event(2)]);
+
+        assert!(matches!(
+            node_manager
+                .get_events_from_peer("10.0.0.3:8888", &cursor)
+                .await,
+            Err(NetworkError::PeerTripped(_))
+        ));
+        assert!(!node_manager.is_tripped("10.0.0.3:8888".parse().unwrap()));
+        assert!(matches!(
+            node_manager
+                .get_events_from_peer("10.0.0.4:8888", &cursor)
+                .await,
+            Err(NetworkError::UnknownPeer(_))
+        ));
+        assert_eq!(node_manager.elected_peer(), Some(&elected));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_elections() {
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("/get-peers") {
+                json_response(&GetPeersResponse { peers: vec![] })
+            } else {
+                json_response(&GetHandShakeResponse {
+                    current_block_number: if url.contains("10.0.0.2") { 9 } else { 5 },
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let peers = vec![
+            peer("10.0.0.1:8888", 0, 1.0, 10),
+            peer("10.0.0.2:8888", 0, 1.0, 10),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport);
+        let mut changes = node_manager.subscribe_elections();
+        let addrs = |change: ElectionChange| {
+            (
+                change.previous.map(|p| p.addr.to_string()),
+                change.elected.map(|p| p.addr.to_string()),
+            )
+        };
+
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(
+            addrs(changes.try_recv().unwrap()),
+            (None, Some("10.0.0.2:8888".to_string()))
+        );
+        // Re-electing the same peer isn't a change.
+        node_manager.sync_with_peers().await.unwrap();
+        assert!(changes.try_recv().is_err());
+
+        // Clones notify the same subscribers.
+        let mut clone = node_manager.clone();
+        clone.force_elect("10.0.0.1:8888").unwrap();
+        clone.sync_with_peers().await.unwrap();
+        assert_eq!(
+            addrs(changes.try_recv().unwrap()),
+            (
+                Some("10.0.0.2:8888".to_string()),
+                Some("10.0.0.1:8888".to_string())
+            )
+        );
+        assert_eq!(
+            addrs(changes.try_recv().unwrap()),
+            (
+                Some("10.0.0.1:8888".to_string()),
+                Some("10.0.0.2:8888".to_string())
+            )
+        );
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_force_elect() {
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("/get-peers") {
+                json_response(&GetPeersResponse { peers: vec![] })
+            } else if url.contains("/events") {
+                json_response(&GetEventsResponse {
+                    spend_events: vec![],
+                    sent_events: vec![],
+                    current_block_number: None,
+                })
+            } else {
+                json_response(&GetHandShakeResponse {
+                    current_block_number: if url.contains("10.0.0.2") { 9 } else { 5 },
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let peers = vec![
+            peer("10.0.0.1:8888", 0, 1.0, 10),
+            peer("10.0.0.2:8888", 0, 1.0, 10),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(
+            node_manager.elected_peer().unwrap().addr.to_string(),
+            "10.0.0.2:8888"
+        );
+
+        assert!(node_manager.force_elect("10.0.0.3:8888").is_err());
+        assert!(node_manager.force_elect("not a peer").is_err());
+        node_manager.force_elect("http://10.0.0.1:8888").unwrap();
+        node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+        let events_from = |host: &str| {
+            let requests = transport.requests.lock().unwrap();
+            requests
+                .iter()
+                .filter(|url| url.contains(host) && url.contains("/events"))
+                .count()
+        };
+        assert!(events_from("10.0.0.1") > 0);
+        assert_eq!(events_from("10.0.0.2"), 0);
+
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(
+            node_manager.elected_peer().unwrap().addr.to_string(),
+            "10.0.0.2:8888"
+        );
+    }
+
+    #[test]
+    fn test_sync_progress() {
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        let at = |last_block| SyncCursor {
+            last_block,
+            ..Default::default()
+        };
+        assert_eq!(node_manager.sync_progress(&at(10)), 0.0);
+        node_manager.elected_peer = Some(peer("10.0.0.1:8888", 0, 1.0, 10));
+        assert_eq!(node_manager.sync_progress(&at(10)), 0.0);
+
+        node_manager.elected_peer = Some(peer("10.0.0.1:8888", 200, 1.0, 10));
+        assert_eq!(node_manager.sync_progress(&at(0)), 0.0);
+        assert_eq!(node_manager.sync_progress(&at(50)), 0.25);
+        assert_eq!(node_manager.sync_progress(&at(200)), 1.0);
+        // A cursor ahead of the peer, e.g. filled from the provider, is done.
+        assert_eq!(node_manager.sync_progress(&at(300)), 1.0);
+    }
+
+    #[test]
+    fn test_set_max_peers_keeps_elected_peer() {
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.add_peer(peer("127.0.0.1:1001", 0, 0.5, 10));
+        node_manager.add_peer(peer("127.0.0.1:1002", 0, 0.2, 10));
+        node_manager.add_peer(peer("127.0.0.1:1003", 0, 0.9, 10));
+        node_manager.add_peer(peer("127.0.0.1:1004", 0, 0.7, 10));
+        node_manager.force_elect("127.0.0.1:1002").unwrap();
+
+        // The elected peer stays although it's the least healthy, and the order is kept.
+        node_manager.set_max_peers(3);
+        let ports: Vec<u16> = node_manager
+            .get_peers()
+            .iter()
+            .map(|p| p.addr.port())
+            .collect();
+        assert_eq!(ports, vec![1002, 1003, 1004]);
+        assert_eq!(node_manager.elected_peer().unwrap().addr.port(), 1002);
+
+        node_manager.set_max_peers(0);
+        assert_eq!(node_manager.peer_count(), 0);
+        assert!(node_manager.elected_peer().is_none());
+    }
+
+    #[test]
+    fn test_add_peers_in_one_pass() {
+        let external: SocketAddr = "10.0.0.9:8888".parse().unwrap();
+        let mut node_manager = NodeManager::new(Some(external), None, vec![], true, false).unwrap();
+        node_manager.add_peer(peer("127.0.0.1:1001", 0, 1.0, 10));
+        node_manager.set_max_peers(4);
+
+        node_manager.add_peers(vec![
+            Peer::from_str("[::ffff:127.0.0.1]:1001").unwrap(),
+            Peer::from_str("127.0.0.1:1002").unwrap(),
+            Peer::from_str("[::ffff:127.0.0.1]:1002").unwrap(),
+            Peer::from_str("10.0.0.9:8888").unwrap(),
+            Peer::from_str("127.0.0.1:8888").unwrap(),
+            Peer::from_str("0.0.0.0:1003").unwrap(),
+            Peer::from_str("127.0.0.1:1004").unwrap(),
+        ]);
+        let ports: Vec<u16> = node_manager
+            .get_peers()
+            .iter()
+            .map(|p| p.addr.port())
+            .collect();
+        assert_eq!(ports, vec![1001, 1002, 1004]);
+        assert_eq!(node_manager.metrics().peers_added, 3);
+
+        // At capacity, newcomers only replace less healthy peers, as with `add_peer`.
+        node_manager.add_peers(vec![
+            peer("127.0.0.1:1005", 0, 0.2, 10),
+            peer("127.0.0.1:1006", 0, 0.1, 10),
+        ]);
+        assert_eq!(node_manager.peer_count(), 4);
+        assert!(node_manager
+            .get_peers()
+            .iter()
+            .any(|p| p.addr.port() == 1005));
+    }
+
+    #[test]
+    fn test_set_peers_replaces_list_and_keeps_health() {
+        let peers = vec![
+            peer("127.0.0.1:1001", 100, 0.3, 10),
+            peer("127.0.0.1:1002", 100, 1.0, 20),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.elected_peer = Some(node_manager.peers[0].clone());
+        node_manager.blacklist.insert(
+            "127.0.0.1:1004".parse().unwrap(),
+            Instant::now() + Duration::from_secs(60),
+        );
+
+        node_manager.set_peers(vec![
+            Peer::from_str("[::ffff:127.0.0.1]:1002").unwrap(),
+            Peer::from_str("127.0.0.1:1002").unwrap(),
+            Peer::from_str("127.0.0.1:1003").unwrap(),
+            Peer::from_str("127.0.0.1:1004").unwrap(),
+            Peer::from_str("0.0.0.0:1005").unwrap(),
+        ]);
+        let peers = node_manager.get_peers();
+        let ports: Vec<u16> = peers.iter().map(|p| p.addr.port()).collect();
+        assert_eq!(ports, vec![1002, 1003]);
+        assert_eq!(peers[0].latency, Some(Duration::from_millis(20)));
+        assert_eq!(peers[1].latency, None);
+        assert_ne!(node_manager.elected_peer().unwrap().addr.port(), 1001);
+
+        let metrics = node_manager.metrics();
+        assert_eq!(metrics.peers_removed, 1);
+
+        node_manager.set_max_peers(1);
+        node_manager.set_peers(vec![
+            peer("127.0.0.1:1006", 0, 0.5, 10),
+            peer("127.0.0.1:1007", 0, 0.9, 10),
+        ]);
+        let ports: Vec<u16> = node_manager
+            .get_peers()
+            .iter()
+            .map(|p| p.addr.port())
+            .collect();
+        assert_eq!(ports, vec![1007]);
+    }
+
+    #[tokio::test]
+    async fn test_gossiped_self_address_is_ignored() {
+        let app = Router::new().route(
+            "/get-peers",
+            get(|| async {
+                Json(GetPeersResponse {
+                    peers: vec![
+                        Peer::from_str("[::ffff:203.0.113.7]:8888").unwrap(),
+                        Peer::from_str("127.0.0.1:8888").unwrap(),
+                        Peer::from_str("203.0.113.8:8888").unwrap(),
+                    ],
+                })
+            }),
+        );
+        let gossiper = spawn_mock_peer(app).await;
+
+        let external = "203.0.113.7:8888".parse().unwrap();
+        let mut node_manager = NodeManager::new(Some(external), None, vec![], true, false).unwrap();
+        node_manager
+            ._add_batch_peer_peers(Peer::new(gossiper))
+            .await
+            .unwrap();
+        let addrs: Vec<SocketAddr> = node_manager.get_peers().iter().map(|p| p.addr).collect();
+        assert_eq!(addrs, vec!["203.0.113.8:8888".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_sync_round_timeout_leaves_slow_peers_alone() {
+        // 10.0.0.2 never answers its handshake in time, and later neither does 10.0.0.1.
+        let all_hang = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let transport = MockTransport::with_delay(
+            {
+                let all_hang = all_hang.clone();
+                move |url| {
+                    let hangs =
+                        url.contains("10.0.0.2") || all_hang.load(atomic::Ordering::Relaxed);
+                    if hangs && url.contains("/handshake") {
+                        Duration::from_secs(30)
+                    } else {
+                        Duration::ZERO
+                    }
+                }
+            },
+            |url, _| {
+                if url.contains("/get-peers") {
+                    return json_response(&GetPeersResponse { peers: vec![] });
+                }
+                json_response(&GetHandShakeResponse {
+                    current_block_number: if url.contains("10.0.0.2") { 9 } else { 7 },
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            },
+        );
+        let peers = vec![
+            Peer::from_str("10.0.0.1:8888").unwrap(),
+            Peer::from_str("10.0.0.2:8888").unwrap(),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.set_request_timeouts(
+            Duration::from_secs(30),
+            Duration::from_secs(30),
+            Duration::from_secs(30),
+        );
+        node_manager.set_sync_round_timeout(Some(Duration::from_millis(300)));
+        let slow_before = node_manager.known_peer("10.0.0.2:8888").unwrap();
+
+        let started = Instant::now();
+        let report = node_manager.sync_with_peers().await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(report.timed_out);
+        assert_eq!(report.reached, 1);
+        assert!(report.dropped.is_empty());
+        assert_eq!(report.elected.unwrap().addr.to_string(), "10.0.0.1:8888");
+        let slow_after = node_manager.known_peer("10.0.0.2:8888").unwrap();
+        assert_eq!(slow_after.health, slow_before.health);
+        assert_eq!(slow_after.current_block, slow_before.current_block);
+        assert_eq!(transport.requests_to("10.0.0.2:8888/handshake"), 1);
+
+        // With nobody answering in time the round still reports rather than failing.
+        all_hang.store(true, atomic::Ordering::Relaxed);
+        let report = node_manager.sync_with_peers().await.unwrap();
+        assert!(report.timed_out);
+        assert_eq!(report.reached, 0);
+        assert_eq!(node_manager.peer_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_gossiping_peer_listing_itself_is_ignored() {
+        let gossiper: SocketAddr = "10.0.0.1:8888".parse().unwrap();
+        let transport = MockTransport::new(move |_, _| {
+            json_response(&GetPeersResponse {
+                peers: vec![
+                    Peer::new(gossiper),
+                    Peer::from_str("[::ffff:10.0.0.1]:8888").unwrap(),
+                    Peer::from_str("10.0.0.2:8888").unwrap(),
+                ],
+            })
+        });
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.set_transport(transport);
+        node_manager
+            ._add_batch_peer_peers(Peer::new(gossiper))
+            .await
+            .unwrap();
+        let addrs: Vec<SocketAddr> = node_manager.get_peers().iter().map(|p| p.addr).collect();
+        assert_eq!(addrs, vec!["10.0.0.2:8888".parse().unwrap()]);
+        assert_eq!(node_manager.metrics().peers_added, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_peer_additions_never_duplicate() {
+        // Overlapping windows of a pool of 40 addresses, every other one IPv4-mapped.
+        fn window(start: usize) -> Vec<Peer> {
+            (start..start + 16)
+                .map(|i| {
+                    let i = i % 40;
+                    let addr = if i % 2 == 0 {
+                        format!("10.0.1.{}:8888", i)
+                    } else {
+                        format!("[::ffff:10.0.1.{}]:8888", i)
+                    };
+                    Peer::from_str(&addr).unwrap()
+                })
+                .collect()
+        }
+        let transport = MockTransport::new(|url, _| {
+            let start = url.len() % 40;
+            json_response(&GetPeersResponse {
+                peers: window(start),
+            })
+        });
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.set_transport(transport);
+        let shared = Arc::new(Mutex::new(node_manager));
+
+        let tasks: Vec<_> = (0..48)
+            .map(|t| {
+                let shared = shared.clone();
+                tokio::spawn(async move {
+                    let peers = window(t * 7);
+                    match t % 3 {
+                        0 => {
+                            for peer in peers {
+                                shared.lock().await.add_peer(peer);
+                                tokio::task::yield_now().await;
+                            }
+                        }
+                        1 => shared.lock().await.add_peers(peers),
+                        _ => {
+                            let gossiper = Peer::from_str(&format!("10.0.2.{}:8888", t)).unwrap();
+                            shared
+                                .lock()
+                                .await
+                                ._add_batch_peer_peers(gossiper)
+                                .await
+                                .unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let node_manager = shared.lock().await;
+        let addrs: HashSet<SocketAddr> = node_manager
+            .get_peers()
+            .iter()
+            .map(|p| p.normalized_addr())
+            .collect();
+        assert_eq!(addrs.len(), node_manager.peer_count());
+        assert_eq!(node_manager.peer_count(), 40);
+        assert_eq!(node_manager.metrics().peers_added, 40);
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_from_seeds_skips_unresolvable_seeds() {
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        let added = node_manager
+            .bootstrap_from_seeds(&["bad seed:port".to_string(), "localhost".to_string()])
+            .await;
+        assert!(added >= 1);
+        assert_eq!(node_manager.peer_count(), added);
+        assert!(node_manager
+            .get_peers()
+            .iter()
+            .all(|p| p.addr.ip().is_loopback() && p.addr.port() == DEFAULT_SEED_PORT));
+    }
+
+    #[test]
+    fn test_save_and_load_peers() {
+        let dir = std::env::temp_dir().join(format!("owshen-peers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("peers.json");
+
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.add_peer(peer("127.0.0.1:1001", 50, 0.5, 10));
+        node_manager.add_peer(Peer::from_str("https://127.0.0.1:1002").unwrap());
+        node_manager.save_peers(&path).unwrap();
+
+        let mut restored = NodeManager::new(None, None, vec![], true, true).unwrap();
+        assert_eq!(restored.load_peers(&path), 2);
+        let peers = restored.get_peers();
+        assert_eq!(peers, node_manager.get_peers());
+        assert!(peers.iter().all(|p| p.current_block == 0));
+        assert_eq!(peers[1].scheme, PeerScheme::Https);
+
+        std::fs::write(&path, "not json").unwrap();
+        let mut corrupt = NodeManager::new(None, None, vec![], true, true).unwrap();
+        assert_eq!(corrupt.load_peers(&path), 0);
+        assert_eq!(corrupt.load_peers(&dir.join("missing.json")), 0);
+        assert_eq!(corrupt.peer_count(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_with_peers_elects_reachable_peer() {
+        let app = Router::new()
+            .route(
+                "/handshake",
+                get(|| async {
+                    Json(GetHandShakeResponse {
+                        current_block_number: 42,
+                        max_events_page: None,
+                        version: None,
+                        events_commitment: None,
+                    })
+                }),
+            )
+            .route(
+                "/get-peers",
+                get(|| async { Json(GetPeersResponse { peers: vec![] }) }),
+            );
+        let live = spawn_mock_peer(app).await;
+        let dead = spawn_mock_peer(Router::new()).await;
+
+        let mut node_manager = NodeManager::new(
+            None,
+            None,
+            vec![Peer::new(live), Peer::new(dead)],
+            true,
+            true,
+        )
+        .unwrap();
+        assert_eq!(node_manager.peer_count(), 2);
+        assert!(node_manager.elected_peer().is_none());
+
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(node_manager.peer_count(), 1);
+        let elected = node_manager.elected_peer().unwrap();
+        assert_eq!(elected.addr, live);
+        assert_eq!(elected.current_block, 42);
+    }
+
+    #[tokio::test]
+    async fn test_ping_elected() {
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        let err = node_manager.ping_elected().await.unwrap_err();
+        assert!(matches!(err, NetworkError::NoElectedPeer));
+
+        let app = Router::new().route(
+            "/handshake",
+            get(|| async {
+                Json(GetHandShakeResponse {
+                    current_block_number: 99,
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }),
+        );
+        node_manager.elected_peer = Some(Peer::new(spawn_mock_peer(app).await));
+        assert_eq!(node_manager.ping_elected().await.unwrap(), 99);
+
+        node_manager.elected_peer = Some(Peer::new(spawn_mock_peer(Router::new()).await));
+        assert!(matches!(
+            node_manager.ping_elected().await,
+            Err(NetworkError::PeerStatus(status)) if status == reqwest::StatusCode::NOT_FOUND
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_events_clamps_to_advertised_page_size() {
+        let longest = Arc::new(AtomicU64::new(0));
+        let longest_seen = longest.clone();
+        let app = Router::new()
+            .route(
+                "/handshake",
+                get(|| async {
+                    Json(GetHandShakeResponse {
+                        current_block_number: 1,
+                        max_events_page: Some(50),
+                        version: None,
+                        events_commitment: None,
+                    })
+                }),
+            )
+            .route(
+                "/get-peers",
+                get(|| async { Json(GetPeersResponse { peers: vec![] }) }),
+            )
+            .route(
+                "/events",
+                get(move |Query(req): Query<GetEventsRequest>| {
+                    longest_seen.fetch_max(req.length as u64, atomic::Ordering::Relaxed);
+                    async move {
+                        Json(GetEventsResponse {
+                            spend_events: (1..=120)
+                                .map(spend_event)
+                                .skip(req.from_spend)
+                                .take(req.length)
+                                .collect(),
+                            sent_events: vec![],
+                            current_block_number: None,
+                        })
+                    }
+                }),
+            );
+        let addr = spawn_mock_peer(app).await;
+
+        let mut node_manager =
+            NodeManager::new(None, None, vec![Peer::new(addr)], true, true).unwrap();
+        node_manager.sync_with_peers().await.unwrap();
+        let (spend_events, _, _) = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+        assert_eq!(spend_events.len(), 120);
+        assert_eq!(longest.load(atomic::Ordering::Relaxed), 50);
+
+        node_manager.set_events_page_size(20);
+        longest.store(0, atomic::Ordering::Relaxed);
+        node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+        assert_eq!(longest.load(atomic::Ordering::Relaxed), 20);
+    }
+
+    #[tokio::test]
+    async fn test_get_events_cancelled_mid_fetch() {
+        // The first page is served right away; the second request cancels the fetch and then
+        // stalls far longer than the test is willing to wait.
+        let cancel = CancellationToken::new();
+        let server_cancel = cancel.clone();
+        let app = Router::new().route(
+            "/events",
+            get(move |Query(req): Query<GetEventsRequest>| {
+                let cancel = server_cancel.clone();
+                async move {
+                    if req.from_spend > 0 {
+                        cancel.cancel();
+                        tokio::time::sleep(Duration::from_secs(30)).await;
+                    }
+                    Json(GetEventsResponse {
+                        spend_events: (1..=10).map(spend_event).skip(req.from_spend).collect(),
+                        sent_events: vec![],
+                        current_block_number: None,
+                    })
+                }
+            }),
+        );
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.elected_peer = Some(Peer::new(spawn_mock_peer(app).await));
+
+        let started = Instant::now();
+        let (spend_events, _, cursor) = node_manager
+            .get_events_with_cursor(&SyncCursor::default(), &cancel)
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(spend_events.len(), 10);
+        assert_eq!(cursor.from_spend, 10);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_track_sync_rounds() {
+        let app = Router::new()
+            .route(
+                "/handshake",
+                get(|| async {
+                    Json(GetHandShakeResponse {
+                        current_block_number: 7,
+                        max_events_page: None,
+                        version: None,
+                        events_commitment: None,
+                    })
+                }),
+            )
+            .route(
+                "/get-peers",
+                get(|| async { Json(GetPeersResponse { peers: vec![] }) }),
+            )
+            .route(
+                "/events",
+                get(|Query(req): Query<GetEventsRequest>| async move {
+                    Json(GetEventsResponse {
+                        spend_events: (1..=3).map(spend_event).skip(req.from_spend).collect(),
+                        sent_events: (0..5).map(sent_event).skip(req.from_sent).collect(),
+                        current_block_number: None,
+                    })
+                }),
+            );
+        let live = spawn_mock_peer(app).await;
+        let dead = spawn_mock_peer(Router::new()).await;
+
+        let mut node_manager = NodeManager::new(
+            None,
+            None,
+            vec![Peer::new(live), Peer::new(dead)],
+            true,
+            true,
+        )
+        .unwrap();
+        node_manager.max_handshake_retries = 1;
+        node_manager.handshake_retry_backoff = Duration::from_millis(1);
+
+        node_manager.sync_with_peers().await.unwrap();
+        node_manager.sync_with_peers().await.unwrap();
+        node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            node_manager.metrics(),
+            SyncMetrics {
+                handshakes_attempted: 3,
+                handshakes_succeeded: 2,
+                handshakes_failed: 1,
+                peers_added: 0,
+                peers_removed: 1,
+                spend_events_fetched: 3,
+                sent_events_fetched: 5,
+                rpc_timeouts: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_keeps_overloaded_peers_and_drops_rejecting_ones() {
+        let handshake = || async {
+            Json(GetHandShakeResponse {
+                current_block_number: 5,
+                max_events_page: None,
+                version: None,
+                events_commitment: None,
+            })
+        };
+        let overloaded = spawn_mock_peer(Router::new().route(
+            "/handshake",
+            get(|| async { StatusCode::SERVICE_UNAVAILABLE }),
+        ))
+        .await;
+        let rejecting = spawn_mock_peer(
+            Router::new().route("/handshake", get(|| async { StatusCode::NOT_FOUND })),
+        )
+        .await;
+        let busy_gossip = spawn_mock_peer(
+            Router::new()
+                .route("/handshake", get(handshake))
+                .route("/get-peers", get(|| async { StatusCode::BAD_GATEWAY })),
+        )
+        .await;
+
+        let mut node_manager = NodeManager::new(
+            None,
+            None,
+            vec![
+                Peer::new(overloaded),
+                Peer::new(rejecting),
+                Peer::new(busy_gossip),
+            ],
+            true,
+            true,
+        )
+        .unwrap();
+        node_manager.max_handshake_retries = 1;
+        node_manager.handshake_retry_backoff = Duration::from_millis(1);
+
+        let report = node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(report.reached, 1);
+        assert_eq!(report.dropped, vec![Peer::new(rejecting)]);
+        assert_eq!(
+            node_manager.get_peers(),
+            vec![Peer::new(overloaded), Peer::new(busy_gossip)]
+        );
+        assert!(node_manager.get_peers()[0].health < 1.0);
+        assert_eq!(node_manager.elected_peer().unwrap().addr, busy_gossip);
+        // The overloaded peer is retried, the rejecting one isn't.
+        assert_eq!(node_manager.metrics().handshakes_attempted, 4);
+    }
+
+    #[tokio::test]
+    async fn test_sync_reports_typed_errors() {
+        let garbled =
+            spawn_mock_peer(Router::new().route("/handshake", get(|| async { "{" }))).await;
+        let slow = spawn_mock_peer(Router::new().route(
+            "/handshake",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                "{}"
+            }),
+        ))
+        .await;
+        let mut node_manager = NodeManager::new(
+            None,
+            None,
+            vec![Peer::new(garbled), Peer::new(slow)],
+            true,
+            true,
+        )
+        .unwrap();
+        node_manager.handshake_timeout = Duration::from_millis(100);
+
+        let url = node_manager.handshake_url(&Peer::new(garbled)).unwrap();
+        assert!(matches!(
+            node_manager
+                .handshake(&url, None, node_manager.handshake_timeout)
+                .await,
+            Err(NetworkError::Parse(_))
+        ));
+        let url = node_manager.handshake_url(&Peer::new(slow)).unwrap();
+        assert!(matches!(
+            node_manager
+                .handshake(&url, None, node_manager.handshake_timeout)
+                .await,
+            Err(NetworkError::Timeout(_))
+        ));
+
+        node_manager.max_handshake_retries = 0;
+        assert!(matches!(
+            node_manager.sync_with_peers().await,
+            Err(NetworkError::AllPeersUnreachable)
+        ));
+        assert_eq!(node_manager.peer_count(), 0);
+
+        // Nodes need an external address to advertise in the handshake.
+        let node = NodeManager::new(None, None, vec![], true, false).unwrap();
+        assert!(matches!(
+            node.handshake_url(&Peer::new(garbled)),
+            Err(NetworkError::NotANode)
+        ));
+        let report: eyre::Report = NetworkError::NoElectedPeer.into();
+        assert!(matches!(
+            NetworkError::from(report),
+            NetworkError::NoElectedPeer
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_spaces_out_bursts() {
+        let limiter = RateLimiter::default();
+        let started = Instant::now();
+        for _ in 0..50 {
+            limiter.acquire(None).await;
+        }
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        // The first second's worth goes out at once, the other 10 are spread over half a
+        // second.
+        let started = Instant::now();
+        for _ in 0..30 {
+            limiter.acquire(Some(20.0)).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(900), "{:?}", elapsed);
+
+        // Tiny rates wait long instead of overflowing the wait.
+        let limiter = RateLimiter::default();
+        limiter.acquire(Some(1e-300)).await;
+        assert!(
+            timeout(Duration::from_millis(50), limiter.acquire(Some(1e-300)))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_respects_request_rate() {
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("/get-peers") {
+                json_response(&GetPeersResponse { peers: vec![] })
+            } else {
+                json_response(&GetHandShakeResponse {
+                    current_block_number: 7,
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let peers = (1..=5)
+            .map(|i| Peer::from_str(&format!("10.0.0.{}:8888", i)).unwrap())
+            .collect();
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.set_max_requests_per_second(Some(20.0));
+
+        // Two rounds of 10 requests each use up the first second's burst...
+        let started = Instant::now();
+        node_manager.sync_with_peers().await.unwrap();
+        node_manager.sync_with_peers().await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(200));
+        // ...and clones share the bucket, so the next round has to wait for it to refill.
+        let mut clone = node_manager.clone();
+        clone.sync_with_peers().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[test]
+    fn test_prune_stale_peers() {
+        let peers = vec![
+            peer("127.0.0.1:1001", 100, 1.0, 10),
+            peer("127.0.0.1:1002", 90, 1.0, 10),
+            peer("127.0.0.1:1003", 89, 1.0, 10),
+            peer("127.0.0.1:1004", 0, 1.0, 10),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        assert_eq!(node_manager.prune_stale_peers(10), 0);
+
+        node_manager.elected_peer = node_manager.elect(&node_manager.peers);
+        assert_eq!(node_manager.prune_stale_peers(10), 2);
+        let ports: Vec<u16> = node_manager
+            .get_peers()
+            .iter()
+            .map(|p| p.addr.port())
+            .collect();
+        assert_eq!(ports, vec![1001, 1002]);
+        assert_eq!(node_manager.metrics().peers_removed, 2);
+    }
+
+    #[test]
+    fn test_blacklisted_peers_are_not_readded() {
+        let peers = vec![
+            peer("127.0.0.1:1001", 100, 1.0, 10),
+            peer("127.0.0.1:1002", 99, 1.0, 50),
+        ];
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(Instant::now())));
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_clock(clock.clone());
+        node_manager.elected_peer = node_manager.elect(&node_manager.peers);
+
+        node_manager.blacklist_peer("127.0.0.1:1001".parse().unwrap(), Duration::from_secs(60));
+        assert_eq!(node_manager.peer_count(), 1);
+        assert_eq!(node_manager.elected_peer().unwrap().addr.port(), 1002);
+        // Equivalent spellings of the address are banned too.
+        node_manager.add_peer(Peer::from_str("[::ffff:127.0.0.1]:1001").unwrap());
+        assert_eq!(node_manager.peer_count(), 1);
+
+        clock.advance(Duration::from_secs(60));
+        node_manager.add_peer(Peer::from_str("127.0.0.1:1001").unwrap());
+        assert_eq!(node_manager.peer_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failing_peers_are_blacklisted_for_longer_each_time() {
+        let dead = spawn_mock_peer(Router::new()).await;
+        let mut node_manager =
+            NodeManager::new(None, None, vec![Peer::new(dead)], true, true).unwrap();
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(Instant::now())));
+        node_manager.set_clock(clock.clone());
+        node_manager.blacklist_duration = Duration::from_secs(100);
+
+        let mut bans = Vec::new();
+        for _ in 0..3 {
+            let started = clock.now();
+            assert!(node_manager.sync_with_peers().await.is_err());
+            assert!(node_manager.is_blacklisted(dead));
+            node_manager.add_peer(Peer::new(dead));
+            assert_eq!(node_manager.peer_count(), 0);
+
+            bans.push(node_manager.blacklist[&dead] - started);
+            // Lift the ban early so the peer can fail again.
+            node_manager.blacklist.clear();
+            node_manager.add_peer(Peer::new(dead));
+        }
+        assert_eq!(bans, [100, 200, 400].map(Duration::from_secs));
+    }
+
+    #[tokio::test]
+    async fn test_empty_peer_list_rebootstraps() {
+        let transport = MockTransport::new(|_, _| {
+            Ok(PeerResponse {
+                status: StatusCode::UNAUTHORIZED,
+                body: String::new(),
+            })
+        });
+        let peers = vec![
+            Peer::from_str("10.0.0.1:8888").unwrap(),
+            Peer::from_str("10.0.0.2:8888").unwrap(),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        assert_eq!(
+            node_manager
+                .bootstrap_from_seeds(&["10.0.0.3:8888".to_string()])
+                .await,
+            1
+        );
+
+        // Every peer refuses us and is dropped, then the bootstrap list and seeds refill it.
+        assert!(matches!(
+            node_manager.sync_with_peers().await,
+            Err(NetworkError::AllPeersUnreachable)
+        ));
+        assert_eq!(transport.requests_to("10.0.0.3:8888"), 1);
+        assert_eq!(node_manager.peer_count(), 3);
+
+        // Banned bootstrap peers wait for their ban to run out.
+        node_manager.blacklist_peer("10.0.0.1:8888".parse().unwrap(), Duration::from_secs(60));
+        node_manager.sync_with_peers().await.unwrap_err();
+        let addrs: Vec<String> = node_manager
+            .get_peers()
+            .iter()
+            .map(|p| p.addr.to_string())
+            .collect();
+        assert_eq!(addrs, vec!["10.0.0.2:8888", "10.0.0.3:8888"]);
+    }
+
+    #[tokio::test]
+    async fn test_peer_status_reports_every_peer() {
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("10.0.0.2") {
+                return Err(NetworkError::Timeout(url.to_string()));
+            }
+            let current_block_number = if url.contains("10.0.0.1") { 40 } else { 42 };
+            json_response(&GetHandShakeResponse {
+                current_block_number,
+                max_events_page: None,
+                version: None,
+                events_commitment: None,
+            })
+        });
+        let peers: Vec<Peer> = (1..=3)
+            .map(|i| Peer::from_str(&format!("10.0.0.{}:8888", i)).unwrap())
+            .collect();
+        let mut node_manager = NodeManager::new(None, None, peers.clone(), true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+
+        let status = node_manager.peer_status().await;
+        let summary: Vec<(u8, bool, Option<u64>)> = status
+            .iter()
+            .map(|s| match s.addr.ip() {
+                IpAddr::V4(ip) => (ip.octets()[3], s.reachable, s.current_block),
+                IpAddr::V6(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![(1, true, Some(40)), (2, false, None), (3, true, Some(42))]
+        );
+        assert!(status[0].latency.is_some() && status[1].latency.is_none());
+
+        // It's only a diagnostic: the peer list and election are untouched.
+        assert_eq!(node_manager.get_peers(), peers);
+        assert!(node_manager.elected_peer().is_none());
+        assert!(node_manager
+            .get_peers()
+            .iter()
+            .all(|p| p.current_block == 0));
+    }
+
+    #[tokio::test]
+    async fn test_node_mode_requires_external_addr() {
+        let transport = MockTransport::new(|_, _| {
+            json_response(&GetHandShakeResponse {
+                current_block_number: 7,
+                max_events_page: None,
+                version: None,
+                events_commitment: None,
+            })
+        });
+        let peers = vec![Peer::from_str("10.0.0.1:8888").unwrap()];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        assert!(node_manager.is_client());
+        assert!(matches!(
+            node_manager.set_client_mode(false),
+            Err(NetworkError::NotANode)
+        ));
+        assert!(node_manager.is_client());
+
+        // A node built without an address can't sync.
+        node_manager.is_client = false;
+        let err = node_manager.sync_with_peers().await.unwrap_err();
+        assert!(matches!(err, NetworkError::NotANode));
+        assert_eq!(err.to_string(), "Caller not a node!");
+        assert_eq!(transport.requests_to("10.0.0.1"), 0);
+
+        // The check doesn't depend on there being peers to handshake with.
+        let mut empty = NodeManager::new(None, None, vec![], true, false).unwrap();
+        empty.set_transport(transport.clone());
+        assert!(matches!(
+            empty.sync_with_peers().await,
+            Err(NetworkError::NotANode)
+        ));
+
+        node_manager.external_addr = Some("203.0.113.7:8888".parse().unwrap());
+        node_manager.set_client_mode(false).unwrap();
+        assert!(!node_manager.is_client());
+        node_manager.sync_with_peers().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_with_injected_transport() {
+        let flaky_attempts = AtomicU64::new(0);
+        let transport = MockTransport::new(move |url, _| {
+            let handshake = GetHandShakeResponse {
+                current_block_number: 7,
+                max_events_page: None,
+                version: None,
+                events_commitment: None,
+            };
+            let status = |status| {
+                Ok(PeerResponse {
+                    status,
+                    body: String::new(),
+                })
+            };
+            match url {
+                _ if url.contains("10.0.0.1:8888/handshake") => {
+                    // Overloaded for the first two attempts.
+                    if flaky_attempts.fetch_add(1, atomic::Ordering::Relaxed) < 2 {
+                        status(StatusCode::SERVICE_UNAVAILABLE)
+                    } else {
+                        json_response(&handshake)
+                    }
+                }
+                _ if url.contains("10.0.0.1:8888/get-peers") => json_response(&GetPeersResponse {
+                    peers: vec![
+                        Peer::from_str("10.0.0.3:8888").unwrap(),
+                        Peer::from_str("10.0.0.4:8888").unwrap(),
+                    ],
+                }),
+                _ if url.contains("10.0.0.2") => status(StatusCode::UNAUTHORIZED),
+                _ => Err(NetworkError::Timeout(url.to_string())),
+            }
+        });
+        let peers = ["10.0.0.1:8888", "10.0.0.2:8888", "10.0.0.3:8888"]
+            .into_iter()
+            .map(|addr| Peer::from_str(addr).unwrap())
+            .collect();
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.max_handshake_retries = 2;
+        node_manager.handshake_retry_backoff = Duration::from_millis(1);
+
+        let report = node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(report.reached, 1);
+        assert_eq!(node_manager.elected_peer().unwrap().current_block, 7);
+        assert_eq!(
+            node_manager.peer_failures[&"10.0.0.1:8888".parse().unwrap()],
+            2
+        );
+        // Client errors aren't retried, timeouts are until the retries run out.
+        assert_eq!(transport.requests_to("10.0.0.2"), 1);
+        assert_eq!(transport.requests_to("10.0.0.3"), 3);
+        // The timed out peer is blacklisted, so gossip can't bring it back.
+        assert_eq!(
+            node_manager.get_peers(),
+            vec![
+                Peer::from_str("10.0.0.1:8888").unwrap(),
+                Peer::from_str("10.0.0.4:8888").unwrap(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_retry_budget_is_shared_across_peers() {
+        // 10.0.0.1 answers on its second attempt, the others never do.
+        let first_failed = AtomicU64::new(0);
+        let transport = MockTransport::new(move |url, _| {
+            if url.contains("/get-peers") {
+                return json_response(&GetPeersResponse { peers: vec![] });
+            }
+            if url.contains("10.0.0.1") && first_failed.fetch_add(1, atomic::Ordering::Relaxed) > 0
+            {
+                return json_response(&GetHandShakeResponse {
+                    current_block_number: 7,
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                });
+            }
+            Err(NetworkError::Timeout(url.to_string()))
+        });
+        let peers = (1..=4)
+            .map(|i| Peer::from_str(&format!("10.0.0.{}:8888", i)).unwrap())
+            .collect();
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.handshake_concurrency = 1;
+        node_manager.max_handshake_retries = 3;
+        node_manager.handshake_retry_backoff = Duration::from_millis(1);
+        node_manager.sync_retry_budget = Some(3);
+
+        let report = node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(report.reached, 1);
+        // 10.0.0.1 takes one retry and 10.0.0.2 the other two; the rest fail at once.
+        assert_eq!(transport.requests_to("10.0.0.2"), 3);
+        assert_eq!(transport.requests_to("10.0.0.3"), 1);
+        assert_eq!(transport.requests_to("10.0.0.4"), 1);
+        assert_eq!(report.dropped.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_sync_sends_bearer_tokens() {
+        let transport = MockTransport::new(|url, bearer| {
+            let expected = if url.contains("10.0.0.1") {
+                "peer-secret"
+            } else {
+                "our-secret"
+            };
+            if bearer != Some(expected) {
+                return Ok(PeerResponse {
+                    status: StatusCode::UNAUTHORIZED,
+                    body: String::new(),
+                });
+            }
+            if url.contains("/get-peers") {
+                json_response(&GetPeersResponse { peers: vec![] })
+            } else {
+                json_response(&GetHandShakeResponse {
+                    current_block_number: 7,
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let peers = vec![
+            Peer::from_str("https://peer-secret@10.0.0.1:8888").unwrap(),
+            Peer::from_str("10.0.0.2:8888").unwrap(),
+            Peer::from_str("wrong-secret@10.0.0.3:8888").unwrap(),
+        ];
+        assert_eq!(peers[0].scheme, PeerScheme::Https);
+        assert_eq!(peers[0].auth_token.as_deref(), Some("peer-secret"));
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.set_auth_token(Some("our-secret".into()));
+
+        let report = node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(report.reached, 2);
+        // The rejected peer is dropped without retries, but not blacklisted.
+        assert_eq!(transport.requests_to("10.0.0.3"), 1);
+        assert_eq!(report.dropped.len(), 1);
+        assert!(!node_manager.is_blacklisted("10.0.0.3:8888".parse().unwrap()));
+        // Tokens stay local.
+        let gossiped = serde_json::to_string(&node_manager.get_peers()).unwrap();
+        assert!(!gossiped.contains("secret"));
+    }
+
+    #[test]
+    fn test_events_commitment() {
+        let mut batched = EventsCommitment::default();
+        batched.extend(&[spend_event(1), spend_event(2)], &[sent_event(0)]);
+        let mut rolled = EventsCommitment::default();
+        rolled.extend(&[spend_event(1)], &[]);
+        rolled.extend(&[spend_event(2)], &[sent_event(0)]);
+        assert_eq!(batched.root(), rolled.root());
+
+        let mut reordered = EventsCommitment::default();
+        reordered.extend(&[spend_event(2), spend_event(1)], &[sent_event(0)]);
+        assert_ne!(batched.root(), reordered.root());
+        // A spend moved into the other stream doesn't collide either.
+        let mut other = EventsCommitment::default();
+        other.extend(&[spend_event(1), spend_event(2)], &[]);
+        assert_ne!(batched.root(), other.root());
+        assert_ne!(other.root(), EventsCommitment::default().root());
+    }
+
+    #[test]
+    fn test_divergent_peers() {
+        let addr = |port| SocketAddr::from(([10, 0, 0, 1], port));
+        let (a, b) = (H256::repeat_byte(1), H256::repeat_byte(2));
+        let divergent = divergent_peers(&[
+            (addr(1), 7, a),
+            (addr(2), 7, a),
+            (addr(3), 7, b),
+            // Peers at other heights aren't compared with those at 7.
+            (addr(4), 8, b),
+            // No majority among these two.
+            (addr(5), 9, a),
+            (addr(6), 9, b),
+        ]);
+        assert_eq!(divergent, vec![addr(3)]);
+    }
+
+    #[tokio::test]
+    async fn test_sync_demotes_divergent_peers() {
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("/get-peers") {
+                return json_response(&GetPeersResponse { peers: vec![] });
+            }
+            let commitment = if url.contains("10.0.0.3") {
+                H256::repeat_byte(2)
+            } else {
+                H256::repeat_byte(1)
+            };
+            json_response(&GetHandShakeResponse {
+                current_block_number: 7,
+                max_events_page: None,
+                version: None,
+                events_commitment: Some(commitment),
+            })
+        });
+        let peers = (1..=3)
+            .map(|i| Peer::from_str(&format!("10.0.0.{}:8888", i)).unwrap())
+            .collect();
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport);
+        let odd_one: SocketAddr = "10.0.0.3:8888".parse().unwrap();
+
+        for _ in 0..10 {
+            let report = node_manager.sync_with_peers().await.unwrap();
+            assert_eq!(report.reached, 3);
+            assert_eq!(report.divergent.len(), 1);
+            assert_eq!(report.divergent[0].addr, odd_one);
+            assert_ne!(report.elected.unwrap().addr, odd_one);
+        }
+        let demoted = node_manager.get_peers();
+        let demoted = demoted.iter().find(|p| p.addr == odd_one).unwrap();
+        assert_eq!(demoted.health, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_per_peer_timeouts() {
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("/get-peers") {
+                json_response(&GetPeersResponse { peers: vec![] })
+            } else if url.contains("/events") {
+                json_response(&GetEventsResponse {
+                    spend_events: vec![],
+                    sent_events: vec![],
+                    current_block_number: Some(7),
+                })
+            } else {
+                json_response(&GetHandShakeResponse {
+                    current_block_number: 7,
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let local = Peer::from_str("10.0.0.1:8888?timeout=250").unwrap();
+        assert_eq!(local.timeout, Some(Duration::from_millis(250)));
+        assert!(Peer::from_str("10.0.0.1:8888?timeout=0").is_err());
+        assert!(Peer::from_str("10.0.0.1:8888?timeout=soon").is_err());
+        let remote = Peer::from_str("10.0.0.2:8888").unwrap();
+        let mut node_manager =
+            NodeManager::new(None, None, vec![local.clone(), remote.clone()], true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.set_request_timeouts(
+            Duration::from_secs(3),
+            Duration::from_secs(4),
+            Duration::from_secs(5),
+        );
+
+        node_manager.sync_with_peers().await.unwrap();
+        for peer in [local, remote] {
+            node_manager.elected_peer = Some(peer);
+            node_manager
+                .get_events_from_elected_peer(0, 0)
+                .await
+                .unwrap();
+        }
+
+        let local_timeouts = transport.timeouts_to("10.0.0.1");
+        assert!(local_timeouts.len() >= 3);
+        assert!(local_timeouts
+            .iter()
+            .all(|timeout| *timeout == Duration::from_millis(250)));
+        // The remote peer falls back to the manager's timeouts for each kind of request.
+        let remote_timeouts = transport.timeouts_to("10.0.0.2");
+        for secs in [3, 4, 5] {
+            assert!(remote_timeouts.contains(&Duration::from_secs(secs)));
+        }
+        // The override stays local.
+        let gossiped = serde_json::to_string(&node_manager.get_peers()).unwrap();
+        assert!(!gossiped.contains("timeout"));
+    }
+
+    #[tokio::test]
+    async fn test_sync_survives_garbled_peer_lists() {
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("10.0.0.1:8888/get-peers") {
+                Ok(PeerResponse {
+                    status: StatusCode::OK,
+                    body: "{\"peers\": [".into(),
+                })
+            } else if url.contains("/get-peers") {
+                json_response(&GetPeersResponse {
+                    peers: vec![Peer::from_str("10.0.0.3:8888").unwrap()],
+                })
+            } else {
+                json_response(&GetHandShakeResponse {
+                    current_block_number: 7,
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let peers = vec![
+            Peer::from_str("10.0.0.1:8888").unwrap(),
+            Peer::from_str("10.0.0.2:8888").unwrap(),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport);
+
+        let report = node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(report.reached, 2);
+        assert_eq!(
+            report.dropped,
+            vec![Peer::from_str("10.0.0.1:8888").unwrap()]
+        );
+        // The peer after the garbled one still got to gossip.
+        assert_eq!(
+            node_manager.get_peers(),
+            vec![
+                Peer::from_str("10.0.0.2:8888").unwrap(),
+                Peer::from_str("10.0.0.3:8888").unwrap(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_requests_identify_the_node() {
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let app = Router::new().route(
+            "/handshake",
+            get(move |headers: axum::http::HeaderMap| {
+                let header =
+                    |name: &str| headers.get(name).map(|v| v.to_str().unwrap().to_string());
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((header("user-agent"), header(NODE_ID_HEADER)));
+                async {
+                    Json(GetHandShakeResponse {
+                        current_block_number: 7,
+                        max_events_page: None,
+                        version: None,
+                        events_commitment: None,
+                    })
+                }
+            }),
+        );
+        let addr = spawn_mock_peer(app).await;
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.elected_peer = Some(Peer::new(addr));
+
+        node_manager.ping_elected().await.unwrap();
+        node_manager.set_node_id(Some("node-a".into())).unwrap();
+        node_manager
+            .set_http_timeout(Duration::from_secs(2))
+            .unwrap();
+        node_manager
+            .set_connection_pool(ConnectionPoolConfig {
+                max_idle_per_host: 0,
+                idle_timeout: None,
+            })
+            .unwrap();
+        node_manager.ping_elected().await.unwrap();
+        assert!(node_manager.set_node_id(Some("bad\nid".into())).is_err());
+
+        let user_agent = Some(format!("owshen/{}", env!("CARGO_PKG_VERSION")));
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (user_agent.clone(), None),
+                (user_agent, Some("node-a".to_string())),
+            ]
+        );
+        assert_eq!(node_manager.http_timeout, Duration::from_secs(2));
+        assert_eq!(node_manager.connection_pool.max_idle_per_host, 0);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_peer_responses_are_dropped() {
+        let small = spawn_mock_peer(Router::new().route(
+            "/handshake",
+            get(|| async {
+                Json(GetHandShakeResponse {
+                    current_block_number: 3,
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }),
+        ))
+        .await;
+        // Streams whitespace forever, without a length header.
+        let endless = spawn_mock_peer(Router::new().route(
+            "/handshake",
+            get(|| async {
+                axum::body::StreamBody::new(stream::repeat_with(|| {
+                    Ok::<_, std::io::Error>(vec![b' '; 1024])
+                }))
+            }),
+        ))
+        .await;
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.set_max_response_body(256);
+
+        node_manager.elected_peer = Some(Peer::new(small));
+        assert_eq!(node_manager.ping_elected().await.unwrap(), 3);
+
+        node_manager.elected_peer = Some(Peer::new(endless));
+        assert!(matches!(
+            node_manager.ping_elected().await,
+            Err(NetworkError::BodyTooLarge { limit: 256, .. })
+        ));
+
+        node_manager.set_max_response_body(8);
+        node_manager.elected_peer = Some(Peer::new(small));
+        assert!(matches!(
+            node_manager.ping_elected().await,
+            Err(NetworkError::BodyTooLarge { limit: 8, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pinned_certificates() {
+        use rustls::client::ServerCertVerifier;
+
+        let cert = rustls::Certificate(b"peer certificate".to_vec());
+        let pin = CertFingerprint::of(&cert.0);
+        let hex_pin = hex::encode(pin.0);
+        let colon_pin = pin
+            .0
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        assert_eq!(CertFingerprint::from_str(&hex_pin).unwrap(), pin);
+        assert_eq!(CertFingerprint::from_str(&colon_pin).unwrap(), pin);
+        assert!(CertFingerprint::from_str(&hex_pin[2..]).is_err());
+        assert!(CertFingerprint::from_str("not hex").is_err());
+
+        let verifier = PinnedCertVerifier { pins: vec![pin] };
+        let verify = |cert: &rustls::Certificate| {
+            verifier.verify_server_cert(
+                cert,
+                &[],
+                &rustls::ServerName::try_from("peer.example").unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                std::time::SystemTime::now(),
+            )
+        };
+        assert!(verify(&cert).is_ok());
+        assert!(verify(&rustls::Certificate(b"someone else".to_vec())).is_err());
+
+        // Plain http peers aren't affected by the pins.
+        let addr = spawn_mock_peer(Router::new().route(
+            "/handshake",
+            get(|| async {
+                Json(GetHandShakeResponse {
+                    current_block_number: 3,
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }),
+        ))
+        .await;
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.elected_peer = Some(Peer::new(addr));
+        node_manager.set_pinned_certificates(vec![pin]).unwrap();
+        assert_eq!(node_manager.pinned_certificates, vec![pin]);
+        assert_eq!(node_manager.ping_elected().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_sync_drops_incompatible_peers() {
+        let peer_with_version = |version: Option<u32>| {
+            Router::new()
+                .route(
+                    "/handshake",
+                    get(move |Query(req): Query<GetHandShakeRequest>| async move {
+                        assert_eq!(req.version, Some(PROTOCOL_VERSION));
+                        Json(GetHandShakeResponse {
+                            current_block_number: 5,
+                            max_events_page: None,
+                            version,
+                            events_commitment: None,
+                        })
+                    }),
+                )
+                .route(
+                    "/get-peers",
+                    get(|| async { Json(GetPeersResponse { peers: vec![] }) }),
+                )
+        };
+        let legacy = spawn_mock_peer(peer_with_version(None)).await;
+        let current = spawn_mock_peer(peer_with_version(Some(PROTOCOL_VERSION))).await;
+
+        let peers = vec![Peer::new(legacy), Peer::new(current)];
+        let mut node_manager = NodeManager::new(None, None, peers.clone(), true, true).unwrap();
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(node_manager.peer_count(), 2);
+
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_min_peer_version(PROTOCOL_VERSION);
+        let report = node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(report.reached, 1);
+        assert_eq!(node_manager.get_peers(), vec![Peer::new(current)]);
+    }
+
+    #[tokio::test]
+    async fn test_sync_shared_keeps_concurrent_changes() {
+        // Handshakes take a while, so the round is still running when the manager changes.
+        let transport = MockTransport::with_delay(
+            |url| {
+                if url.contains("/handshake") {
+                    Duration::from_millis(200)
+                } else {
+                    Duration::ZERO
+                }
+            },
+            |url, _| {
+                if url.contains("/get-peers") {
+                    return json_response(&GetPeersResponse { peers: vec![] });
+                }
+                json_response(&GetHandShakeResponse {
+                    current_block_number: if url.contains("10.0.0.1") { 5 } else { 9 },
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            },
+        );
+        let peers = ["10.0.0.1:8888", "10.0.0.2:8888", "10.0.0.3:8888"]
+            .into_iter()
+            .map(|addr| Peer::from_str(addr).unwrap())
+            .collect();
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport);
+        node_manager.election_block_tolerance = 0;
+        let manager = Arc::new(Mutex::new(node_manager));
+
+        let round = tokio::spawn({
+            let manager = manager.clone();
+            async move { NodeManager::sync_shared(&manager, |m| m).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // While the round runs, another task elects a peer, adds one and removes one.
+        {
+            let mut manager = manager.lock().await;
+            manager.force_elect("10.0.0.1:8888").unwrap();
+            manager.add_peer(Peer::from_str("10.0.0.7:8888").unwrap());
+            manager.remove_peer(Peer::from_str("10.0.0.3:8888").unwrap());
+        }
+        let report = round.await.unwrap().unwrap();
+        assert_eq!(report.reached, 3);
+
+        let manager = manager.lock().await;
+        let blocks: Vec<(String, u64)> = manager
+            .get_peers()
+            .iter()
+            .map(|p| (p.addr.to_string(), p.current_block))
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                ("10.0.0.1:8888".to_string(), 5),
+                ("10.0.0.2:8888".to_string(), 9),
+                ("10.0.0.7:8888".to_string(), 0)
+            ]
+        );
+        assert_eq!(
+            manager.elected_peer().unwrap().addr.to_string(),
+            "10.0.0.1:8888"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_loop_runs_until_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("/get-peers") {
+                json_response(&GetPeersResponse {
+                    peers: vec![Peer::from_str("10.0.0.2:8888").unwrap()],
+                })
+            } else {
+                json_response(&GetHandShakeResponse {
+                    current_block_number: 5,
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let peers = vec![Peer::from_str("10.0.0.1:8888").unwrap()];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.set_state_dir(Some(dir.path().to_path_buf()));
+        let manager = Arc::new(Mutex::new(node_manager));
+
+        let handle =
+            NodeManager::spawn_sync_loop(manager.clone(), |m| m, Duration::from_millis(20));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while transport.requests_to("10.0.0.2:8888/handshake") < 2 {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        handle.stop().await;
+
+        // Nothing runs after `stop`, and every round saved the peers it found.
+        let requests = transport.requests.lock().unwrap().len();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(transport.requests.lock().unwrap().len(), requests);
+        assert_eq!(manager.lock().await.peer_count(), 2);
+        let mut restored = NodeManager::new(None, None, vec![], true, true).unwrap();
+        assert_eq!(restored.load_peers(&dir.path().join(STATE_PEERS_FILE)), 2);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_saves_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("/get-peers") {
+                json_response(&GetPeersResponse {
+                    peers: vec![Peer::from_str("10.0.0.2:8888").unwrap()],
+                })
+            } else {
+                json_response(&GetHandShakeResponse {
+                    current_block_number: 5,
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let mut node_manager = NodeManager::new(
+            None,
+            None,
+            vec![peer("10.0.0.1:8888", 0, 1.0, 10)],
+            true,
+            true,
+        )
+        .unwrap();
+        node_manager.set_transport(transport);
+        // Without a state directory there is nothing to save.
+        node_manager.shutdown().unwrap();
+        node_manager.set_state_dir(Some(dir.path().to_path_buf()));
+        let cursor = SyncCursor {
+            from_spend: 3,
+            from_sent: 4,
+            last_block: 5,
+        };
+        node_manager.record_cursor(&cursor);
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(node_manager.peer_count(), 2);
+        node_manager.shutdown().unwrap();
+
+        let mut restored = NodeManager::new(None, None, vec![], true, true).unwrap();
+        restored.set_state_dir(Some(dir.path().to_path_buf()));
+        assert_eq!(restored.restore(), Some(cursor));
+        assert_eq!(restored.get_peers(), node_manager.get_peers());
+
+        std::fs::remove_file(dir.path().join(STATE_CURSOR_FILE)).unwrap();
+        let mut fresh = NodeManager::new(None, None, vec![], true, true).unwrap();
+        fresh.set_state_dir(Some(dir.path().to_path_buf()));
+        assert_eq!(fresh.restore(), None);
+        assert_eq!(fresh.peer_count(), 2);
+    }
+
+    #[test]
+    fn test_validate_events_page() {
+        let page = |spends: Vec<SpendFilter>, sents: Vec<SentFilter>| GetEventsResponse {
+            spend_events: spends,
+            sent_events: sents,
+            current_block_number: None,
+        };
+        let seen = HashSet::from([U256::from(1)]);
+        let valid = page(
+            vec![spend_event(0), spend_event(0), spend_event(2)],
+            (3..6).map(sent_event).collect(),
+        );
+        assert!(validate_events_page(&valid, 3, Some(&sent_event(2)), None, &seen).is_ok());
+        assert!(validate_events_page(&valid, 2, None, None, &seen).is_err());
+        assert!(validate_events_page(&valid, 3, Some(&sent_event(1)), None, &seen).is_err());
+        // Without a previous page, the first sent event is anchored to the requested position.
+        assert!(validate_events_page(&valid, 3, None, Some(U256::from(3)), &seen).is_ok());
+        assert!(validate_events_page(&valid, 3, None, Some(U256::from(1)), &seen).is_err());
+
+        let duplicate = page(vec![spend_event(1)], vec![]);
+        assert!(validate_events_page(&duplicate, 3, None, None, &seen).is_err());
+        let mut out_of_field = spend_event(3);
+        out_of_field.nullifier = U256::MAX;
+        let out_of_field = page(vec![out_of_field], vec![]);
+        assert!(validate_events_page(&out_of_field, 3, None, None, &seen).is_err());
+
+        let mut backwards = (0..2).map(sent_event).collect::<Vec<_>>();
+        backwards[0].timestamp = U256::from(100);
+        assert!(validate_events_page(&page(vec![], backwards), 3, None, None, &seen).is_err());
+        let mut future = sent_event(0);
+        future.timestamp = U256::from(u64::MAX);
+        assert!(validate_events_page(&page(vec![], vec![future]), 3, None, None, &seen).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_events_page_demotes_peer() {
+        // The second page skips ahead in the deposit index sequence.
+        let bad = spawn_mock_peer(Router::new().route(
+            "/events",
+            get(|Query(req): Query<GetEventsRequest>| async move {
+                let sent_events = match req.from_sent {
+                    0 => (0..2).map(sent_event).collect(),
+                    2 => vec![sent_event(2), sent_event(9)],
+                    _ => vec![],
+                };
+                Json(GetEventsResponse {
+                    spend_events: vec![],
+                    sent_events,
+                    current_block_number: None,
+                })
+            }),
+        ))
+        .await;
+        let peers = vec![
+            peer(&bad.to_string(), 10, 1.0, 10),
+            peer("127.0.0.1:1002", 10, 0.5, 10),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_events_page_size(2);
+        node_manager.elected_peer = node_manager.elect(&node_manager.peers);
+        assert_eq!(node_manager.elected_peer().unwrap().addr, bad);
+
+        let err = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NetworkError::InvalidEventsPage(addr, _) if addr == bad));
+        assert_eq!(node_manager.elected_peer().unwrap().addr.port(), 1002);
+        assert_eq!(node_manager.get_peers()[0].health, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_events_page_skipping_ahead_is_rejected() {
+        // Asked for the sent events from the start, the peer serves them from deposit 5 on.
+        let bad = spawn_mock_peer(Router::new().route(
+            "/events",
+            get(|Query(req): Query<GetEventsRequest>| async move {
+                let sent_events = match req.from_sent {
+                    0 => (5..7).map(sent_event).collect(),
+                    _ => vec![],
+                };
+                Json(GetEventsResponse {
+                    spend_events: vec![],
+                    sent_events,
+                    current_block_number: None,
+                })
+            }),
+        ))
+        .await;
+        let (network, _) = spawn_mock_rpc(20, vec![]).await;
+        let peers = vec![peer(&bad.to_string(), 10, 1.0, 10)];
+        let node_manager = NodeManager::new(None, Some(network), peers, true, true).unwrap();
+
+        let err = node_manager
+            .get_events_from_peer(&bad.to_string(), &SyncCursor::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NetworkError::InvalidEventsPage(addr, _) if addr == bad));
+    }
+
+    #[tokio::test]
+    async fn test_failing_elected_peer_trips_circuit_breaker() {
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("/get-peers") {
+                json_response(&GetPeersResponse { peers: vec![] })
+            } else if url.contains("/events") {
+                if url.contains("10.0.0.1") {
+                    return Ok(PeerResponse {
+                        status: StatusCode::INTERNAL_SERVER_ERROR,
+                        body: String::new(),
+                    });
+                }
+                json_response(&GetEventsResponse {
+                    spend_events: vec![],
+                    sent_events: vec![],
+                    current_block_number: None,
+                })
+            } else {
+                let current_block_number = if url.contains("10.0.0.1") { 100 } else { 90 };
+                json_response(&GetHandShakeResponse {
+                    current_block_number,
+                    max_events_page: None,
+                    version: None,
+                    events_commitment: None,
+                })
+            }
+        });
+        let peers = vec![
+            Peer::from_str("10.0.0.1:8888").unwrap(),
+            Peer::from_str("10.0.0.2:8888").unwrap(),
+        ];
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(Instant::now())));
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.set_clock(clock.clone());
+        node_manager.breaker_cooldown = Duration::from_secs(60);
+        let tripped: SocketAddr = "10.0.0.1:8888".parse().unwrap();
+
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(node_manager.elected_peer().unwrap().addr, tripped);
+        let err = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NetworkError::PeerTripped(addr) if addr == tripped));
+        assert_eq!(transport.requests_to("10.0.0.1:8888/events"), 3);
+        assert_ne!(node_manager.elected_peer().unwrap().addr, tripped);
+
+        // The peer answers handshakes fine, but stays out of elections while cooling down.
+        node_manager.sync_with_peers().await.unwrap();
+        assert_eq!(node_manager.peer_count(), 2);
+        assert_ne!(node_manager.elected_peer().unwrap().addr, tripped);
+        assert!(node_manager.is_tripped(tripped));
+        // However the address is spelled.
+        assert!(node_manager.is_tripped("[::ffff:10.0.0.1]:8888".parse().unwrap()));
+
+        clock.advance(Duration::from_secs(60));
+        assert!(!node_manager.is_tripped(tripped));
+    }
+
+    #[tokio::test]
+    async fn test_failed_page_never_yields_truncated_events() {
+        // Serves two events per page, but the third page fails the first `flaky` times.
+        fn peer_transport(flaky: u64) -> Arc<MockTransport> {
+            let failures = AtomicU64::new(0);
+            MockTransport::new(move |url, _| {
+                let from_sent: usize = url
+                    .split("from_sent=")
+                    .nth(1)
+                    .and_then(|rest| rest.split('&').next())
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if from_sent == 4 && failures.fetch_add(1, atomic::Ordering::Relaxed) < flaky {
+                    return Ok(PeerResponse {
+                        status: StatusCode::SERVICE_UNAVAILABLE,
+                        body: String::new(),
+                    });
+                }
+                json_response(&GetEventsResponse {
+                    spend_events: vec![],
+                    sent_events: (from_sent as u64..10).take(2).map(sent_event).collect(),
+                    current_block_number: None,
+                })
+            })
+        }
+
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.set_events_page_size(2);
+        node_manager.set_transport(peer_transport(u64::MAX));
+        node_manager.elected_peer = Some(peer("10.0.0.1:8888", 5, 1.0, 10));
+        let err = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NetworkError::PeerTripped(_)));
+
+        // A page that fails fewer times than the breaker allows is retried in place.
+        node_manager.set_transport(peer_transport(2));
+        node_manager.elected_peer = Some(peer("10.0.0.1:8888", 5, 1.0, 10));
+        let (_, sent_events, _) = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+        let indexes: Vec<u64> = sent_events.iter().map(|e| e.index.as_u64()).collect();
+        assert_eq!(indexes, (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_event_batches_stream_pages() {
+        // Serves two sent events per page out of `total`, with its height, and fails from
+        // `fail_from` on.
+        fn peer_transport(total: u64, fail_from: u64) -> Arc<MockTransport> {
+            MockTransport::new(move |url, _| {
+                let from_sent: u64 = url
+                    .split("from_sent=")
+                    .nth(1)
+                    .and_then(|rest| rest.split('&').next())
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if from_sent >= fail_from {
+                    return Ok(PeerResponse {
+                        status: StatusCode::SERVICE_UNAVAILABLE,
+                        body: String::new(),
+                    });
+                }
+                json_response(&GetEventsResponse {
+                    spend_events: vec![],
+                    sent_events: (from_sent..total).take(2).map(sent_event).collect(),
+                    current_block_number: Some(40 + from_sent),
+                })
+            })
+        }
+        let transport = peer_transport(6, 7);
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.set_events_page_size(2);
+        node_manager.set_transport(transport.clone());
+        node_manager.elected_peer = Some(peer("10.0.0.1:8888", 5, 1.0, 10));
+
+        let mut batches =
+            Box::pin(node_manager.event_batches(&SyncCursor::default(), EventStreams::Both));
+        let first = batches.next().await.unwrap().unwrap();
+        assert_eq!(first.sent_events.len(), 2);
+        assert_eq!(first.cursor.from_sent, 2);
+        assert_eq!(first.cursor.last_block, 40);
+        // Nothing more is fetched until the stream is polled again.
+        assert_eq!(transport.requests_to("10.0.0.1"), 1);
+
+        let rest: Vec<EventBatch> = batches.map(Result::unwrap).collect().await;
+        let sizes: Vec<usize> = rest.iter().map(|b| b.sent_events.len()).collect();
+        assert_eq!(sizes, vec![2, 2, 0]);
+        let last = rest.last().unwrap();
+        assert_eq!(last.cursor.from_sent, 6);
+        assert_eq!(last.cursor.last_block, 46);
+
+        // An error ends the stream after the batches that were good.
+        node_manager.set_transport(peer_transport(6, 3));
+        let cursor = SyncCursor {
+            from_sent: 2,
+            ..Default::default()
+        };
+        let results: Vec<_> = node_manager
+            .event_batches(&cursor, EventStreams::Both)
+            .collect()
+            .await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap().cursor.from_sent, 4);
+        assert!(matches!(results[1], Err(NetworkError::PeerTripped(_))));
+    }
+
+    #[tokio::test]
+    async fn test_events_page_shrinks_on_parse_failures() {
+        // Truncates any response carrying more than four events.
+        let transport = MockTransport::new(|url, _| {
+            let param = |name: &str| -> usize {
+                url.split(&format!("{}=", name))
+                    .nth(1)
+                    .and_then(|rest| rest.split('&').next())
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            };
+            let from_sent = param("from_sent");
+            let sent_events: Vec<_> = (from_sent as u64..20)
+                .take(param("length"))
+                .map(sent_event)
+                .collect();
+            let mut resp = json_response(&GetEventsResponse {
+                spend_events: vec![],
+                sent_events: sent_events.clone(),
+                current_block_number: None,
+            })?;
+            if sent_events.len() > 4 {
+                resp.body.truncate(resp.body.len() / 2);
+            }
+            Ok(resp)
+        });
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.set_events_page_size(16);
+        node_manager.set_transport(transport.clone());
+        node_manager.elected_peer = Some(peer("10.0.0.1:8888", 5, 1.0, 20));
+
+        let (_, sent_events, _) = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+        let indexes: Vec<u64> = sent_events.iter().map(|e| e.index.as_u64()).collect();
+        assert_eq!(indexes, (0..20).collect::<Vec<_>>());
+        let requests = transport.requests.lock().unwrap().clone();
+        assert!(requests[0].contains("length=16"));
+        assert!(requests[1].contains("length=8"));
+        assert!(requests[2].contains("length=4"));
+        // Grows back after a streak of good pages, only to shrink again.
+        assert!(requests.iter().skip(3).any(|url| url.contains("length=8")));
+
+        // A peer whose pages never parse still trips the breaker.
+        node_manager.set_events_page_size(4);
+        node_manager.set_transport(MockTransport::new(|_, _| {
+            Ok(PeerResponse {
+                status: StatusCode::OK,
+                body: "{\"spend_events\": [".into(),
+            })
+        }));
+        let err = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NetworkError::PeerTripped(_)));
+    }
+
+    #[tokio::test]
+    async fn test_get_events_sharded() {
+        // Every peer serves the same nine events of each stream, except 10.0.0.3, which
+        // fails every events request and has its shards handed to the others.
+        let transport = MockTransport::new(|url, _| {
+            if url.contains("10.0.0.3") {
+                return Ok(PeerResponse {
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                    body: String::new(),
+                });
+            }
+            let param = |name: &str| -> usize {
+                url.split(&format!("{}=", name))
+                    .nth(1)
+                    .unwrap()
+                    .split('&')
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            };
+            let length = param("length");
+            json_response(&GetEventsResponse {
+                spend_events: (1..=9)
+                    .map(spend_event)
+                    .skip(param("from_spend"))
+                    .take(length)
+                    .collect(),
+                sent_events: (0..9)
+                    .map(sent_event)
+                    .skip(param("from_sent"))
+                    .take(length)
+                    .collect(),
+                current_block_number: Some(7),
+            })
+        });
+        let peers = vec![
+            peer("10.0.0.1:8888", 7, 1.0, 10),
+            peer("10.0.0.2:8888", 7, 1.0, 10),
+            peer("10.0.0.3:8888", 7, 1.0, 10),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.set_events_page_size(2);
+
+        let cursor = SyncCursor {
+            from_spend: 1,
+            from_sent: 1,
+            last_block: 3,
+        };
+        let (spends, sents, next) = node_manager.get_events_sharded(4, &cursor).await.unwrap();
+        assert_eq!(spends, (2..=5).map(spend_event).collect::<Vec<_>>());
+        assert_eq!(sents, (1..5).map(sent_event).collect::<Vec<_>>());
+        assert_eq!(
+            next,
+            SyncCursor {
+                from_spend: 5,
+                from_sent: 5,
+                last_block: 3,
+            }
+        );
+
+        // Asking for more than the peers have stops at the end of their events.
+        let (spends, sents, next) = node_manager
+            .get_events_sharded(20, &SyncCursor::default())
+            .await
+            .unwrap();
+        assert_eq!(spends, (1..=9).map(spend_event).collect::<Vec<_>>());
+        assert_eq!(sents, (0..9).map(sent_event).collect::<Vec<_>>());
+        assert_eq!(
+            next,
+            SyncCursor {
+                from_spend: 9,
+                from_sent: 9,
+                last_block: 7,
+            }
+        );
+        assert!(transport.requests_to("10.0.0.3") > 0);
+        assert!(node_manager.elected_peer().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_events_sharded_configured_peers() {
+        let transport = MockTransport::new(|url, _| {
+            let from = |name: &str| -> usize {
+                url.split(&format!("{}=", name))
+                    .nth(1)
+                    .unwrap()
+                    .split('&')
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            };
+            json_response(&GetEventsResponse {
+                spend_events: (1..=9)
+                    .map(spend_event)
+                    .skip(from("from_spend"))
+                    .take(2)
+                    .collect(),
+                sent_events: (0..9)
+                    .map(sent_event)
+                    .skip(from("from_sent"))
+                    .take(2)
+                    .collect(),
+                current_block_number: Some(7),
+            })
+        });
+        // Only the two healthiest peers are fetched from.
+        let peers = vec![
+            peer("10.0.0.1:8888", 7, 1.0, 10),
+            peer("10.0.0.2:8888", 7, 0.5, 10),
+            peer("10.0.0.3:8888", 7, 0.9, 10),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.set_events_page_size(2);
+        node_manager.set_shard_peers(2);
+
+        let (spends, sents, _) = node_manager
+            .get_events_sharded(8, &SyncCursor::default())
+            .await
+            .unwrap();
+        assert_eq!(spends, (1..=8).map(spend_event).collect::<Vec<_>>());
+        assert_eq!(sents, (0..8).map(sent_event).collect::<Vec<_>>());
+        assert_eq!(transport.requests_to("10.0.0.1"), 2);
+        assert_eq!(transport.requests_to("10.0.0.2"), 0);
+        assert_eq!(transport.requests_to("10.0.0.3"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_events_sharded_lopsided_streams() {
+        // One spend event but nine sent events: running out of spends in the first shard
+        // mustn't cut the sent events short.
+        let transport = MockTransport::new(|url, _| {
+            let param = |name: &str| -> usize {
+                url.split(&format!("{}=", name))
+                    .nth(1)
+                    .unwrap()
+                    .split('&')
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            };
+            let length = param("length");
+            json_response(&GetEventsResponse {
+                spend_events: (1..=1)
+                    .map(spend_event)
+                    .skip(param("from_spend"))
+                    .take(length)
+                    .collect(),
+                sent_events: (0..9)
+                    .map(sent_event)
+                    .skip(param("from_sent"))
+                    .take(length)
+                    .collect(),
+                current_block_number: Some(7),
+            })
+        });
+        let peers = vec![
+            peer("10.0.0.1:8888", 7, 1.0, 10),
+            peer("10.0.0.2:8888", 7, 1.0, 10),
+        ];
+        let mut node_manager = NodeManager::new(None, None, peers, true, true).unwrap();
+        node_manager.set_transport(transport);
+        node_manager.set_events_page_size(2);
+
+        let (spends, sents, next) = node_manager
+            .get_events_sharded(6, &SyncCursor::default())
+            .await
+            .unwrap();
+        assert_eq!(spends, vec![spend_event(1)]);
+        assert_eq!(sents, (0..6).map(sent_event).collect::<Vec<_>>());
+        assert_eq!(
+            next,
+            SyncCursor {
+                from_spend: 1,
+                from_sent: 6,
+                last_block: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_single_event_stream() {
+        // Behaves like a peer that predates stream selection and always serves both.
+        let transport = MockTransport::new(|url, _| {
+            let param = |name: &str| -> usize {
+                url.split(&format!("{}=", name))
+                    .nth(1)
+                    .unwrap()
+                    .split('&')
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            };
+            json_response(&GetEventsResponse {
+                spend_events: (1..=5)
+                    .map(spend_event)
+                    .skip(param("from_spend"))
+                    .take(2)
+                    .collect(),
+                sent_events: (0..1)
+                    .map(sent_event)
+                    .skip(param("from_sent"))
+                    .take(2)
+                    .collect(),
+                current_block_number: None,
+            })
+        });
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.set_events_page_size(2);
+        node_manager.set_transport(transport.clone());
+        node_manager.elected_peer = Some(peer("10.0.0.1:8888", 5, 1.0, 10));
+
+        let (spend_events, sent_events, _) = node_manager
+            .get_event_streams_from_elected_peer(0, 0, EventStreams::Spend)
+            .await
+            .unwrap();
+        assert_eq!(spend_events.len(), 5);
+        assert!(sent_events.is_empty());
+        assert_eq!(transport.requests_to("streams=spend"), 4);
+
+        let cursor = SyncCursor {
+            from_spend: 3,
+            from_sent: 0,
+            last_block: 0,
+        };
+        let (spend_events, sent_events, next) = node_manager
+            .get_event_streams_with_cursor(&cursor, EventStreams::Sent, &CancellationToken::new())
+            .await
+            .unwrap();
+        assert!(spend_events.is_empty());
+        assert_eq!(sent_events.len(), 1);
+        assert_eq!((next.from_spend, next.from_sent), (3, 1));
+        assert_eq!(transport.requests_to("streams=sent"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_events_returns_block_reported_with_pages() {
+        let pages = AtomicU64::new(0);
+        let transport = MockTransport::new(move |_, _| {
+            // The peer keeps syncing while we page through its events.
+            let page = pages.fetch_add(1, atomic::Ordering::Relaxed);
+            let sent_events = if page.is_multiple_of(2) {
+                vec![sent_event(0)]
+            } else {
+                vec![]
+            };
+            json_response(&GetEventsResponse {
+                spend_events: vec![],
+                sent_events,
+                current_block_number: Some(10 + page),
+            })
+        });
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.set_transport(transport.clone());
+        node_manager.elected_peer = Some(peer("10.0.0.1:8888", 5, 1.0, 10));
+
+        let (_, sent_events, block) = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+        assert_eq!(sent_events.len(), 1);
+        assert_eq!(block, 11);
+    }
+
+    #[tokio::test]
+    async fn test_get_events_over_compressed_responses() {
+        let encodings = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = encodings.clone();
+        let app = Router::new()
+            .route(
+                "/events",
+                get(|Query(req): Query<GetEventsRequest>| async move {
+                    Json(GetEventsResponse {
+                        spend_events: vec![],
+                        sent_events: (0..300)
+                            .map(sent_event)
+                            .skip(req.from_sent)
+                            .take(req.length)
+                            .collect(),
+                        current_block_number: None,
+                    })
+                }),
+            )
+            .layer(tower_http::compression::CompressionLayer::new())
+            .layer(axum::middleware::map_response(
+                move |resp: axum::response::Response| {
+                    let encoding = resp.headers().get("content-encoding").cloned();
+                    recorded.lock().unwrap().push(encoding);
+                    async move { resp }
+                },
+            ));
+        let addr = spawn_mock_peer(app).await;
+
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.elected_peer = Some(Peer::new(addr));
+        let (_, sent_events, _) = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+        assert_eq!(sent_events.len(), 300);
+        let encodings = encodings.lock().unwrap();
+        assert!(encodings.iter().all(|e| e.as_ref().unwrap() == "gzip"));
+    }
+
+    #[tokio::test]
+    async fn test_get_events_with_lopsided_pages() {
+        // Spend pages are capped well below the requested length while sent pages aren't,
+        // and the spend stream runs out long before the sent one.
+        let spends: Vec<SpendFilter> = (1..=300).map(spend_event).collect();
+        let sents: Vec<SentFilter> = (0..700).map(sent_event).collect();
+        let app = Router::new().route(
+            "/events",
+            get(move |Query(req): Query<GetEventsRequest>| {
+                let spends = spends.clone();
+                let sents = sents.clone();
+                async move {
+                    Json(GetEventsResponse {
+                        spend_events: spends
+                            .into_iter()
+                            .skip(req.from_spend)
+                            .take(req.length.min(100))
+                            .collect(),
+                        sent_events: sents
+                            .into_iter()
+                            .skip(req.from_sent)
+                            .take(req.length)
+                            .collect(),
+                        current_block_number: None,
+                    })
+                }
+            }),
+        );
+        let addr = spawn_mock_peer(app).await;
+
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.elected_peer = Some(Peer::new(addr));
+
+        let (spend_events, sent_events, _) = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            spend_events.iter().map(|e| e.nullifier).collect::<Vec<_>>(),
+            (1..=300).map(U256::from).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            sent_events.iter().map(|e| e.index).collect::<Vec<_>>(),
+            (0..700).map(U256::from).collect::<Vec<_>>()
+        );
+
+        let (spend_events, sent_events, _) = node_manager
+            .get_events_from_elected_peer(300, 650)
+            .await
+            .unwrap();
+        assert!(spend_events.is_empty());
+        assert_eq!(sent_events.len(), 50);
+
+        // Resuming from a cursor that ended on a partial page picks up exactly where it
+        // stopped.
+        let cursor = SyncCursor {
+            from_spend: 250,
+            from_sent: 0,
+            last_block: 0,
+        };
+        let (spend_events, _, cursor) = node_manager
+            .get_events_with_cursor(&cursor, &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(spend_events.len(), 50);
+        assert_eq!(cursor.from_spend, 300);
+        assert_eq!(cursor.from_sent, 700);
+        let (spend_events, sent_events, next) = node_manager
+            .get_events_with_cursor(&cursor, &CancellationToken::new())
+            .await
+            .unwrap();
+        assert!(spend_events.is_empty() && sent_events.is_empty());
+        assert_eq!(next, cursor);
+    }
+
+    #[test]
+    fn test_network_manager_lookups() {
+        let network_manager = NetworkManager::new().unwrap();
+
+        let weth = network_manager.get_token("Goerli", "WETH").unwrap();
+        assert_eq!(
+            weth.token_address,
+            H160::from_str("0xdD69DB25F6D620A7baD3023c5d32761D353D3De9").unwrap()
+        );
+        assert!(network_manager.has("Goerli", "WETH"));
+        assert_eq!(network_manager.get("Goerli").unwrap().len(), 1);
+
+        assert!(network_manager.get("Mainnet").is_none());
+        assert!(!network_manager.has("Mainnet", "WETH"));
+        assert!(network_manager.get_token("Mainnet", "WETH").is_none());
+
+        assert!(!network_manager.has("Goerli", "DIVE"));
+        assert!(network_manager.get_token("Goerli", "DIVE").is_none());
+
+        assert!(!network_manager.has("Goerli", "weth"));
+        assert!(!network_manager.has("goerli", "WETH"));
+    }
+
+    #[test]
+    fn test_native_tokens() {
+        let mut network_manager = NetworkManager::new().unwrap();
+        let eth = network_manager.native_token("Goerli").unwrap();
+        assert_eq!((eth.symbol.as_str(), eth.decimals), ("ETH", 18));
+        assert!(network_manager.native_token("Sepolia").is_none());
+
+        let sepolia_eth = NativeToken {
+            symbol: "SepoliaETH".to_string(),
+            decimals: 18,
+        };
+        network_manager.set_native_token("Sepolia".to_string(), sepolia_eth.clone());
+        assert_eq!(network_manager.native_token("Sepolia"), Some(&sepolia_eth));
+
+        let json = serde_json::to_string(&network_manager).unwrap();
+        let restored: NetworkManager = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.native_token("Sepolia"), Some(&sepolia_eth));
+
+        // Configs written before native tokens were recorded still load.
+        let old: NetworkManager = serde_json::from_str(r#"{"networks": {}}"#).unwrap();
+        assert!(old.native_tokens.is_empty());
+    }
+
+    #[test]
+    fn test_network_manager_resolve() {
+        let mut network_manager = NetworkManager::new().unwrap();
+        let dive = H160::from_low_u64_be(1);
+        network_manager
+            .add_network(
+                "Sepolia".to_string(),
+                vec![TokenInfo {
+                    token_address: dive,
+                    symbol: "DIVE".to_string(),
+                    ..Default::default()
+                }],
+            )
+            .unwrap();
+
+        assert_eq!(
+            network_manager.resolve_symbol("Sepolia", dive),
+            Some("DIVE")
+        );
+        assert_eq!(
+            network_manager.resolve_address("Sepolia", "DIVE"),
+            Some(dive)
+        );
+        assert_eq!(network_manager.resolve_symbol("Goerli", dive), None);
+        assert_eq!(
+            network_manager.resolve_symbol("Sepolia", H160::from_low_u64_be(2)),
+            None
+        );
+        assert_eq!(network_manager.resolve_address("Sepolia", "WETH"), None);
+    }
+
+    #[test]
+    fn test_network_manager_add_and_merge() {
+        let token = |symbol: &str, addr: u64| TokenInfo {
+            token_address: H160::from_low_u64_be(addr),
+            symbol: symbol.to_string(),
+            ..Default::default()
+        };
+        let mut network_manager = NetworkManager::new().unwrap();
+        assert!(network_manager
+            .add_network("Sepolia".into(), vec![token("DIVE", 1), token("DIVE", 2)])
+            .is_err());
+        assert!(network_manager
+            .add_network("Sepolia".into(), vec![token("DIVE", 0)])
+            .is_err());
+        assert!(network_manager.get("Sepolia").is_none());
+
+        network_manager
+            .merge_network("Goerli".into(), vec![token("WETH", 9), token("DIVE", 1)])
+            .unwrap();
+        let goerli = network_manager.get("Goerli").unwrap();
+        assert_eq!(goerli.len(), 2);
+        assert_eq!(
+            network_manager.resolve_address("Goerli", "WETH"),
+            Some(H160::from_str("0xdD69DB25F6D620A7baD3023c5d32761D353D3De9").unwrap())
+        );
+        assert!(network_manager
+            .merge_network("Goerli".into(), vec![token("USDC", 0)])
+            .is_err());
+        assert_eq!(network_manager.token_count("Goerli"), 2);
+    }
+
+    #[test]
+    fn test_network_manager_names() {
+        let mut network_manager = NetworkManager::new().unwrap();
+        for name in ["Sepolia", "Arbitrum", "Mainnet"] {
+            network_manager.add_network(name.into(), vec![]).unwrap();
+        }
+        assert_eq!(
+            network_manager.network_names(),
+            vec!["Arbitrum", "Goerli", "Mainnet", "Sepolia"]
+        );
+        assert_eq!(network_manager.token_count("Goerli"), 1);
+        assert_eq!(network_manager.token_count("Sepolia"), 0);
+        assert_eq!(network_manager.token_count("Unknown"), 0);
+    }
+
+    #[test]
+    fn test_token_info_format_amount() {
+        let weth = NetworkManager::new()
+            .unwrap()
+            .get_token("Goerli", "WETH")
+            .unwrap()
+            .clone();
+        assert_eq!(weth.decimals, 18);
+        assert_eq!(weth.format_amount(U256::exp10(18) * 3 / 2), "1.5");
+        assert_eq!(weth.format_amount(U256::exp10(18) * 2), "2");
+        assert_eq!(weth.format_amount(U256::from(1)), "0.000000000000000001");
+        assert_eq!(weth.format_amount(U256::zero()), "0");
+
+        let usdc = TokenInfo {
+            decimals: 6,
+            ..weth.clone()
+        };
+        assert_eq!(usdc.format_amount(U256::from(1_234_500)), "1.2345");
+        let whole = TokenInfo {
+            decimals: 0,
+            ..weth
+        };
+        assert_eq!(whole.format_amount(U256::from(42)), "42");
+    }
+
+    #[test]
+    fn test_token_info_missing_fields_default() {
+        let token: TokenInfo = serde_json::from_str(
+            r#"{"token_address":"0xdd69db25f6d620a7bad3023c5d32761d353d3de9","symbol":"WETH"}"#,
+        )
+        .unwrap();
+        assert_eq!(token.decimals, 18);
+        assert_eq!(token.chain_id, None);
+        assert!(!serde_json::to_string(&token).unwrap().contains("chain_id"));
+    }
+
+    fn event_log<E: EthEvent + Tokenizable>(event: E, block_number: u64) -> Log {
+        let tokens = match event.into_token() {
+            Token::Tuple(tokens) => tokens,
+            token => vec![token],
+        };
+        Log {
+            topics: vec![E::signature()],
+            data: ethers::abi::encode(&tokens).into(),
+            block_number: Some(block_number.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Serves `eth_blockNumber` and `eth_getLogs` over JSON-RPC from a fixed set of logs, and
+    /// `eth_getBlockByNumber` with block `n` mined at `12 * n`. Each block with logs holds a
+    /// single transaction whose hash is the block number, with a receipt for
+    /// `eth_getTransactionReceipt`.
+    async fn spawn_mock_rpc(head: u64, logs: Vec<Log>) -> (Network, Arc<AtomicU64>) {
+        let log_queries = Arc::new(AtomicU64::new(0));
+        let counter = log_queries.clone();
+        let app = Router::new().route(
+            "/",
+            post(move |Json(req): Json<serde_json::Value>| {
+                let logs = logs.clone();
+                let counter = counter.clone();
+                let respond = move |req: &serde_json::Value| {
+                    let logs = logs.clone();
+                    let result = match req["method"].as_str() {
+                        Some("eth_blockNumber") => serde_json::json!(U64::from(head)),
+                        Some("eth_getBlockByNumber") => {
+                            let number: U64 =
+                                serde_json::from_value(req["params"][0].clone()).unwrap();
+                            serde_json::json!(Block::<TxHash> {
+                                number: Some(number),
+                                timestamp: (number.as_u64() * 12).into(),
+                                ..Default::default()
+                            })
+                        }
+                        Some("eth_getTransactionReceipt") => {
+                            let hash: H256 =
+                                serde_json::from_value(req["params"][0].clone()).unwrap();
+                            let block = hash.to_low_u64_be();
+                            let logs: Vec<Log> = logs
+                                .into_iter()
+                                .filter(|l| l.block_number == Some(block.into()))
+                                .collect();
+                            if logs.is_empty() {
+                                serde_json::Value::Null
+                            } else {
+                                serde_json::json!(TransactionReceipt {
+                                    transaction_hash: hash,
+                                    block_number: Some(block.into()),
+                                    logs,
+                                    ..Default::default()
+                                })
+                            }
+                        }
+                        Some("eth_getLogs") => {
+                            counter.fetch_add(1, atomic::Ordering::Relaxed);
+                            let filter = &req["params"][0];
+                            let block = |key: &str| {
+                                U64::from_str_radix(
+                                    filter[key].as_str().unwrap().trim_start_matches("0x"),
+                                    16,
+                                )
+                                .unwrap()
+                            };
+                            let (from, to) = (block("fromBlock"), block("toBlock"));
+                            let topic: H256 =
+                                serde_json::from_value(filter["topics"][0].clone()).unwrap();
+                            serde_json::json!(logs
+                                .into_iter()
+                                .filter(|l| l.topics[0] == topic)
+                                .filter(|l| (from..=to).contains(&l.block_number.unwrap()))
+                                .collect::<Vec<_>>())
+                        }
+                        _ => serde_json::Value::Null,
+                    };
+                    serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": req["id"],
+                        "result": result,
+                    })
+                };
+                async move {
+                    match req.as_array() {
+                        Some(batch) => Json(batch.iter().map(respond).collect()),
+                        None => Json(respond(&req)),
+                    }
+                }
+            }),
+        );
+        let addr = spawn_mock_peer(app).await;
+        let network = Network {
+            provider: Arc::new(Provider::<Http>::try_from(format!("http://{}", addr)).unwrap()),
+            ws_provider: None,
+            rpc_client: reqwest::Client::new(),
+            config: Config {
+                name: "test".into(),
+                endpoint: format!("http://{}", addr),
+                chain_id: 1337,
+                dive_contract_address: H160::zero(),
+                owshen_contract_address: H160::zero(),
+                owshen_contract_deployment_block_number: U64::from(10),
+                owshen_contract_abi: Abi::default(),
+                erc20_abi: Abi::default(),
+                token_contracts: NetworkManager::new().unwrap(),
+                poseidon4_contract_address: H160::zero(),
+                poseidon2_contract_address: H160::zero(),
+                rpc_headers: HashMap::new(),
+                genesis_deposit_index: Some(0),
+            },
+        };
+        (network, log_queries)
+    }
+
+    #[tokio::test]
+    async fn test_discover_token() {
+        let token = H160::from_low_u64_be(0xda1);
+        let calls = Arc::new(AtomicU64::new(0));
+        let counter = calls.clone();
+        let app = Router::new().route(
+            "/",
+            post(move |Json(req): Json<serde_json::Value>| {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, atomic::Ordering::Relaxed);
+                    let call = &req["params"][0];
+                    let to: H160 = serde_json::from_value(call["to"].clone()).unwrap();
+                    let data = call["data"]
+                        .as_str()
+                        .or(call["input"].as_str())
+                        .unwrap()
+                        .to_string();
+                    let result = match (to == token, &data[..10]) {
+                        (true, "0x95d89b41") => ethers::abi::encode(&[Token::String("DAI".into())]),
+                        (true, "0x313ce567") => ethers::abi::encode(&[Token::Uint(18.into())]),
+                        _ => {
+                            return Json(serde_json::json!({
+                                "jsonrpc": "2.0",
+                                "id": req["id"],
+                                "error": {"code": 3, "message": "execution reverted"},
+                            }))
+                        }
+                    };
+                    Json(serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": req["id"],
+                        "result": Bytes::from(result),
+                    }))
+                }
+            }),
+        );
+        let addr = spawn_mock_peer(app).await;
+        let mut network_manager = NetworkManager::new().unwrap();
+        network_manager.set_provider(
+            "Goerli".into(),
+            Arc::new(Provider::<Http>::try_from(format!("http://{}", addr)).unwrap()),
+        );
+
+        let discovered = network_manager
+            .discover_token("Goerli", token)
+            .await
+            .unwrap();
+        assert_eq!(discovered.symbol, "DAI");
+        assert_eq!(discovered.decimals, 18);
+        assert_eq!(discovered.chain_id, Some(5));
+        assert_eq!(
+            network_manager.resolve_address("Goerli", "DAI"),
+            Some(token)
+        );
+        let queries = calls.load(atomic::Ordering::Relaxed);
+        network_manager
+            .discover_token("Goerli", token)
+            .await
+            .unwrap();
+        assert_eq!(calls.load(atomic::Ordering::Relaxed), queries);
+
+        // A failure is remembered, so the address isn't queried again right away.
+        let not_a_token = H160::from_low_u64_be(0xbad);
+        assert!(network_manager
+            .discover_token("Goerli", not_a_token)
+            .await
+            .is_err());
+        let queries = calls.load(atomic::Ordering::Relaxed);
+        assert!(network_manager
+            .discover_token("Goerli", not_a_token)
+            .await
+            .is_err());
+        assert_eq!(calls.load(atomic::Ordering::Relaxed), queries);
+        assert_eq!(network_manager.token_count("Goerli"), 2);
+
+        // Failures older than the retry delay are forgotten, so the address is queried again.
+        let long_ago = Instant::now() - TOKEN_DISCOVERY_RETRY * 2;
+        let other = H160::from_low_u64_be(0xbad2);
+        network_manager
+            .discovery_failures
+            .insert(("Goerli".into(), other), long_ago);
+        network_manager
+            .discovery_failures
+            .insert(("Goerli".into(), not_a_token), long_ago);
+        assert!(network_manager
+            .discover_token("Goerli", not_a_token)
+            .await
+            .is_err());
+        assert!(calls.load(atomic::Ordering::Relaxed) > queries);
+        assert_eq!(network_manager.discovery_failures.len(), 1);
+        assert!(network_manager
+            .discover_token("Sepolia", token)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_events_for_tx() {
+        let mut elsewhere = event_log(spend_event(3), 12);
+        elsewhere.address = H160::from_low_u64_be(1);
+        let logs = vec![
+            event_log(spend_event(1), 12),
+            event_log(sent_event(0), 12),
+            elsewhere,
+            event_log(spend_event(2), 13),
+        ];
+        let (network, log_queries) = spawn_mock_rpc(20, logs).await;
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        assert!(node_manager
+            .get_events_for_tx(H256::from_low_u64_be(12))
+            .await
+            .is_err());
+        node_manager.set_provider_network(network);
+
+        let (spends, sents) = node_manager
+            .get_events_for_tx(H256::from_low_u64_be(12))
+            .await
+            .unwrap();
+        assert_eq!(spends, vec![spend_event(1)]);
+        assert_eq!(sents, vec![sent_event(0)]);
+        let (spends, sents) = node_manager
+            .get_events_for_tx(H256::from_low_u64_be(13))
+            .await
+            .unwrap();
+        assert_eq!(spends, vec![spend_event(2)]);
+        assert!(sents.is_empty());
+        assert!(node_manager
+            .get_events_for_tx(H256::from_low_u64_be(99))
+            .await
+            .is_err());
+        assert_eq!(log_queries.load(atomic::Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_events_falls_back_to_provider() {
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        let err = node_manager
+            .get_events_from_elected_peer(0, 0)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NetworkError::NoElectedPeer));
+
+        let logs = vec![
+            event_log(spend_event(1), 12),
+            event_log(sent_event(0), 12),
+            event_log(sent_event(1), 30),
+            event_log(spend_event(2), 40),
+        ];
+        let (network, _) = spawn_mock_rpc(40, logs).await;
+        node_manager.set_provider_network(network);
+
+        let (spend_events, sent_events, cursor) = node_manager
+            .get_events_with_cursor(&SyncCursor::default(), &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(spend_events.len(), 2);
+        assert_eq!(sent_events.len(), 2);
+        assert_eq!(
+            cursor,
+            SyncCursor {
+                from_spend: 2,
+                from_sent: 2,
+                last_block: 40,
+            }
+        );
+
+        // Without a block to resume from, positions already held are skipped.
+        let (spend_events, sent_events, _) = node_manager
+            .get_events_from_elected_peer(1, 2)
+            .await
+            .unwrap();
+        assert_eq!(spend_events, vec![spend_event(2)]);
+        assert!(sent_events.is_empty());
+
+        node_manager.set_provider_fallback(false);
+        assert!(node_manager
+            .get_events_with_cursor(&cursor, &CancellationToken::new())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync_events_fills_tail_from_provider() {
+        let transport = MockTransport::new(|url, _| {
+            let sent_events = if url.contains("from_sent=0") {
+                (0..3).map(sent_event).collect()
+            } else {
+                vec![]
+            };
+            json_response(&GetEventsResponse {
+                spend_events: vec![],
+                sent_events,
+                current_block_number: Some(20),
+            })
+        });
+        // The provider also has the events the peer already served.
+        let logs = vec![
+            event_log(sent_event(2), 15),
+            event_log(sent_event(3), 30),
+            event_log(spend_event(1), 35),
+        ];
+        let (network, log_queries) = spawn_mock_rpc(40, logs).await;
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.set_transport(transport);
+        node_manager.elected_peer = Some(peer("10.0.0.1:8888", 20, 1.0, 10));
+
+        let (spend_events, sent_events, cursor) = node_manager
+            .sync_events(&SyncCursor::default())
+            .await
+            .unwrap();
+        assert!(spend_events.is_empty());
+        assert_eq!(sent_events.len(), 3);
+        assert_eq!(cursor.last_block, 20);
+
+        node_manager.set_provider_network(network);
+        let (spend_events, sent_events, cursor) = node_manager
+            .sync_events(&SyncCursor::default())
+            .await
+            .unwrap();
+        assert_eq!(spend_events, vec![spend_event(1)]);
+        assert_eq!(sent_events, (0..4).map(sent_event).collect::<Vec<_>>());
+        assert_eq!(
+            cursor,
+            SyncCursor {
+                from_spend: 1,
+                from_sent: 4,
+                last_block: 40,
+            }
+        );
+        assert_eq!(log_queries.load(atomic::Ordering::Relaxed), 2);
+
+        // Once the provider is ahead of the peer, only newer blocks are asked for.
+        let (spend_events, sent_events, next) = node_manager.sync_events(&cursor).await.unwrap();
+        assert!(spend_events.is_empty() && sent_events.is_empty());
+        assert_eq!(next, cursor);
+        assert_eq!(log_queries.load(atomic::Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_event_cache_skips_repeated_queries() {
+        let logs = vec![event_log(spend_event(1), 12), event_log(spend_event(2), 20)];
+        let (network, log_queries) = spawn_mock_rpc(40, logs).await;
+        let mut node_manager = NodeManager::new(None, Some(network), vec![], true, true).unwrap();
+        let queries = || log_queries.load(atomic::Ordering::Relaxed);
+
+        // Disabled by default.
+        node_manager.get_spend_events(10, 30).await.unwrap();
+        node_manager.get_spend_events(10, 30).await.unwrap();
+        assert_eq!(queries(), 2);
+
+        node_manager.set_event_cache_capacity(1);
+        assert_eq!(
+            node_manager.get_spend_events(10, 30).await.unwrap().len(),
+            2
+        );
+        assert_eq!(
+            node_manager.get_spend_events(10, 30).await.unwrap().len(),
+            2
+        );
+        assert_eq!(queries(), 3);
+        // Same range, different event type.
+        assert!(node_manager
+            .get_sent_events(10, 30)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(queries(), 4);
+        // Capacity one, so the sent query evicted the spend range.
+        node_manager.get_spend_events(10, 30).await.unwrap();
+        assert_eq!(queries(), 5);
+
+        node_manager.clear_event_cache();
+        node_manager.get_spend_events(10, 30).await.unwrap();
+        assert_eq!(queries(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_capped_event_queries_stop_after_max_blocks() {
+        let logs = vec![
+            event_log(spend_event(1), 12),
+            event_log(spend_event(2), 20),
+            event_log(spend_event(3), 35),
+        ];
+        let (network, _) = spawn_mock_rpc(40, logs).await;
+        let mut node_manager = NodeManager::new(None, Some(network), vec![], true, true).unwrap();
+
+        // Unlimited by default.
+        let (events, reached) = node_manager.get_spend_events_capped(10, 41).await.unwrap();
+        assert_eq!((events.len(), reached), (3, 41));
+
+        // A cap of zero still moves forward.
+        node_manager.max_blocks_per_call = Some(0);
+        let (events, reached) = node_manager.get_spend_events_capped(12, 41).await.unwrap();
+        assert_eq!((events.len(), reached), (1, 13));
+
+        node_manager.set_max_blocks_per_call(Some(10));
+        let mut from = 10;
+        let mut calls = Vec::new();
+        while from < 41 {
+            let (events, reached) = node_manager
+                .get_spend_events_capped(from, 41)
+                .await
+                .unwrap();
+            calls.push((from, reached, events.len()));
+            from = reached;
+        }
+        assert_eq!(
+            calls,
+            vec![(10, 20, 1), (20, 30, 1), (30, 40, 1), (40, 41, 0)]
+        );
+
+        // The uncapped queries still cover the whole range.
+        assert_eq!(
+            node_manager.get_spend_events(10, 41).await.unwrap().len(),
+            3
+        );
+
+        // With the head at 40 and 5 confirmations, nothing from block 36 on is confirmed.
+        node_manager.set_confirmations(5);
+        let (_, reached) = node_manager.get_sent_events_capped(30, 41).await.unwrap();
+        assert_eq!(reached, 36);
+        let err = node_manager
+            .get_sent_events_capped(36, 41)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<NetworkError>(),
+            Some(NetworkError::NotConfirmed(36))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_spend_events_polls_http_provider() {
+        let logs = vec![
+            event_log(spend_event(1), 12),
+            event_log(sent_event(0), 25),
+            event_log(spend_event(2), 30),
+            event_log(spend_event(3), 40),
+        ];
+        let (network, log_queries) = spawn_mock_rpc(40, logs).await;
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        assert!(matches!(
+            node_manager.subscribe_spend_events(20).await,
+            Err(NetworkError::ProviderNotSet)
+        ));
+
+        node_manager.set_provider_network(network);
+        node_manager.event_poll_interval = Duration::from_millis(10);
+        let mut events = node_manager.subscribe_spend_events(20).await.unwrap();
+        assert_eq!(events.recv().await, Some(spend_event(2)));
+        assert_eq!(events.recv().await, Some(spend_event(3)));
+
+        // Later polls only ask for blocks past the head, so nothing is sent twice.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(events.try_recv().is_err());
+        assert_eq!(log_queries.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_confirmations_hold_back_recent_events() {
+        let logs = vec![
+            event_log(spend_event(1), 12),
+            event_log(spend_event(2), 35),
+            event_log(spend_event(3), 38),
+        ];
+        let (network, _) = spawn_mock_rpc(40, logs).await;
+        let mut node_manager = NodeManager::new(None, Some(network), vec![], true, true).unwrap();
+        assert_eq!(
+            node_manager.get_spend_events(10, 100).await.unwrap().len(),
+            3
+        );
+
+        node_manager.set_confirmations(5);
+        assert_eq!(node_manager.latest_confirmed_block().await.unwrap(), 35);
+        assert_eq!(
+            node_manager.get_spend_events(10, 100).await.unwrap().len(),
+            2
+        );
+        assert!(node_manager
+            .get_spend_events(36, 100)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_events_fails_over_between_providers() {
+        let logs = vec![event_log(spend_event(1), 12), event_log(spend_event(2), 20)];
+        let (live, _) = spawn_mock_rpc(40, logs).await;
+        let (mut dead, _) = spawn_mock_rpc(40, vec![]).await;
+        dead.provider = Arc::new(
+            Provider::<Http>::try_from(format!("http://{}", spawn_mock_peer(Router::new()).await))
+                .unwrap(),
+        );
+
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+        node_manager.event_scan.max_consecutive_failures = 2;
+        node_manager.add_provider_network(dead.clone());
+        assert!(node_manager.get_spend_events(10, 30).await.is_err());
+        assert!(node_manager.latest_confirmed_block().await.is_err());
+
+        node_manager.add_provider_network(live.clone());
+        assert_eq!(
+            node_manager.get_spend_events(10, 30).await.unwrap().len(),
+            2
+        );
+        assert_eq!(node_manager.latest_confirmed_block().await.unwrap(), 40);
+
+        node_manager.set_provider_network(dead);
+        assert_eq!(node_manager.provider_networks().count(), 1);
+        assert!(node_manager.get_spend_events(10, 30).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_events_reports_progress() {
+        let logs = vec![event_log(spend_event(1), 12), event_log(spend_event(2), 20)];
+        let (network, _) = spawn_mock_rpc(5000, logs).await;
+        let mut node_manager = NodeManager::new(None, Some(network), vec![], true, true).unwrap();
+        node_manager.event_scan.base_step = 1000;
+        node_manager.event_scan.max_step = 1000;
+
+        let mut reports = Vec::new();
+        let mut record = |current, total| reports.push((current, total));
+        let events = node_manager
+            .get_spend_events_with_progress(10, 2510, Some(&mut record))
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(reports, vec![(1000, 2500), (2000, 2500), (2500, 2500)]);
+    }
+
+    #[tokio::test]
+    async fn test_scan_events_decodes_spend_and_sent_events() {
+        let (provider, mock) = Provider::mocked();
+        let contract: ContractInstance<Arc<Provider<MockProvider>>, _> =
+            Contract::new(H160::zero(), Abi::default(), Arc::new(provider));
+
+        // Responses are popped from the back, so the last pushed answers the first query.
+        mock.push::<Vec<Log>, _>(vec![event_log(spend_event(3), 1500)])
+            .unwrap();
+        mock.push::<Vec<Log>, _>(vec![
+            event_log(spend_event(1), 10),
+            event_log(spend_event(2), 20),
+        ])
+        .unwrap();
+        let (spend_events, err): (Vec<(SpendFilter, u64)>, _) = scan_events(
+            &contract,
+            0,
+            2000,
+            &EventScanConfig::default(),
+            DEFAULT_RPC_QUERY_TIMEOUT,
+            &SyncCounters::default(),
+            None,
+        )
+        .await;
+        assert!(err.is_none());
+        assert_eq!(
+            spend_events
+                .iter()
+                .map(|(e, block)| (e.nullifier, *block))
+                .collect::<Vec<_>>(),
+            vec![
+                (U256::from(1), 10),
+                (U256::from(2), 20),
+                (U256::from(3), 1500)
+            ]
+        );
+
+        mock.push::<Vec<Log>, _>(vec![event_log(sent_event(2), 1500)])
+            .unwrap();
+        mock.push::<Vec<Log>, _>(vec![
+            event_log(sent_event(0), 10),
+            event_log(sent_event(1), 20),
+        ])
+        .unwrap();
+        let (sent_events, err): (Vec<(SentFilter, u64)>, _) = scan_events(
+            &contract,
+            0,
+            2000,
+            &EventScanConfig::default(),
+            DEFAULT_RPC_QUERY_TIMEOUT,
+            &SyncCounters::default(),
+            None,
+        )
+        .await;
+        assert!(err.is_none());
+        assert_eq!(
+            sent_events,
+            vec![
+                (sent_event(0), 10),
+                (sent_event(1), 20),
+                (sent_event(2), 1500)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_block_range_scanner() {
+        let config = EventScanConfig {
+            base_step: 4,
+            min_step: 2,
+            max_step: 8,
+            max_consecutive_failures: 2,
+            transient_retries: 0,
+        };
+        let mut scanner = BlockRangeScanner::new(10, 30, &config);
+        assert_eq!(scanner.next_range(), Some((10, 13)));
+        scanner.record_success();
+        assert_eq!(scanner.next_range(), Some((14, 17)));
+        scanner.record_success();
+        assert_eq!(scanner.next_range(), Some((18, 25)));
+        scanner.record_failure().unwrap();
+        assert_eq!(scanner.next_range(), Some((18, 21)));
+        scanner.record_success();
+        scanner.record_success();
+        assert_eq!(scanner.position(), 26);
+        assert_eq!(scanner.next_range(), Some((26, 29)));
+        scanner.record_success();
+        assert_eq!(scanner.next_range(), None);
+
+        let mut scanner = BlockRangeScanner::new(0, 100, &config);
+        scanner.record_failure().unwrap();
+        assert!(matches!(
+            scanner.record_failure(),
+            Err(NetworkError::ScanAborted {
+                from: 0,
+                failures: 2
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_scan_block_ranges_converges_on_narrow_provider() {
+        // A provider that only answers ranges of at most 100 blocks.
+        let config = EventScanConfig {
+            base_step: 1024,
+            min_step: 1,
+            max_step: 4096,
+            ..Default::default()
+        };
+        let mut queried = Vec::new();
+        let (blocks, err): (Vec<u64>, _) = scan_block_ranges(0, 1000, &config, None, |from, to| {
+            queried.push((from, to));
+            async move {
+                (to - from < 100)
+                    .then(|| (from..=to).collect())
+                    .ok_or(ChunkFailure::Timeout)
+            }
+        })
+        .await;
+        assert!(err.is_none());
+        assert_eq!(blocks, (0..1000).collect::<Vec<_>>());
+        assert!(queried.iter().all(|(from, to)| from <= to && *to < 1000));
+    }
+
+    #[tokio::test]
+    async fn test_scan_block_ranges_respects_step_bounds() {
+        let config = EventScanConfig {
+            base_step: 8,
+            min_step: 4,
+            max_step: 32,
+            ..Default::default()
+        };
+        let mut steps = Vec::new();
+        let mut calls = 0;
+        let _: (Vec<()>, _) = scan_block_ranges(0, 500, &config, None, |from, to| {
+            calls += 1;
+            steps.push(to - from + 1);
+            let ok = calls != 3;
+            async move { ok.then(Vec::new).ok_or(ChunkFailure::Timeout) }
+        })
+        .await;
+        assert_eq!(&steps[..5], &[8, 8, 16, 8, 8]);
+        assert!(steps.iter().all(|step| *step <= 32));
+        assert!(steps.contains(&32));
+
+        // Even a zero minimum never lets the range collapse to zero blocks.
+        let config = EventScanConfig {
+            base_step: 1,
+            min_step: 0,
+            max_step: 0,
+            ..Default::default()
+        };
+        let mut failed_once = false;
+        let (blocks, _): (Vec<u64>, _) = scan_block_ranges(10, 13, &config, None, |from, to| {
+            let fail = !failed_once;
+            failed_once = true;
+            async move {
+                (!fail)
+                    .then(|| (from..=to).collect())
+                    .ok_or(ChunkFailure::Timeout)
+            }
+        })
+        .await;
+        assert_eq!(blocks, vec![10, 11, 12]);
+    }
+
+    #[tokio::test]
+    async fn test_scan_block_ranges_retries_transient_errors_in_place() {
+        let config = EventScanConfig {
+            base_step: 100,
+            transient_retries: 2,
+            ..Default::default()
+        };
+        let mut queried = Vec::new();
+        let (blocks, err): (Vec<u64>, _) = scan_block_ranges(0, 200, &config, None, |from, to| {
+            queried.push((from, to));
+            // The first chunk hits two blips, the second one three.
+            let failure = match queried.len() {
+                1 | 2 | 4 | 5 | 6 => Some(ChunkFailure::Transient),
+                _ => None,
+            };
+            async move {
+                match failure {
+                    Some(failure) => Err(failure),
+                    None => Ok((from..=to).collect()),
+                }
+            }
+        })
+        .await;
+        assert!(err.is_none());
+        assert_eq!(blocks, (0..200).collect::<Vec<_>>());
+        // Two retries of the same range are allowed, the third blip shrinks it.
+        assert_eq!(
+            queried,
+            vec![
+                (0, 99),
+                (0, 99),
+                (0, 99),
+                (100, 199),
+                (100, 199),
+                (100, 199),
+                (100, 149),
+                (150, 199),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_block_ranges_gives_up_on_dead_provider() {
+        let config = EventScanConfig {
+            base_step: 10,
+            max_consecutive_failures: 5,
+            ..Default::default()
+        };
+        let mut calls = 0;
+        let (blocks, err): (Vec<u64>, _) = scan_block_ranges(0, 100, &config, None, |from, to| {
+            calls += 1;
+            // Only the first chunk succeeds, every later query times out.
+            let ok = calls == 1;
+            async move {
+                ok.then(|| (from..=to).collect())
+                    .ok_or(ChunkFailure::Timeout)
+            }
+        })
+        .await;
+        assert_eq!(blocks, (0..10).collect::<Vec<_>>());
+        assert!(matches!(
+            err,
+            Some(NetworkError::ScanAborted {
+                from: 10,
+                failures: 5
+            })
+        ));
+        assert_eq!(calls, 6);
+    }
+
+    #[tokio::test]
+    async fn test_provider_sends_rpc_headers() {
+        let app = Router::new().route(
+            "/",
+            post(
+                |headers: axum::http::HeaderMap, Json(req): Json<serde_json::Value>| async move {
+                    if headers.get("x-api-key").map(|v| v.as_bytes()) != Some(b"secret") {
+                        return Err(StatusCode::UNAUTHORIZED);
+                    }
+                    let result = match req["method"].as_str() {
+                        Some("eth_blockNumber") => serde_json::json!(U64::from(40)),
+                        _ => serde_json::json!([]),
+                    };
+                    Ok(Json(serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": req["id"],
+                        "result": result,
+                    })))
+                },
+            ),
+        );
+        let addr = spawn_mock_peer(app).await;
+        let (network, _) = spawn_mock_rpc(0, vec![]).await;
+        let endpoint = format!("http://{}", addr);
+        let mut config = network.config;
+        let mut node_manager = NodeManager::new(None, None, vec![], true, true).unwrap();
+
+        node_manager
+            .set_provider_network(Network::connect(&endpoint, None, config.clone()).unwrap());
+        assert!(node_manager.get_spend_events(10, 20).await.is_err());
+
+        config.rpc_headers = HashMap::from([("x-api-key".to_string(), "secret".to_string())]);
+        node_manager
+            .set_provider_network(Network::connect(&endpoint, None, config.clone()).unwrap());
+        assert!(node_manager
+            .get_spend_events(10, 20)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(node_manager
+            .get_sent_events(10, 20)
+            .await
+            .unwrap()
+            .is_empty());
+
+        config.rpc_headers = HashMap::from([("x-api-key".to_string(), "line\nbreak".to_string())]);
+        assert!(Network::connect(&endpoint, None, config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_reachability() {
+        let us = spawn_mock_peer(Router::new().route(
+            "/handshake",
+            get(|Query(req): Query<GetHandShakeRequest>| async move {
+                assert!(req.is_client);
+                assert_eq!(req.addr, None);
+                Json(GetHandShakeResponse {
+                    current_block_number: 3,
+                    max_events_page: None,
+                    version: Some(PROTOCOL_VERSION),
+                    events_commitment: None,
+                })
+            }),
+        ))
+        .await;
+        let context = Arc::new(Mutex::new(crate::config::NodeContext {
+            node_manager: NodeManager::new(None, None, vec![], false, true).unwrap(),
+            spent_events: vec![],
+            sent_events: vec![],
+            currnet_block_number: 0,
+            events_commitment: Default::default(),
+            mempool: vec![],
+        }));
+        let checker = spawn_mock_peer(Router::new().route(
+            "/reachability",
+            get(
+                move |Query(req): Query<crate::apis::GetReachabilityRequest>| async move {
+                    crate::apis::reachability(Query(req), context)
+                        .await
+                        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            ),
+        ))
+        .await;
+        let old_peer = spawn_mock_peer(Router::new()).await;
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        // Peers that don't know the endpoint are skipped.
+        let mut node_manager = NodeManager::new(
+            Some(us),
+            None,
+            vec![Peer::new(old_peer), Peer::new(checker)],
+            true,
+            false,
+        )
+        .unwrap();
+        assert!(node_manager.verify_reachability().await.unwrap());
+
+        node_manager.external_addr = Some(closed);
+        assert!(!node_manager.verify_reachability().await.unwrap());
+
+        node_manager.set_peers(vec![Peer::new(old_peer)]);
+        assert!(node_manager.verify_reachability().await.is_err());
+
+        let client = NodeManager::new(None, None, vec![Peer::new(checker)], true, true).unwrap();
+        let err = client.verify_reachability().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<NetworkError>(),
+            Some(NetworkError::NotANode)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_query_events_without_provider() {
+        let node_manager = NodeManager::new(None, None, vec![], false, true).unwrap();
+        let err = node_manager.get_spend_events(0, 100).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<NetworkError>(),
+            Some(NetworkError::ProviderNotSet)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cleared_provider_behaves_like_unset() {
+        let logs = vec![event_log(spend_event(1), 12)];
+        let (primary, _) = spawn_mock_rpc(40, logs.clone()).await;
+        let (backup, _) = spawn_mock_rpc(40, logs).await;
+        let mut node_manager = NodeManager::new(None, Some(primary), vec![], true, true).unwrap();
+        node_manager.set_event_cache_capacity(4);
+        node_manager.add_provider_network(backup);
+        assert_eq!(
+            node_manager.get_spend_events(10, 30).await.unwrap().len(),
+            1
+        );
+
+        node_manager.clear_provider_network();
+        assert!(node_manager.get_provider_network().is_none());
+        assert_eq!(node_manager.provider_networks().count(), 0);
+        for err in [
+            node_manager.get_spend_events(10, 30).await.unwrap_err(),
+            node_manager.latest_confirmed_block().await.unwrap_err(),
+        ] {
+            assert!(matches!(
+                err.downcast_ref::<NetworkError>(),
+                Some(NetworkError::ProviderNotSet)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events_with_timestamps() {
+        let logs = vec![
+            event_log(spend_event(1), 12),
+            event_log(spend_event(2), 12),
+            event_log(spend_event(3), 30),
+            event_log(sent_event(0), 30),
+        ];
+        let (network, _) = spawn_mock_rpc(40, logs).await;
+        let mut node_manager = NodeManager::new(None, Some(network), vec![], false, true).unwrap();
+
+        let spends = node_manager
+            .get_spend_events_with_timestamps(10, 40)
+            .await
+            .unwrap();
+        assert_eq!(
+            spends
+                .iter()
+                .map(|e| (e.block_number, e.timestamp))
+                .collect::<Vec<_>>(),
+            vec![(12, 144), (12, 144), (30, 360)]
+        );
+        assert_eq!(spends[2].event, spend_event(3));
+        let sents = node_manager
+            .get_sent_events_with_timestamps(10, 40)
+            .await
+            .unwrap();
+        assert_eq!(sents.len(), 1);
+        assert_eq!((sents[0].block_number, sents[0].timestamp), (30, 360));
+        assert_eq!(
+            node_manager.get_spend_events(10, 40).await.unwrap().len(),
+            3
+        );
+
+        // Cached timestamps don't need the provider anymore.
+        node_manager.clear_provider_network();
+        let timestamps = node_manager.block_timestamps(&[30, 12]).await.unwrap();
+        assert_eq!(timestamps, HashMap::from([(12, 144), (30, 360)]));
+        assert!(node_manager.block_timestamps(&[31]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_block_timestamps_are_batched() {
+        let batches = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = batches.clone();
+        let app = Router::new().route(
+            "/",
+            post(move |Json(req): Json<serde_json::Value>| {
+                let seen = seen.clone();
+                async move {
+                    let Some(batch) = req.as_array() else {
+                        return Json(serde_json::json!({
+                            "jsonrpc": "2.0",
+                            "id": req["id"],
+                            "result": U64::from(500),
+                        }));
+                    };
+                    seen.lock().unwrap().push(batch.len());
+                    Json(serde_json::json!(batch
+                        .iter()
+                        .map(|req| {
+                            let number: U64 =
+                                serde_json::from_value(req["params"][0].clone()).unwrap();
+                            serde_json::json!({
+                                "jsonrpc": "2.0",
+                                "id": req["id"],
+                                "result": Block::<TxHash> {
+                                    number: Some(number),
+                                    timestamp: (number.as_u64() * 12).into(),
+                                    ..Default::default()
+                                },
+                            })
+                        })
+                        .collect::<Vec<_>>()))
+                }
+            }),
+        );
+        let addr = spawn_mock_peer(app).await;
+        let (network, _) = spawn_mock_rpc(500, vec![]).await;
+        let network = Network {
+            provider: Arc::new(Provider::<Http>::try_from(format!("http://{}", addr)).unwrap()),
+            ..network
+        };
+        let node_manager = NodeManager::new(None, Some(network), vec![], false, true).unwrap();
+
+        let blocks: Vec<u64> = (1..=150).chain(1..=10).collect();
+        let timestamps = node_manager.block_timestamps(&blocks).await.unwrap();
+        assert_eq!(timestamps.len(), 150);
+        assert_eq!(timestamps[&150], 1800);
+        let mut batches = batches.lock().unwrap().clone();
+        batches.sort();
+        assert_eq!(batches, vec![50, BLOCK_TIMESTAMP_BATCH]);
+    }
+
+    #[tokio::test]
+    async fn test_scan_from_deployment() {
+        let node_manager = NodeManager::new(None, None, vec![], false, true).unwrap();
+        let err = node_manager.scan_from_deployment(40).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<NetworkError>(),
+            Some(NetworkError::ProviderNotSet)
+        ));
+
+        // The mock contract is deployed at block 10, so the log at block 3 is never asked for.
+        let logs = vec![
+            event_log(spend_event(1), 3),
+            event_log(spend_event(2), 12),
+            event_log(sent_event(0), 30),
+        ];
+        let (network, log_queries) = spawn_mock_rpc(40, logs).await;
+        let node_manager = NodeManager::new(None, Some(network), vec![], false, true).unwrap();
+
+        let (spend_events, sent_events) = node_manager.scan_from_deployment(31).await.unwrap();
+        assert_eq!(spend_events, vec![spend_event(2)]);
+        assert_eq!(sent_events, vec![sent_event(0)]);
+
+        let queries = log_queries.load(atomic::Ordering::Relaxed);
+        let (spend_events, sent_events) = node_manager.scan_from_deployment(10).await.unwrap();
+        assert!(spend_events.is_empty() && sent_events.is_empty());
+        assert_eq!(log_queries.load(atomic::Ordering::Relaxed), queries);
+    }
 }